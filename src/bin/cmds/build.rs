@@ -19,6 +19,9 @@ pub fn cli() -> App<'static, 'static> {
         .arg(args::target_bin())
         .arg(args::target_test())
         .arg(args::build_threads())
+        .args(&args::profile())
+        .arg(args::keep_going())
+        .arg(args::no_hooks())
         .arg(args::offline())
         .arg(args::debug_log())
         .arg(args::idris_opts())
@@ -40,7 +43,8 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
     );
 
     // This is where our default codegen backend is set
-    let backend = get::backends(c, args);
+    let profile = get::profile(&ctx, &project)?;
+    let backend = get::backends(c, args, &profile);
 
     build::build(&ctx, &project, &ts, true, &backend)
 }
@@ -0,0 +1,99 @@
+use super::{args, get};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::cache::{self, GcOpts},
+    util::{config::Config, error::Result},
+};
+use std::path::PathBuf;
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("cache")
+        .about("Inspects and cleans up the global cache")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("Lists the contents of the cache, with their sizes and when they were last used")
+                .arg(args::debug_log()),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Checks cached sources against their recorded hashes, removing corrupt ones")
+                .arg(args::debug_log()),
+        )
+        .subcommand(
+            SubCommand::with_name("gc")
+                .about("Removes old or least recently used entries from the cache")
+                .arg(
+                    Arg::with_name("max-size")
+                        .long("max-size")
+                        .takes_value(true)
+                        .help("Evict least recently used entries until the cache is at most this big (e.g. 500M, 2G)"),
+                )
+                .arg(
+                    Arg::with_name("older-than")
+                        .long("older-than")
+                        .takes_value(true)
+                        .help("Evict entries which haven't been used for this long (e.g. 30d, 12h)"),
+                )
+                .arg(args::debug_log()),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Exports every cached build to a directory or tarball")
+                .arg(
+                    Arg::with_name("to")
+                        .required(true)
+                        .help("The directory or tarball (.tar, .tar.gz or .tgz) to export to"),
+                )
+                .arg(args::debug_log()),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Imports builds exported by `elba cache export` into the cache")
+                .arg(
+                    Arg::with_name("from")
+                        .required(true)
+                        .help("The directory or tarball to import from"),
+                )
+                .arg(args::debug_log()),
+        )
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    match args.subcommand() {
+        ("list", Some(args)) => {
+            let bcx = get::build_ctx(c, args);
+            println!("{}", cache::list(&bcx)?);
+            Ok("listed cache contents".to_string())
+        }
+        ("verify", Some(args)) => {
+            let bcx = get::build_ctx(c, args);
+            cache::verify(&bcx)
+        }
+        ("gc", Some(args)) => {
+            let opts = GcOpts {
+                max_size: args
+                    .value_of("max-size")
+                    .map(cache::parse_size)
+                    .transpose()?,
+                older_than: args
+                    .value_of("older-than")
+                    .map(cache::parse_duration)
+                    .transpose()?,
+            };
+            let bcx = get::build_ctx(c, args);
+            cache::gc(&bcx, &opts)
+        }
+        ("export", Some(args)) => {
+            let to = PathBuf::from(args.value_of_os("to").unwrap());
+            let bcx = get::build_ctx(c, args);
+            cache::export(&bcx, &to)
+        }
+        ("import", Some(args)) => {
+            let from = PathBuf::from(args.value_of_os("from").unwrap());
+            let bcx = get::build_ctx(c, args);
+            cache::import(&bcx, &from)
+        }
+        _ => unreachable!(),
+    }
+}
@@ -13,6 +13,9 @@ pub fn exec(c: &mut Config, _args: &ArgMatches) -> Result<String> {
     clear_dir(&layout.build).context(format_err!("couldn't clear {}", layout.build.display()))?;
     clear_dir(&layout.indices)
         .context(format_err!("couldn't clear {}", layout.indices.display()))?;
+    clear_dir(&layout.git).context(format_err!("couldn't clear {}", layout.git.display()))?;
+    clear_dir(&layout.records)
+        .context(format_err!("couldn't clear {}", layout.records.display()))?;
     clear_dir(&layout.tmp).context(format_err!("couldn't clear {}", layout.tmp.display()))?;
 
     Ok("cache directories cleared".to_string())
@@ -11,6 +11,8 @@ pub fn cli() -> App<'static, 'static> {
     SubCommand::with_name("doc")
         .about("Builds the docs for the root package")
         .arg(args::build_threads())
+        .args(&args::profile())
+        .arg(args::keep_going())
         .arg(args::debug_log())
         .arg(args::offline())
         .arg(args::idris_opts())
@@ -0,0 +1,70 @@
+use std::{env::current_dir, str::FromStr};
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::{build, index},
+    remote::resolution::IndexRes,
+    util::{config::Config, error::Result},
+};
+use failure::{format_err, ResultExt};
+use url::Url;
+
+use super::{args, get};
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("index")
+        .about("Manages package indices")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("add")
+                .about("Packages the current project and adds it to an index on disk")
+                .arg(
+                    Arg::with_name("index")
+                        .required(true)
+                        .help("The index to add the package to, by name or index resolution"),
+                )
+                .arg(
+                    Arg::with_name("url")
+                        .long("url")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Where the package's tarball will be available from"),
+                )
+                .arg(args::no_verify())
+                .arg(args::no_hooks())
+                .args(&args::profile())
+                .args(&args::backends()),
+        )
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    match args.subcommand() {
+        ("add", Some(args)) => {
+            let project = current_dir().context(format_err!(
+                "couldn't get current dir; doesn't exist or no permissions..."
+            ))?;
+
+            let ctx = get::build_ctx(c, args);
+            let (project, _) = build::find_manifest(&project, false, None)?;
+
+            let name = args.value_of("index").unwrap();
+            let index = match ctx.indices.get(name) {
+                Some(index) => index.clone(),
+                None => IndexRes::from_str(name)
+                    .with_context(|_| format_err!("no index named {} in configuration", name))?,
+            };
+            let url = Url::parse(args.value_of("url").unwrap())
+                .with_context(|e| format_err!("invalid tarball url: {}", e))?;
+
+            let profile = get::profile(&ctx, &project)?;
+            let backend = get::backends(c, args, &profile);
+
+            if !args.is_present("no-verify") {
+                build::build(&ctx, &project, &(true, false, None, None), true, &backend)?;
+            }
+
+            index::add(&ctx, &project, &index, url, &backend)
+        }
+        _ => unreachable!(),
+    }
+}
@@ -14,6 +14,9 @@ pub fn cli() -> App<'static, 'static> {
         .about("Installs a package's artifacts")
         .arg(Arg::with_name("spec"))
         .arg(args::build_threads())
+        .args(&args::profile())
+        .arg(args::keep_going())
+        .arg(args::no_hooks())
         .arg(args::target_bin())
         .arg(args::debug_log())
         .arg(args::offline())
@@ -46,7 +49,11 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
         .map(|x| x.collect())
         .unwrap_or_else(|| vec![]);
 
-    let backend = get::backends(c, args);
-
-    build::install(&ctx, proj, &targets, &backend, args.is_present("force"))
+    build::install(
+        &ctx,
+        proj,
+        &targets,
+        |profile| get::backends(c, args, profile),
+        args.is_present("force"),
+    )
 }
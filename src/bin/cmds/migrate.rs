@@ -0,0 +1,42 @@
+use super::{args, get};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use console::{user_attended, Term};
+use elba::{
+    cli::build,
+    util::{config::Config, error::Result},
+};
+use failure::{bail, format_err, ResultExt};
+use std::env::current_dir;
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("migrate")
+        .about("Converts the .ipkg file in the current directory into an elba.toml manifest")
+        .arg(
+            Arg::with_name("group")
+                .long("group")
+                .takes_value(true)
+                .help("The group to put the package in (asked for if not given)"),
+        )
+        .arg(args::offline())
+        .arg(args::debug_log())
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let project = current_dir().context(format_err!(
+        "couldn't get current dir; doesn't exist or no permissions..."
+    ))?;
+
+    let group = match args.value_of("group") {
+        Some(group) => group.to_owned(),
+        None if user_attended() => {
+            let term = Term::stdout();
+            term.write_str("Group name for the package: ")?;
+            term.read_line()?.trim().to_owned()
+        }
+        None => bail!("a group name has to be given with --group"),
+    };
+
+    let bcx = get::build_ctx(c, args);
+
+    build::migrate(&bcx, &project, &group)
+}
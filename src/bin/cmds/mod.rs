@@ -1,16 +1,20 @@
 mod add;
 mod build;
+mod cache;
 mod check;
 mod clean;
 mod doc;
+mod index;
 mod init;
 mod install;
+mod migrate;
 mod new;
 mod package;
 mod print_config;
 mod repl;
 mod script;
 mod search;
+mod task;
 mod test;
 mod uninstall;
 mod update;
@@ -34,17 +38,21 @@ pub fn subcommands() -> Vec<App<'static, 'static>> {
     vec![
         add::cli(),
         build::cli(),
+        cache::cli(),
         check::cli(),
         clean::cli(),
         doc::cli(),
+        index::cli(),
         init::cli(),
         install::cli(),
+        migrate::cli(),
         new::cli(),
         package::cli(),
         print_config::cli(),
         repl::cli(),
         script::cli(),
         search::cli(),
+        task::cli(),
         test::cli(),
         uninstall::cli(),
         update::cli(),
@@ -55,17 +63,21 @@ pub fn execute_internal(cmd: &str) -> Option<Exec> {
     match cmd {
         "add" => Some(add::exec),
         "build" => Some(build::exec),
+        "cache" => Some(cache::exec),
         "check" => Some(check::exec),
         "clean" => Some(clean::exec),
         "doc" => Some(doc::exec),
+        "index" => Some(index::exec),
         "init" => Some(init::exec),
         "install" => Some(install::exec),
+        "migrate" => Some(migrate::exec),
         "new" => Some(new::exec),
         "package" => Some(package::exec),
         "print-config" => Some(print_config::exec),
         "repl" => Some(repl::exec),
         "script" => Some(script::exec),
         "search" => Some(search::exec),
+        "task" => Some(task::exec),
         "test" => Some(test::exec),
         "uninstall" => Some(uninstall::exec),
         "update" => Some(update::exec),
@@ -109,20 +121,33 @@ pub fn execute_external(cmd: &str, args: &ArgMatches) -> Result<String> {
 
 mod get {
     use super::*;
-    use elba::cli::build::BuildCtx;
+    use elba::{
+        build::context::JobTokens,
+        cli::build::{self, BuildCtx},
+        package::manifest::Profile,
+    };
     use slog::Drain;
+    use std::path::Path;
 
     pub fn build_ctx(c: &mut Config, args: &ArgMatches) -> BuildCtx {
         let logger = get::logger(c, args);
+        let threads = get::threads(c, args);
 
         BuildCtx {
             compiler: c.compiler.clone(),
             indices: c.indices.to_owned(),
             global_cache: c.layout(),
+            net: c.net.clone(),
+            http: c.http.clone(),
             logger,
-            threads: get::threads(c, args),
+            threads,
+            tokens: JobTokens::new(threads),
             shell: c.shell(),
             offline: args.is_present("offline"),
+            keep_going: args.is_present("keep-going"),
+            profile: get::profile_name(args),
+            driver: c.driver,
+            no_hooks: args.is_present("no-hooks"),
             opts: get::idris_opts(c, args),
         }
     }
@@ -139,19 +164,23 @@ mod get {
         }
     }
 
-    pub fn backends(c: &mut Config, args: &ArgMatches) -> Backend {
-        let mut backend = args
+    /// Picks the codegen backend to use. Flags on the command line take priority over the build
+    /// profile, which takes priority over the config.
+    pub fn backends(c: &mut Config, args: &ArgMatches, profile: &Profile) -> Backend {
+        let name = args
             .value_of_lossy("backend")
-            .and_then(|x| {
-                let x = x.into_owned();
-                c.get_backend(&x)
-            })
+            .map(|x| x.into_owned())
+            .or_else(|| profile.backend.clone());
+
+        let mut backend = name
+            .as_ref()
+            .and_then(|x| c.get_backend(x))
             .unwrap_or_else(|| c.default_backend());
 
         // We do this because we want to preserve the name of the backend, even if it wasn't in the
         // config
-        if let Some(x) = args.value_of_lossy("backend") {
-            backend.name = x.into_owned();
+        if let Some(x) = name {
+            backend.name = x;
         }
 
         backend.portable = if args.is_present("portable") {
@@ -159,16 +188,37 @@ mod get {
         } else if args.is_present("non-portable") {
             false
         } else {
-            backend.portable
+            profile.portable.unwrap_or(backend.portable)
         };
 
         if let Some(x) = args.values_of_lossy("be-opts") {
             backend.opts = x;
+        } else {
+            backend.opts.extend(profile.backend_opts.iter().cloned());
         }
 
         backend
     }
 
+    pub fn profile_name(args: &ArgMatches) -> String {
+        if args.is_present("release") {
+            "release".to_string()
+        } else {
+            args.value_of("profile").unwrap_or("dev").to_string()
+        }
+    }
+
+    /// Looks up the selected build profile in the manifest of the package at `project`. If
+    /// there's no package there, the built-in profiles are used.
+    pub fn profile(ctx: &BuildCtx, project: &Path) -> Result<Profile> {
+        if !build::manifest_exists(project) {
+            return Profile::builtin(&ctx.profile);
+        }
+
+        let (_, manifest) = build::find_manifest(project, true, None)?;
+        manifest.profile(&ctx.profile)
+    }
+
     pub fn threads(_c: &mut Config, args: &ArgMatches) -> u32 {
         args.value_of("threads")
             .and_then(|s| s.parse().ok())
@@ -223,7 +273,7 @@ mod args {
             .short("j")
             .takes_value(true)
             .number_of_values(1)
-            .help("The number of threads to use to build")
+            .help("The maximum number of compiler processes to run at once")
     }
 
     pub fn backends() -> Vec<Arg> {
@@ -249,6 +299,32 @@ mod args {
         ]
     }
 
+    pub fn keep_going() -> Arg {
+        Arg::with_name("keep-going")
+            .long("keep-going")
+            .help("Keep building everything which doesn't depend on a package that failed to build")
+    }
+
+    pub fn profile() -> Vec<Arg> {
+        vec![
+            Arg::with_name("release")
+                .long("release")
+                .help("Build with the release profile"),
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .number_of_values(1)
+                .conflicts_with("release")
+                .help("The build profile to use (dev by default)"),
+        ]
+    }
+
+    pub fn no_hooks() -> Arg {
+        Arg::with_name("no-hooks")
+            .long("no-hooks")
+            .help("Don't run the package's hooks (other than prebuild)")
+    }
+
     pub fn offline() -> Arg {
         Arg::with_name("offline")
             .long("offline")
@@ -3,10 +3,7 @@ use std::env::current_dir;
 use clap::{App, ArgMatches, SubCommand};
 use elba::{
     cli::{build, index},
-    util::{
-        config::{Backend, Config},
-        error::Result,
-    },
+    util::{config::Config, error::Result},
 };
 use failure::{format_err, ResultExt};
 
@@ -15,6 +12,9 @@ use super::{args, get};
 pub fn cli() -> App<'static, 'static> {
     SubCommand::with_name("package")
         .arg(args::no_verify())
+        .arg(args::no_hooks())
+        .args(&args::profile())
+        .args(&args::backends())
         .about("Compiles the package and packages it into a nice tarball")
 }
 
@@ -26,17 +26,14 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
     let ctx = get::build_ctx(c, args);
     let (project, _) = build::find_manifest(&project, false, None).unwrap();
 
+    let profile = get::profile(&ctx, &project)?;
+    let backend = get::backends(c, args, &profile);
+
     if !args.is_present("no-verify") {
-        build::build(
-            &ctx,
-            &project,
-            &(true, false, None, None),
-            true,
-            &Backend::default(),
-        )?;
+        build::build(&ctx, &project, &(true, false, None, None), true, &backend)?;
     }
 
-    let (gz_name, _) = index::package(&project)?;
+    let (gz_name, _) = index::package(&ctx, &project, &backend)?;
 
     Ok(format!(
         "created compressed tarball at `{}`",
@@ -11,6 +11,7 @@ pub fn cli() -> App<'static, 'static> {
     SubCommand::with_name("repl")
         .about("Launches a repl, loading the root package")
         .arg(args::build_threads())
+        .args(&args::profile())
         .arg(args::target_bin())
         .arg(args::target_lib())
         .arg(args::offline())
@@ -50,8 +51,9 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
         build::Interactivity::Normal
     };
 
-    let backend = get::backends(c, args);
     let ctx = get::build_ctx(c, args);
+    let profile = get::profile(&ctx, &project)?;
+    let backend = get::backends(c, args, &profile);
 
     build::repl(&ctx, &project, &ts, &backend, interactivity)
 }
@@ -3,42 +3,67 @@ use std::env::current_dir;
 use clap::{App, Arg, ArgMatches, SubCommand};
 use console::style;
 use elba::{
-    build::run_script,
+    build::{exec_script, HookEnv},
     cli::build::find_manifest,
-    util::{config::Config, error::Result, fmt_multiple, shell::Verbosity},
+    util::{config::Config, error::Result, shell::Verbosity},
 };
 use failure::{format_err, ResultExt};
 
+use super::{args, get};
+
 pub fn cli() -> App<'static, 'static> {
     SubCommand::with_name("script")
         .about("Run a project script")
         .arg(Arg::with_name("script-name").required(true))
+        .args(&args::profile())
+        .args(&args::backends())
+        .arg(
+            Arg::with_name("script-args")
+                .multiple(true)
+                .last(true)
+                .help("Extra arguments to pass to the last command of the script"),
+        )
 }
 
 pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
     let name = &*args.value_of_lossy("script-name").unwrap();
+    let script_args = args.values_of_lossy("script-args").unwrap_or_else(Vec::new);
     let cdir = current_dir().context(format_err!(
         "couldn't get current dir; doesn't exist or no permissions..."
     ))?;
 
-    let (_, manifest) = find_manifest(&cdir, true, None)?;
+    // Scripts always run from the root of the package, wherever elba was run from
+    let (project, manifest) = find_manifest(&cdir, true, None)?;
+    let cmds = manifest.script(name)?;
+
+    let profile_name = get::profile_name(args);
+    let profile = manifest.profile(&profile_name)?;
+    let backend = get::backends(c, args, &profile);
+    let target_dir = project.join("target").join(&profile_name);
+    let env = HookEnv {
+        manifest: &manifest,
+        root: &project,
+        target_dir: &target_dir,
+        backend: &backend.name,
+        profile: &profile_name,
+    };
+    let vars = env.vars()?;
+
+    for (ix, cmd) in cmds.iter().enumerate() {
+        let cmd_args = if ix == cmds.len() - 1 {
+            &script_args[..]
+        } else {
+            &[]
+        };
 
-    if let Some(s) = manifest.scripts.get(name) {
         c.shell().println(
             style("Running").dim(),
-            format!("script `{}` > {}", name, s),
+            format!("script `{}` > {}", name, cmd),
             Verbosity::Normal,
         );
-        c.shell().println_plain(
-            fmt_multiple(
-                &run_script(&cdir, s)
-                    .with_context(|e| format_err!("error running script:\n{}", e))?,
-            ),
-            Verbosity::Normal,
-        );
-
-        Ok(format!("successfully executed script `{}`", name))
-    } else {
-        Err(format_err!("script `{}` not found", name))
+        exec_script(&project, cmd, cmd_args, &vars)
+            .with_context(|e| format_err!("error running script `{}`: {}", name, e))?;
     }
+
+    Ok(format!("successfully executed script `{}`", name))
 }
@@ -0,0 +1,44 @@
+use super::{args, get};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::build,
+    util::{config::Config, error::Result},
+};
+use failure::{format_err, ResultExt};
+use std::env::current_dir;
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("task")
+        .about("Builds the package's tools and runs one of their binaries")
+        .arg(
+            Arg::with_name("task-name")
+                .required(true)
+                .help("The name of the binary to run"),
+        )
+        .arg(args::build_threads())
+        .args(&args::profile())
+        .arg(args::offline())
+        .arg(args::debug_log())
+        .args(&args::backends())
+        .arg(
+            Arg::with_name("task-args")
+                .multiple(true)
+                .last(true)
+                .help("Arguments to pass to the binary"),
+        )
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let project = current_dir().context(format_err!(
+        "couldn't get current dir; doesn't exist or no permissions..."
+    ))?;
+
+    let ctx = get::build_ctx(c, args);
+    let name = args.value_of_lossy("task-name").unwrap();
+    let task_args = args.values_of_lossy("task-args").unwrap_or_else(Vec::new);
+
+    let profile = get::profile(&ctx, &project)?;
+    let backend = get::backends(c, args, &profile);
+
+    build::task(&ctx, &project, &name, &task_args, &backend)
+}
@@ -12,6 +12,9 @@ pub fn cli() -> App<'static, 'static> {
         .about("Runs the tests of the root package")
         .args(&args::backends())
         .arg(args::build_threads())
+        .args(&args::profile())
+        .arg(args::keep_going())
+        .arg(args::no_hooks())
         .arg(args::offline())
         .arg(args::debug_log())
         .arg(
@@ -37,7 +40,8 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
     let ctx = get::build_ctx(c, args);
 
     // This is where our default codegen backend is set
-    let backend = get::backends(c, args);
+    let profile = get::profile(&ctx, &project)?;
+    let backend = get::backends(c, args, &profile);
 
     let targets = args
         .values_of("targets")
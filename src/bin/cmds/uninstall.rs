@@ -29,7 +29,7 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
     let logger = get::logger(c, args);
     let shell = c.shell();
 
-    let cache = Cache::from_disk(&logger, c.layout(), shell)?;
+    let cache = Cache::from_disk(&logger, c.layout(), c.net.clone(), &c.http, shell)?;
 
     let rc = cache.remove_bins(&spec, &targets)?;
 
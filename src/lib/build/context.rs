@@ -1,12 +1,18 @@
 use crate::{
     retrieve::cache::Cache,
-    util::{config::Backend, error::Result, fmt_output},
+    util::{
+        config::{Backend, Driver},
+        error::Result,
+        fmt_output,
+    },
 };
 use failure::{format_err, ResultExt};
 use std::{
     path::{Path, PathBuf},
     process::Command,
+    sync::Arc,
 };
+use tokio::sync::{Semaphore, SemaphorePermit};
 
 #[derive(Debug, Clone)]
 pub struct BuildContext {
@@ -17,9 +23,38 @@ pub struct BuildContext {
     /// The global cache to use.
     pub cache: Cache,
     pub threads: u32,
+    /// The tokens which every compiler process has to hold while it runs.
+    pub tokens: JobTokens,
+    /// Whether to keep building the packages and targets which don't depend on one which failed
+    pub keep_going: bool,
+    /// The name of the build profile in use
+    pub profile: String,
+    /// How libraries get built, unless their manifest says otherwise
+    pub driver: Driver,
     pub opts: Vec<String>,
 }
 
+/// A pool of tokens shared by everything built in a single run of elba (the package, its
+/// dependencies and any tools it needs), in the style of make's jobserver.
+///
+/// Every compiler process (checking a module, generating code or building docs) and build script
+/// needs a token while it runs, so no matter how many packages are being built at once, at most as
+/// many compiler processes run as there are tokens in the pool.
+#[derive(Debug, Clone)]
+pub struct JobTokens(Arc<Semaphore>);
+
+impl JobTokens {
+    pub fn new(tokens: u32) -> Self {
+        JobTokens(Arc::new(Semaphore::new(tokens.max(1) as usize)))
+    }
+
+    /// Waits for a token to be free. The token is given back to the pool once the returned
+    /// permit is dropped.
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.0.acquire().await
+    }
+}
+
 /// Information on the compiler executable
 #[derive(Debug, Clone)]
 pub struct Compiler {
@@ -106,3 +141,28 @@ impl CompilerFlavor {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn job_tokens_are_shared() {
+        let tokens = JobTokens::new(2);
+        let other = tokens.clone();
+        let mut rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let first = tokens.acquire().await;
+            let second = other.acquire().await;
+            assert_eq!(tokens.0.available_permits(), 0);
+            drop(first);
+            assert_eq!(other.0.available_permits(), 1);
+            drop(second);
+        });
+
+        // There's always at least one token to go around
+        assert_eq!(JobTokens::new(0).0.available_permits(), 1);
+    }
+}
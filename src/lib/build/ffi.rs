@@ -0,0 +1,183 @@
+//! C code which libraries bring along for their foreign functions.
+//!
+//! A library's C sources are compiled whenever the library is built, and the objects go into the
+//! `ffi` directory of its build output, along with its headers and a `link` file listing the C
+//! libraries it needs. The `ffi` directory of a library also gets copies of everything in its
+//! dependencies' `ffi` directories, so an executable only has to look at the libraries it depends
+//! on directly to find everything it has to be linked with.
+
+use std::{
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use console::style;
+use failure::{format_err, ResultExt};
+use futures::future;
+use itertools::Itertools;
+
+use super::{context::BuildContext, invoke::invoke_cc};
+use crate::{
+    package::manifest::FfiTarget,
+    retrieve::cache::{Binary, Source},
+    util::{
+        clear_dir, copy_dir,
+        error::Result,
+        shell::{OutputGroup, Shell, Verbosity},
+    },
+};
+
+/// Everything the C compiler needs to link an executable against a set of libraries.
+#[derive(Debug, Default)]
+pub struct Ffi {
+    pub objects: Vec<PathBuf>,
+    pub include_dirs: Vec<PathBuf>,
+    pub link: Vec<String>,
+}
+
+impl Ffi {
+    /// Gathers the C outputs of a set of libraries.
+    pub fn collect(deps: &[Binary]) -> Result<Self> {
+        let mut ffi = Ffi::default();
+        for dep in deps {
+            let dir = dep.target.path().join("ffi");
+            if dir.exists() {
+                ffi.load(&dir)?;
+            }
+        }
+
+        // Libraries which share a dependency each have a copy of its objects, but they can only
+        // be linked in once
+        ffi.objects = ffi
+            .objects
+            .into_iter()
+            .unique_by(|x| x.file_name().map(|x| x.to_owned()))
+            .collect();
+        ffi.link = ffi.link.into_iter().unique().collect();
+
+        Ok(ffi)
+    }
+
+    fn load(&mut self, dir: &Path) -> Result<()> {
+        let mut objects = fs::read_dir(dir)
+            .with_context(|e| format_err!("couldn't read {}: {}", dir.display(), e))?
+            .filter_map(|x| x.ok())
+            .map(|x| x.path())
+            .filter(|x| x.extension() == Some(OsStr::new("o")))
+            .collect::<Vec<_>>();
+        objects.sort();
+        self.objects.extend(objects);
+
+        let include = dir.join("include");
+        if include.exists() {
+            self.include_dirs.push(include);
+        }
+
+        if let Ok(link) = fs::read_to_string(dir.join("link")) {
+            self.link.extend(link.lines().map(|x| x.to_owned()));
+        }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty() && self.include_dirs.is_empty() && self.link.is_empty()
+    }
+
+    /// The arguments telling the C compiler where to find headers.
+    pub fn include_args(&self) -> Vec<String> {
+        self.include_dirs
+            .iter()
+            .map(|x| format!("-I{}", x.display()))
+            .collect()
+    }
+
+    /// The arguments linking an executable with every object file and library.
+    pub fn link_args(&self) -> Vec<String> {
+        self.objects
+            .iter()
+            .map(|x| x.display().to_string())
+            .chain(self.link.iter().map(|x| format!("-l{}", x)))
+            .collect()
+    }
+}
+
+/// Compiles the C sources of a library into `out`, then copies everything that its dependencies
+/// need to be linked with over too. Nothing is created if there's nothing to link with.
+pub async fn build_ffi<'a>(
+    source: &'a Source,
+    ffi: Option<&'a FfiTarget>,
+    deps: &'a [Binary],
+    out: &'a Path,
+    bcx: &'a BuildContext,
+    shell: Shell,
+) -> Result<OutputGroup> {
+    let dep_ffi = Ffi::collect(deps)?;
+    if ffi.is_none() && dep_ffi.is_empty() {
+        return Ok(OutputGroup::new());
+    }
+
+    clear_dir(out)?;
+
+    let include = out.join("include");
+    for dir in &dep_ffi.include_dirs {
+        copy_dir(dir, &include, false)?;
+    }
+    for object in &dep_ffi.objects {
+        fs::copy(object, out.join(object.file_name().unwrap()))
+            .with_context(|e| format_err!("couldn't copy {}: {}", object.display(), e))?;
+    }
+
+    let mut link = vec![];
+    let mut outputs = vec![];
+    if let Some(ffi) = ffi {
+        for dir in &ffi.include_dirs {
+            copy_dir(&source.path().join(&dir.0), &include, false)?;
+        }
+
+        let mut args = vec![];
+        if include.exists() {
+            args.push(format!("-I{}", include.display()));
+        }
+        args.extend(ffi.cflags.iter().cloned());
+
+        // Object files from different packages all end up in the same directory, so they're
+        // named after the package as well as the source file.
+        let prefix = source.meta().name().as_normalized().replace("/", "_");
+        let compiles = ffi.c_sources.iter().map(|c_source| {
+            let object = out.join(format!(
+                "{}_{}.o",
+                prefix,
+                c_source
+                    .0
+                    .with_extension("")
+                    .to_string_lossy()
+                    .replace("/", "_")
+                    .replace("\\", "_")
+            ));
+
+            shell.println(
+                style("Compiling").cyan(),
+                format!("{} [{}]", c_source.0.display(), source.meta().name()),
+                Verbosity::Normal,
+            );
+
+            invoke_cc(source.path(), &c_source.0, object, &args, bcx, shell)
+        });
+        outputs = future::try_join_all(compiles).await?;
+
+        link.extend(ffi.link.iter().cloned());
+    }
+
+    // A library's own C libraries come first, so that static libraries it depends on can still
+    // be resolved from the ones after it.
+    link.extend(dep_ffi.link);
+    let link = link.into_iter().unique().collect::<Vec<_>>();
+    if !link.is_empty() {
+        fs::write(out.join("link"), link.join("\n"))
+            .with_context(|e| format_err!("couldn't write the libraries to link with: {}", e))?;
+    }
+
+    Ok(OutputGroup(outputs))
+}
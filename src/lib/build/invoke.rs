@@ -1,20 +1,22 @@
 //! Utilities for interacting with the Idris compiler
 
 use crate::{
-    build::context::BuildContext,
+    build::{context::BuildContext, ffi::Ffi},
     retrieve::cache::Binary,
     util::{
+        clear_dir, copy_dir,
         error::Result,
         fmt_output,
         shell::{Shell, Verbosity},
     },
 };
-use failure::bail;
+use failure::{bail, format_err, ResultExt};
 use itertools::Itertools;
 use std::{
+    env,
+    ffi::OsString,
     path::{Path, PathBuf},
     process::Output,
-    time::Duration,
 };
 use tokio::process::Command;
 
@@ -42,41 +44,94 @@ pub async fn invoke_compile<'a>(
         }
     } else {
         process.env(
-            "BLODWEN_PATH",
-            deps.iter()
-                .map(|x| x.target.path().to_string_lossy())
-                .join(":"),
+            "IDRIS2_PATH",
+            idris2_path(deps.iter().map(|x| x.target.path()))?,
         );
+        process.args(IDRIS2_DIRS);
     }
 
     process.args(args);
     process.arg(target);
 
-    // Idris sometimes breaks down if multiple modules is being compiled
-    // in parallel. We retry if the stdout contains 'loadable'.
-    for _ in 0..15usize {
-        shell.println_plain(format!("> {:#?}", process), Verbosity::Verbose);
+    let _token = bcx.tokens.acquire().await;
+    shell.println_plain(format!("> {:#?}", process), Verbosity::Verbose);
 
-        let output = process.output().await?;
+    let output = process.output().await?;
+    if !output.status.success() {
+        bail!("> {:#?}\n{}", process, fmt_output(&output))
+    }
 
-        if !output.status.success() {
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            if stdout.contains("loadable") {
-                tokio::time::delay_for(Duration::from_secs(5)).await;
-            } else {
-                bail!("> {:#?}\n{}", process, fmt_output(&output))
-            }
-        } else {
-            return Ok(output);
-        }
+    Ok(output)
+}
+
+/// Builds the package described by the ipkg file at `ipkg`, from the directory it's in.
+pub async fn invoke_ipkg<'a>(
+    deps: &'a [Binary],
+    ipkg: &'a Path,
+    bcx: &'a BuildContext,
+    shell: Shell,
+) -> Result<Output> {
+    let mut process: Command = bcx.compiler.process().into();
+    process
+        .kill_on_drop(true)
+        .current_dir(ipkg.parent().unwrap())
+        .arg("--build")
+        .arg(ipkg.file_name().unwrap());
+
+    // Idris 1 gets its dependencies through the ipkg's options
+    if bcx.compiler.flavor().is_idris2() {
+        process.env(
+            "IDRIS2_PATH",
+            idris2_path(deps.iter().map(|x| x.target.path()))?,
+        );
+    }
+
+    let _token = bcx.tokens.acquire().await;
+    shell.println_plain(format!("> {:#?}", process), Verbosity::Verbose);
+
+    let output = process.output().await?;
+    if !output.status.success() {
+        bail!("> {:#?}\n{}", process, fmt_output(&output))
+    }
+
+    Ok(output)
+}
+
+/// Compiles one of the C sources of a library (relative to the package root `root`) into an
+/// object file, using the C compiler in `CC` or `cc` if it isn't set.
+pub async fn invoke_cc<'a>(
+    root: &'a Path,
+    c_source: &'a Path,
+    object: PathBuf,
+    args: &'a [String],
+    bcx: &'a BuildContext,
+    shell: Shell,
+) -> Result<Output> {
+    let mut process = Command::new(env::var_os("CC").unwrap_or_else(|| "cc".into()));
+    process
+        .kill_on_drop(true)
+        .current_dir(root)
+        .arg("-c")
+        .arg(c_source)
+        .arg("-o")
+        .arg(object)
+        .args(args);
+
+    let _token = bcx.tokens.acquire().await;
+    shell.println_plain(format!("> {:#?}", process), Verbosity::Verbose);
+
+    let output = process.output().await?;
+    if !output.status.success() {
+        bail!("> {:#?}\n{}", process, fmt_output(&output))
     }
 
-    bail!("Compiler is overloaded on compiling: {}", target.display());
+    Ok(output)
 }
 
 // If we want to create something with output from a codegen backend (either a library or a binary)
 // we look to invoke_codegen.
 pub async fn invoke_codegen<'a>(
+    deps: &'a [Binary],
     binary: &'a [PathBuf],
     output: &'a str,
     build: PathBuf,
@@ -87,15 +142,16 @@ pub async fn invoke_codegen<'a>(
     bcx: &'a BuildContext,
     shell: Shell,
 ) -> Result<Output> {
+    let flavor = bcx.compiler.flavor();
+    if flavor.is_idris2() {
+        return invoke_codegen_idris2(deps, binary, output, build, output_dir, args, bcx, shell)
+            .await;
+    }
+
     let mut process: Command = bcx.compiler.process().into();
 
-    let flavor = bcx.compiler.flavor();
     if is_artifact {
-        if flavor.is_idris1() {
-            process.arg("--interface");
-        } else {
-            bail!("Only the Idris 1 compiler supports library artifacts");
-        }
+        process.arg("--interface");
     }
 
     process
@@ -103,7 +159,7 @@ pub async fn invoke_codegen<'a>(
         .current_dir(output_dir)
         .args(&["-o", &output])
         .args(&[
-            if bcx.backend.portable && flavor.is_idris1() {
+            if bcx.backend.portable {
                 "--portable-codegen"
             } else {
                 "--codegen"
@@ -111,31 +167,28 @@ pub async fn invoke_codegen<'a>(
             &bcx.backend.name,
         ]);
 
-    if !bcx.backend.opts.is_empty() && flavor.is_idris1() {
-        process
-            .arg("--cg-opt")
-            .arg(bcx.backend.opts.iter().join(" "));
+    // Executables have to be linked with the C code of the libraries they use
+    let mut cg_opts = bcx.backend.opts.clone();
+    if !is_artifact && bcx.backend.name == "c" {
+        let ffi = Ffi::collect(deps)?;
+        cg_opts.extend(ffi.include_args());
+        cg_opts.extend(ffi.link_args());
+    }
+
+    if !cg_opts.is_empty() {
+        process.arg("--cg-opt").arg(cg_opts.iter().join(" "));
     }
 
     process.args(args);
 
-    if flavor.is_idris1() {
-        process.arg("-i");
-        process.arg(&build);
-    } else {
-        process.env(
-            "BLODWEN_PATH",
-            binary
-                .iter()
-                .map(|x| x.parent().unwrap().to_string_lossy())
-                .join(":"),
-        );
-    }
+    process.arg("-i");
+    process.arg(&build);
 
     for bin in binary {
         process.arg(bin);
     }
 
+    let _token = bcx.tokens.acquire().await;
     shell.println_plain(format!("> {:#?}", process), Verbosity::Verbose);
 
     let output = process.output().await?;
@@ -149,3 +202,115 @@ pub async fn invoke_codegen<'a>(
 
     Ok(output)
 }
+
+/// The arguments telling Idris 2 where to find sources and put build files. The compiler is
+/// always run from the directory the sources were copied to, with build files going under `build`.
+const IDRIS2_DIRS: &[&str] = &["--source-dir", ".", "--build-dir", "build"];
+
+// Idris 2 can't generate code for a library on its own; its library outputs are just the .ttc
+// files, which compile_lib takes care of. So here, `main` is the source file of the main module
+// of an executable.
+async fn invoke_codegen_idris2<'a>(
+    deps: &'a [Binary],
+    main: &'a [PathBuf],
+    output: &'a str,
+    build: PathBuf,
+    output_dir: PathBuf,
+    args: &'a [String],
+    bcx: &'a BuildContext,
+    shell: Shell,
+) -> Result<Output> {
+    let mut process: Command = bcx.compiler.process().into();
+
+    // Idris 2 leaves executables (along with any support files they need) in build/exec.
+    let exec = build.join("build/exec");
+    clear_dir(&exec)?;
+
+    process
+        .kill_on_drop(true)
+        .current_dir(&build)
+        .args(IDRIS2_DIRS)
+        .args(&["-o", &output])
+        .args(&["--cg", idris2_cg(&bcx.backend.name)]);
+
+    for opt in &bcx.backend.opts {
+        process.arg("--directive").arg(opt);
+    }
+
+    process.args(args);
+
+    process.env(
+        "IDRIS2_PATH",
+        idris2_path(deps.iter().map(|x| x.target.path()))?,
+    );
+
+    // The C backend takes extra flags for the C compiler from the environment
+    if idris2_cg(&bcx.backend.name) == "refc" {
+        let ffi = Ffi::collect(deps)?;
+        let flags = [
+            ("IDRIS2_CFLAGS", ffi.include_args()),
+            (
+                "IDRIS2_LDFLAGS",
+                ffi.objects
+                    .iter()
+                    .map(|x| x.display().to_string())
+                    .collect(),
+            ),
+            (
+                "IDRIS2_LIBS",
+                ffi.link.iter().map(|x| format!("-l{}", x)).collect(),
+            ),
+        ];
+        for (var, values) in flags.iter().filter(|(_, values)| !values.is_empty()) {
+            let existing = env::var(var).unwrap_or_default();
+            process.env(
+                var,
+                existing
+                    .split_whitespace()
+                    .chain(values.iter().map(|x| x.as_str()))
+                    .join(" "),
+            );
+        }
+    }
+
+    for m in main {
+        process.arg(m);
+    }
+
+    let _token = bcx.tokens.acquire().await;
+    shell.println_plain(format!("> {:#?}", process), Verbosity::Verbose);
+
+    let out = process.output().await?;
+    if !out.status.success() {
+        bail!("> {:#?}\n{}", process, fmt_output(&out))
+    }
+
+    copy_dir(&exec, &output_dir, false)?;
+
+    Ok(out)
+}
+
+/// Maps the name of a codegen backend onto the name Idris 2 gives it. Most backends are called
+/// the same thing, but Idris 1's C backend is `refc` in Idris 2.
+pub fn idris2_cg(backend: &str) -> &str {
+    match backend {
+        "c" => "refc",
+        "js" => "javascript",
+        x => x,
+    }
+}
+
+/// Builds the value of `IDRIS2_PATH`, which lists the directories Idris 2 looks for .ttc files in:
+/// the given directories, then whatever was in `IDRIS2_PATH` already.
+pub fn idris2_path<'a>(dirs: impl Iterator<Item = &'a Path>) -> Result<OsString> {
+    let existing = env::var_os("IDRIS2_PATH");
+    let existing = existing
+        .iter()
+        .flat_map(env::split_paths)
+        .collect::<Vec<_>>();
+
+    let path = env::join_paths(dirs.map(|x| x.to_path_buf()).chain(existing))
+        .with_context(|e| format_err!("couldn't build IDRIS2_PATH: {}", e))?;
+
+    Ok(path)
+}
@@ -1,8 +1,12 @@
-use super::{compile_bin, compile_doc, compile_lib, context::BuildContext, Target, Targets};
+use super::{
+    compile_bin, compile_doc, compile_lib,
+    context::BuildContext,
+    script::{run_build_scripts, ScriptOutput},
+    Target, Targets,
+};
 use crate::{
-    retrieve::cache::{Binary, BuildHash, OutputLayout, Source},
+    retrieve::cache::{Binary, BuildHash, BuildMeta, OutputLayout, Source},
     util::{
-        clear_dir,
         error::Result,
         fmt_multiple,
         graph::Graph,
@@ -11,11 +15,16 @@ use crate::{
     },
 };
 use console::style;
-use failure::{bail, format_err, ResultExt};
+use failure::{bail, format_err, Error, ResultExt};
 use futures::future;
-use petgraph::graph::NodeIndex;
+use petgraph::{graph::NodeIndex, visit::Dfs};
 use slog::{debug, o, Logger};
-use std::{collections::HashSet, future::Future, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    future::Future,
+    path::PathBuf,
+};
 use tokio::runtime::Runtime;
 
 /// Work refers to either a Source and its BuildHash which needs to be built,
@@ -54,6 +63,53 @@ impl Work {
     }
 }
 
+/// What became of a job by the end of a build which kept going after failures.
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    Succeeded,
+    Failed,
+    /// The job never ran, because of these jobs it depends on (directly or not) failing
+    Skipped(Vec<NodeIndex>),
+}
+
+/// Works out what became of the jobs in `graph`: every `finished` job either succeeded or is one
+/// of the `failed` ones, and any job which was still `waiting` to be built when the build stopped
+/// was skipped, since something it depends on failed.
+fn outcomes<T: Eq>(
+    graph: &Graph<T>,
+    finished: &[NodeIndex],
+    failed: &HashSet<NodeIndex>,
+    waiting: impl Fn(&T) -> bool,
+) -> Vec<(NodeIndex, Outcome)> {
+    let mut res = finished
+        .iter()
+        .map(|index| {
+            if failed.contains(index) {
+                (*index, Outcome::Failed)
+            } else {
+                (*index, Outcome::Succeeded)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    for index in graph.inner.node_indices() {
+        if finished.contains(&index) || !waiting(&graph[index]) {
+            continue;
+        }
+
+        let mut blockers = vec![];
+        let mut dfs = Dfs::new(&graph.inner, index);
+        while let Some(dep) = dfs.next(&graph.inner) {
+            if failed.contains(&dep) {
+                blockers.push(dep);
+            }
+        }
+        res.push((index, Outcome::Skipped(blockers)));
+    }
+
+    res
+}
+
 /// A Job is an individual unit of work in the elba build graph.
 #[derive(Debug, PartialEq, Eq)]
 pub struct Job {
@@ -70,6 +126,18 @@ impl Default for Job {
     }
 }
 
+/// The results of building a package's targets.
+struct JobOutput {
+    index: NodeIndex,
+    /// The package's library, if it has one and it was built
+    binary: Option<Binary>,
+    /// The binaries which were built, along with the summary of the package they came from
+    bins: Vec<(PathBuf, String)>,
+    /// The targets which failed to build. This can only be non-empty if we're keeping going after
+    /// failures; otherwise, the first failure is returned as an error.
+    failures: Vec<Error>,
+}
+
 pub struct JobQueue {
     /// The graph of jobs which need to be done.
     pub graph: Graph<Job>,
@@ -77,6 +145,8 @@ pub struct JobQueue {
     pub logger: Logger,
     pub shell: Shell,
     pub bcx: BuildContext,
+    /// What the build scripts of the packages being built produced.
+    pub scripts: HashMap<NodeIndex, ScriptOutput>,
 }
 
 // The current implementation of the JobQueue combines target generation and dependency preparation
@@ -105,6 +175,10 @@ impl JobQueue {
         }
 
         let ver = ver.ok();
+
+        // Build scripts have to be run before anything else, since what they generate is part of
+        // the build hash
+        let scripts = run_build_scripts(&solve, &bcx, shell)?;
         let logger = plog.new(o!(
             "phase" => "build",
             "threads" => bcx.threads,
@@ -131,6 +205,7 @@ impl JobQueue {
                     &targets,
                     &bcx,
                     (node != NodeIndex::new(0) || bcx.codegen) && targets.is_codegen(),
+                    &scripts,
                 );
 
                 let root_ol = root_ol.as_ref();
@@ -177,6 +252,7 @@ impl JobQueue {
             bcx,
             logger,
             shell,
+            scripts,
         })
     }
 
@@ -200,6 +276,11 @@ impl JobQueue {
         let mut parallal_jobs_future = Vec::new();
         let mut bins_vec = Vec::new();
 
+        // The packages we've tried to build, in the order that they finished, and what went wrong
+        // with them (if anything)
+        let mut finished: Vec<(NodeIndex, Vec<Error>)> = Vec::new();
+        let mut names: HashMap<NodeIndex, String> = HashMap::new();
+
         loop {
             // Bottom jobs are Dirty jobs whose dependencies are all satisfied.
             let bottom_jobs = self.graph.inner.node_indices().filter(|&index| {
@@ -213,6 +294,9 @@ impl JobQueue {
             // Spwan new jobs
             for job in bottom_jobs {
                 if !ongoing_jobs.contains(&job) {
+                    if let Work::Dirty(source, _) = &self.graph[job].work {
+                        names.insert(job, source.pretty_summary());
+                    }
                     parallal_jobs_future.push(Box::pin(self.complete_job(job)?));
                     ongoing_jobs.insert(job);
                 }
@@ -229,7 +313,12 @@ impl JobQueue {
 
             // Handle the job result
             match job_res {
-                Ok((job_index, binary, mut bins)) => {
+                Ok(JobOutput {
+                    index: job_index,
+                    binary,
+                    mut bins,
+                    failures,
+                }) => {
                     ongoing_jobs.remove(&job_index);
 
                     // prg += 1;
@@ -263,6 +352,7 @@ impl JobQueue {
                     }
 
                     bins_vec.append(&mut bins);
+                    finished.push((job_index, failures));
                 }
                 Err(err) => {
                     // pb.finish_and_clear();
@@ -273,9 +363,31 @@ impl JobQueue {
             }
         }
 
-        // Clean up the build environment
+        if self.bcx.keep_going {
+            self.print_summary(&finished, &names);
+        }
+
+        if finished.iter().any(|(_, failures)| !failures.is_empty()) {
+            bail!("one or more packages couldn't be built");
+        }
+
+        // Clean up the build environment. The lib build directory stays around so that the next
+        // build only has to check modules which changed.
         if let Some(ol) = root_ol.as_ref() {
-            let res = clear_dir(&ol.build);
+            let res = (|| -> Result<()> {
+                for entry in fs::read_dir(&ol.build)? {
+                    let path = entry?.path();
+                    if path.ends_with("lib") || path.ends_with("fingerprints.toml") {
+                        continue;
+                    }
+                    if path.is_dir() {
+                        remove_dir_all::remove_dir_all(&path)?;
+                    } else {
+                        fs::remove_file(&path)?;
+                    }
+                }
+                Ok(())
+            })();
             if let Err(e) = res {
                 self.shell.println(
                     style("[warn]").yellow().bold(),
@@ -318,12 +430,74 @@ impl JobQueue {
         Ok((root_children, bins_vec))
     }
 
+    /// Prints which packages were built, which failed to build and which were skipped because
+    /// something they depend on failed, followed by the errors from every failure.
+    fn print_summary(
+        &self,
+        finished: &[(NodeIndex, Vec<Error>)],
+        names: &HashMap<NodeIndex, String>,
+    ) {
+        self.shell.println_empty(Verbosity::Quiet);
+        self.shell.println(
+            style("[summary]").bold(),
+            "Build results:",
+            Verbosity::Quiet,
+        );
+
+        let failed = finished
+            .iter()
+            .filter(|(_, failures)| !failures.is_empty())
+            .map(|(index, _)| *index)
+            .collect::<HashSet<_>>();
+        let finished_ixs = finished.iter().map(|(index, _)| *index).collect::<Vec<_>>();
+
+        for (index, outcome) in outcomes(&self.graph, &finished_ixs, &failed, |job| {
+            job.work.is_dirty()
+        }) {
+            match outcome {
+                Outcome::Succeeded => {
+                    self.shell
+                        .println(style("Succeeded").green(), &names[&index], Verbosity::Quiet)
+                }
+                Outcome::Failed => {
+                    self.shell
+                        .println(style("Failed").red(), &names[&index], Verbosity::Quiet)
+                }
+                Outcome::Skipped(blockers) => {
+                    // Skipped jobs never started, so they don't have a name yet
+                    if let Work::Dirty(source, _) = &self.graph[index].work {
+                        self.shell.println(
+                            style("Skipped").yellow(),
+                            format!(
+                                "{} (depends on {})",
+                                source.pretty_summary(),
+                                blockers
+                                    .iter()
+                                    .map(|dep| names[dep].as_str())
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            ),
+                            Verbosity::Quiet,
+                        );
+                    }
+                }
+            }
+        }
+
+        for (_, failures) in finished {
+            for failure in failures {
+                self.shell.println_empty(Verbosity::Quiet);
+                self.shell
+                    .println(style("[error]").red().bold(), failure, Verbosity::Quiet);
+            }
+        }
+    }
+
     // Drive a job from dirty to done
     fn complete_job(
         &self,
         job_index: NodeIndex,
-    ) -> Result<impl Future<Output = Result<(NodeIndex, Option<Binary>, Vec<(PathBuf, String)>)>>>
-    {
+    ) -> Result<impl Future<Output = Result<JobOutput>>> {
         if let Work::Dirty(source, build_hash) = &self.graph[job_index].work {
             self.shell.println(
                 style("Building").cyan(),
@@ -358,6 +532,7 @@ impl JobQueue {
                 build_hash.clone(),
                 targets,
                 deps,
+                self.scripts.get(&job_index).cloned(),
                 layout,
                 self.root_ol.is_some(),
                 self.logger.clone(),
@@ -377,138 +552,237 @@ impl JobQueue {
         build_hash: BuildHash,
         targets: Targets,
         deps: Vec<Binary>,
+        script: Option<ScriptOutput>,
         layout: OutputLayout,
         is_root: bool,
         logger: Logger,
         bcx: BuildContext,
         shell: Shell,
-    ) -> Result<(NodeIndex, Option<Binary>, Vec<(PathBuf, String)>)> {
+    ) -> Result<JobOutput> {
         let mut res: Option<Binary> = None;
         let mut bins: Vec<(PathBuf, String)> = Vec::new();
+        let mut failures = Vec::new();
         let has_lib = targets.has_lib();
 
         for target in targets.0 {
-            match target {
-                Target::Lib(cg) => {
-                    debug!(
-                        logger, "building target";
-                        "target_type" => "lib",
-                        "target" => cg,
-                        "summary" => source.summary()
-                    );
-                    let out = compile_lib(&source, cg, &deps, &layout, &bcx, shell)
+            let built = async {
+                match target {
+                    Target::Lib(cg) => {
+                        debug!(
+                            logger, "building target";
+                            "target_type" => "lib",
+                            "target" => cg,
+                            "summary" => source.summary()
+                        );
+                        let out =
+                            compile_lib(&source, cg, &deps, script.as_ref(), &layout, &bcx, shell)
+                                .await
+                                .with_context(|e| {
+                                    format!(
+                                        "Couldn't build library target for {}\n{}",
+                                        source.pretty_summary(),
+                                        e
+                                    )
+                                })?;
+
+                        res = if job_index == NodeIndex::new(0) && is_root {
+                            let out = fmt_multiple(&out);
+                            shell.println_plain(out, Verbosity::Normal);
+
+                            let target = DirLock::acquire_shared(&layout.lib)?;
+                            Some(Binary::new(target))
+                        } else {
+                            let meta = BuildMeta {
+                                compiler: bcx.compiler.version().unwrap_or_default(),
+                                backend: if cg {
+                                    Some(bcx.backend.name.clone())
+                                } else {
+                                    None
+                                },
+                            };
+                            Some(bcx.cache.store_build(&layout.lib, &build_hash, &meta)?)
+                        }
+                    }
+                    Target::Bin(ix) => {
+                        debug!(
+                            logger, "building target";
+                            "target_type" => "bin",
+                            "target" => ix,
+                            "summary" => source.summary()
+                        );
+                        let mut deps = deps.clone();
+                        let root_lib;
+                        if has_lib {
+                            root_lib = {
+                                let target = DirLock::acquire_shared(&layout.lib)?;
+                                Binary::new(target)
+                            };
+                            deps.push(root_lib);
+                        }
+                        let (out, path) = compile_bin(
+                            &source,
+                            Target::Bin(ix),
+                            &deps,
+                            script.as_ref(),
+                            &layout,
+                            &bcx,
+                            shell,
+                        )
                         .await
                         .with_context(|e| {
                             format!(
-                                "Couldn't build library target for {}\n{}",
+                                "Couldn't build binary {} for {}\n{}",
+                                ix,
                                 source.pretty_summary(),
                                 e
                             )
                         })?;
 
-                    res = if job_index == NodeIndex::new(0) && is_root {
-                        let out = fmt_multiple(&out);
-                        shell.println_plain(out, Verbosity::Normal);
+                        if let Some(p) = path {
+                            bins.push((p, source.summary()));
+                        }
 
-                        let target = DirLock::acquire(&layout.lib)?;
-                        Some(Binary::new(target))
-                    } else {
-                        Some(bcx.cache.store_build(&layout.lib, &build_hash)?)
-                    }
-                }
-                Target::Bin(ix) => {
-                    debug!(
-                        logger, "building target";
-                        "target_type" => "bin",
-                        "target" => ix,
-                        "summary" => source.summary()
-                    );
-                    let mut deps = deps.clone();
-                    let root_lib;
-                    if has_lib {
-                        root_lib = {
-                            let target = DirLock::acquire(&layout.lib)?;
-                            Binary::new(target)
-                        };
-                        deps.push(root_lib);
+                        if job_index == NodeIndex::new(0) && is_root {
+                            let out = fmt_multiple(&out);
+                            shell.println_plain(out, Verbosity::Normal);
+                        }
                     }
-                    let (out, path) =
-                        compile_bin(&source, Target::Bin(ix), &deps, &layout, &bcx, shell)
-                            .await
-                            .with_context(|e| {
-                                format!(
-                                    "Couldn't build binary {} for {}\n{}",
-                                    ix,
-                                    source.pretty_summary(),
-                                    e
-                                )
-                            })?;
+                    Target::Test(ix) => {
+                        debug!(
+                            logger, "building target";
+                            "target_type" => "test",
+                            "target" => ix,
+                            "summary" => source.summary()
+                        );
+                        let mut deps = deps.clone();
+                        let root_lib;
+                        if has_lib {
+                            root_lib = {
+                                let target = DirLock::acquire_shared(&layout.lib)?;
+                                Binary::new(target)
+                            };
+                            deps.push(root_lib);
+                        }
+                        let (out, _) = compile_bin(
+                            &source,
+                            Target::Test(ix),
+                            &deps,
+                            script.as_ref(),
+                            &layout,
+                            &bcx,
+                            shell,
+                        )
+                        .await
+                        .with_context(|e| {
+                            format!(
+                                "Couldn't build test {} for {}\n{}",
+                                ix,
+                                source.pretty_summary(),
+                                e
+                            )
+                        })?;
 
-                    if let Some(p) = path {
-                        bins.push((p, source.summary()));
-                    }
+                        if job_index == NodeIndex::new(0) && is_root {
+                            let out = fmt_multiple(&out);
+                            shell.println_plain(out, Verbosity::Normal);
+                        }
 
-                    if job_index == NodeIndex::new(0) && is_root {
-                        let out = fmt_multiple(&out);
-                        shell.println_plain(out, Verbosity::Normal);
-                    }
-                }
-                Target::Test(ix) => {
-                    debug!(
-                        logger, "building target";
-                        "target_type" => "test",
-                        "target" => ix,
-                        "summary" => source.summary()
-                    );
-                    let mut deps = deps.clone();
-                    let root_lib;
-                    if has_lib {
-                        root_lib = {
-                            let target = DirLock::acquire(&layout.lib)?;
-                            Binary::new(target)
-                        };
-                        deps.push(root_lib);
+                        // For now, only the root package can do tests, so we
+                        // don't worry about storing the binary anywhere.
                     }
-                    let (out, _) =
-                        compile_bin(&source, Target::Test(ix), &deps, &layout, &bcx, shell)
+                    Target::Doc => {
+                        debug!(
+                            logger, "building target";
+                            "target_type" => "doc",
+                            "summary" => source.summary()
+                        );
+                        let out = compile_doc(&source, &deps, &layout, &bcx)
                             .await
                             .with_context(|e| {
                                 format!(
-                                    "Couldn't build test {} for {}\n{}",
-                                    ix,
+                                    "Couldn't build docs for {}\n{}",
                                     source.pretty_summary(),
                                     e
                                 )
                             })?;
 
-                    if job_index == NodeIndex::new(0) && is_root {
-                        let out = fmt_multiple(&out);
-                        shell.println_plain(out, Verbosity::Normal);
+                        if job_index == NodeIndex::new(0) && is_root {
+                            let out_str = fmt_multiple(&out);
+                            shell.println_plain(out_str, Verbosity::Normal);
+                        }
                     }
+                }
+                Ok::<(), Error>(())
+            }
+            .await;
 
-                    // For now, only the root package can do tests, so we
-                    // don't worry about storing the binary anywhere.
+            if let Err(e) = built {
+                if !bcx.keep_going {
+                    return Err(e);
                 }
-                Target::Doc => {
-                    debug!(
-                        logger, "building target";
-                        "target_type" => "doc",
-                        "summary" => source.summary()
-                    );
-                    let out = compile_doc(&source, &deps, &layout, &bcx)
-                        .await
-                        .with_context(|e| {
-                            format!("Couldn't build docs for {}\n{}", source.pretty_summary(), e)
-                        })?;
+                failures.push(e);
 
-                    if job_index == NodeIndex::new(0) && is_root {
-                        let out_str = fmt_multiple(&out);
-                        shell.println_plain(out_str, Verbosity::Normal);
-                    }
+                // Every other target of the package uses its library, so there's nothing more we
+                // can build here.
+                if let Target::Lib(_) = target {
+                    break;
                 }
             }
         }
 
-        Ok((job_index, res, bins))
+        Ok(JobOutput {
+            index: job_index,
+            binary: res,
+            bins,
+            failures,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outcomes_skip_dependents_of_failures() {
+        // The root depends on three libraries, two of which fail to build. Another library which
+        // depends on one of the failed ones (and a fresh one) never gets built either.
+        let mut inner = petgraph::Graph::new();
+        let root = inner.add_node("root");
+        let broken = inner.add_node("broken");
+        let fine = inner.add_node("fine");
+        let dependent = inner.add_node("dependent");
+        let fresh = inner.add_node("fresh");
+        let other = inner.add_node("other");
+        inner.add_edge(root, broken, ());
+        inner.add_edge(root, fine, ());
+        inner.add_edge(root, dependent, ());
+        inner.add_edge(root, other, ());
+        inner.add_edge(dependent, broken, ());
+        inner.add_edge(dependent, fresh, ());
+        let graph = Graph::new(inner);
+
+        let failed = vec![broken, other].into_iter().collect::<HashSet<_>>();
+        let res = outcomes(&graph, &[fine, broken, other], &failed, |&job| {
+            job != "fresh"
+        });
+
+        assert_eq!(
+            res[..3],
+            [
+                (fine, Outcome::Succeeded),
+                (broken, Outcome::Failed),
+                (other, Outcome::Failed),
+            ]
+        );
+        assert_eq!(res[4], (dependent, Outcome::Skipped(vec![broken])));
+        match &res[3] {
+            (index, Outcome::Skipped(blockers)) if *index == root => {
+                let blockers = blockers.iter().collect::<HashSet<_>>();
+                assert_eq!(blockers, vec![&broken, &other].into_iter().collect());
+            }
+            x => panic!("expected the root to be skipped, got {:?}", x),
+        }
+        assert_eq!(res.len(), 5);
     }
 }
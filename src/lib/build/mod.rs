@@ -1,36 +1,49 @@
 //! Actually building Idris packages.
 
 pub mod context;
+pub mod ffi;
 pub mod invoke;
 pub mod job;
+pub mod modules;
+pub mod script;
 
 use std::{
+    collections::{HashMap, HashSet},
     env,
-    ffi::OsStr,
-    fs,
+    ffi::{OsStr, OsString},
+    fs, iter,
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Output},
 };
 
 use console::style;
 use failure::{bail, format_err, ResultExt};
 use futures::future;
 use itertools::Itertools;
-use rand::{distributions::Alphanumeric, seq::SliceRandom, thread_rng, Rng};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use walkdir::WalkDir;
 
 use self::{
     context::BuildContext,
-    invoke::{invoke_codegen, invoke_compile},
+    ffi::build_ffi,
+    invoke::{invoke_codegen, invoke_compile, invoke_ipkg},
+    modules::{module_path, sync_sources, Fingerprints, ModuleGraph},
+    script::ScriptOutput,
 };
 use crate::{
+    package::{
+        ipkg::{Dialect, Ipkg},
+        manifest::{LibTarget, Manifest},
+    },
     retrieve::cache::{Binary, OutputLayout, Source},
     util::{
-        clear_dir, copy_dir, copy_dir_iter,
+        clear_dir,
+        config::Driver,
+        copy_dir, copy_dir_iter,
         error::Result,
         fmt_multiple, fmt_output, generate_ipkg,
         shell::{OutputGroup, Shell, Verbosity},
-        valid_file,
+        valid_file, SubPath,
     },
 };
 
@@ -140,11 +153,12 @@ pub async fn compile_lib<'a>(
     source: &'a Source,
     codegen: bool,
     deps: &'a [Binary],
+    script: Option<&'a ScriptOutput>,
     layout: &'a OutputLayout,
     bcx: &'a BuildContext,
     shell: Shell,
 ) -> Result<OutputGroup> {
-    let lib_target = source.meta().targets.lib.clone().ok_or_else(|| {
+    let mut lib_target = source.meta().targets.lib.clone().ok_or_else(|| {
         format_err!(
             "package {} doesn't contain a lib target",
             source.meta().package.name
@@ -153,22 +167,31 @@ pub async fn compile_lib<'a>(
 
     // We know that lib_target.path will be relative to the package root
     let src_path = source.path().join(&lib_target.path.0);
-    let mut targets = lib_target
+
+    // Modules generated by the build script are built along with the ones in the source directory
+    let mut src_roots = vec![src_path.as_path()];
+    if let Some(script) = script {
+        lib_target.mods.extend(script.modules.iter().cloned());
+        lib_target
+            .idris_opts
+            .extend(script.idris_opts.iter().cloned());
+        src_roots.push(&script.out_dir);
+    }
+
+    let targets = lib_target
         .mods
         .iter()
         .map(|mod_name| {
-            let path: PathBuf = mod_name.trim_matches('.').replace(".", "/").into();
-            if src_path.join(&path).with_extension("idr").exists() {
-                Ok(path.with_extension("idr"))
-            } else if src_path.join(&path).with_extension("lidr").exists() {
-                Ok(path.with_extension("lidr"))
-            } else {
-                Err(format_err!(
-                    "module {} isn't a subpath and doesn't exist under path {}",
-                    mod_name,
-                    src_path.display()
-                ))
-            }
+            src_roots
+                .iter()
+                .find_map(|root| module_path(root, mod_name))
+                .ok_or_else(|| {
+                    format_err!(
+                        "module {} isn't a subpath and doesn't exist under path {}",
+                        mod_name,
+                        src_path.display()
+                    )
+                })
         })
         .collect::<Result<Vec<_>>>()?;
 
@@ -179,67 +202,39 @@ pub async fn compile_lib<'a>(
     let src_walker = source
         .meta()
         .list_files(source.path(), &src_path, |x| x.path() != layout.build)?;
-
-    clear_dir(&layout.build.join("lib"))?;
-    copy_dir_iter(src_walker, &src_path, &layout.build.join("lib"))?;
-
-    run_prebuild_script(source, &layout.build.join("lib"), shell)?;
-
-    // shuffle the targets to decreases the prosiblity that complier
-    // overloads because of paralleling
-    {
-        let mut rng = thread_rng();
-        targets.shuffle(&mut rng);
-    }
-
-    let mut compilations = targets.iter().map(|target| {
-        let module = target
-            .with_extension("")
-            .to_string_lossy()
-            .replace("/", ".")
-            .replace("\\", ".");
-
-        shell.println(
-            style("Compiling").cyan(),
-            format!("{} [{}]", module, source.meta().name()),
-            Verbosity::Normal,
-        );
-
-        invoke_compile(deps, &target, layout.build.join("lib"), &args, bcx, shell)
-    });
-
-    let mut outputs = Vec::new();
-
-    // Invoke compilations in parallel
-    let mut ongoing_compilation = Vec::new();
-    loop {
-        while let Some(compile) = compilations.next() {
-            ongoing_compilation.push(Box::pin(compile));
-            if ongoing_compilation.len() >= bcx.threads as usize {
-                break;
-            }
+    let generated = script
+        .into_iter()
+        .flat_map(|script| WalkDir::new(&script.out_dir))
+        .filter_map(|x| x.ok().filter(valid_file));
+
+    // The build directory is kept around between builds, so that modules which haven't changed
+    // don't need to be checked again.
+    let flavor = bcx.compiler.flavor();
+    let build_lib = layout.build.join("lib");
+    sync_sources(src_walker.chain(generated), &src_roots, &build_lib, flavor)?;
+
+    run_prebuild_script(source, &build_lib, layout, bcx, shell)?;
+
+    let outputs = match lib_target.driver.unwrap_or(bcx.driver) {
+        Driver::Modules => {
+            check_modules(
+                source, &targets, &build_lib, deps, &args, layout, bcx, shell,
+            )
+            .await?
         }
-
-        if ongoing_compilation.is_empty() {
-            break;
+        Driver::Ipkg => {
+            vec![build_ipkg(source, &lib_target, deps, &args, layout, bcx, shell).await?]
         }
-
-        let (output, _, remaining) = future::select_all(ongoing_compilation).await;
-        match output {
-            Ok(output) => outputs.push(output),
-            Err(err) => {
-                bail!(err);
-            }
-        }
-        ongoing_compilation = remaining;
-    }
+    };
 
     let mut res = OutputGroup(outputs);
 
     clear_dir(&layout.lib)?;
 
-    let from = if bcx.compiler.flavor().is_idris2() {
-        layout.build.join("lib/build")
+    // Idris 2 keeps its build outputs apart from the sources, so we can take them as they are.
+    // Any TTC version directory is kept, since Idris 2 looks for it in IDRIS2_PATH directories.
+    let from = if flavor.is_idris2() {
+        layout.build.join("lib/build/ttc")
     } else {
         layout.build.join("lib")
     };
@@ -262,12 +257,26 @@ pub async fn compile_lib<'a>(
     clear_dir(&layout.lib)?;
     copy_dir_iter(lib_files.clone().into_iter(), &from, &layout.lib)?;
 
-    if codegen {
+    let ffi_outputs = build_ffi(
+        source,
+        lib_target.ffi.as_ref(),
+        deps,
+        &layout.lib.join("ffi"),
+        bcx,
+        shell,
+    )
+    .await?;
+    res.0.extend(ffi_outputs.0);
+
+    if codegen && flavor.is_idris2() {
+        // Idris 2 doesn't have export lists, so the closest thing to library artifacts are the
+        // .ttc files themselves.
+        let artifacts = layout.artifacts.join(&bcx.backend.name);
+        clear_dir(&artifacts)?;
+        copy_dir_iter(lib_files.into_iter(), &from, &artifacts)?;
+    } else if codegen {
         clear_dir(&layout.artifacts.join(&bcx.backend.name))?;
 
-        // TODO: Idris 2 now doesn't support lib interface,
-        // so it's temporarily safe to assume all the codegen
-        // targets are ibc files.
         let lib_bins = lib_files
             .into_iter()
             .map(|x| x.into_path())
@@ -275,6 +284,7 @@ pub async fn compile_lib<'a>(
             .collect::<Vec<_>>();
 
         let output = invoke_codegen(
+            deps,
             &lib_bins,
             source.meta().name().name(),
             layout.build.join("lib"),
@@ -292,17 +302,190 @@ pub async fn compile_lib<'a>(
     Ok(res)
 }
 
+/// Checks every module of a library which has changed since the last build (or which imports one
+/// that has), along with the modules which haven't been checked yet.
+#[allow(clippy::too_many_arguments)]
+async fn check_modules<'a>(
+    source: &'a Source,
+    targets: &'a [PathBuf],
+    build_lib: &'a Path,
+    deps: &'a [Binary],
+    args: &'a [String],
+    layout: &'a OutputLayout,
+    bcx: &'a BuildContext,
+    shell: Shell,
+) -> Result<Vec<Output>> {
+    // Anything that affects every module goes into the fingerprint of every module
+    let mut salt = bcx.compiler.version().unwrap_or_default();
+    salt.extend(args.iter().map(|x| format!("\n{}", x)));
+    salt.extend(
+        deps.iter()
+            .map(|x| format!("\n{}", x.target.path().display())),
+    );
+
+    let graph = ModuleGraph::new(build_lib, targets)?;
+    let mut fingerprints = Fingerprints::load(&layout.build.join("fingerprints.toml"));
+    let flavor = bcx.compiler.flavor();
+
+    // Modules which are already up to date count as built from the start
+    let mut built = HashSet::new();
+    let mut pending = vec![];
+    let mut new_fingerprints = HashMap::new();
+    for (module, info) in graph.modules() {
+        let fingerprint = graph.fingerprint(module, &salt);
+        let output = if flavor.is_idris2() {
+            ttc_dir(&build_lib.join("build"))
+                .join(&info.path)
+                .with_extension("ttc")
+        } else {
+            build_lib.join(&info.path).with_extension("ibc")
+        };
+
+        if fingerprints.is_fresh(module, &fingerprint) && output.exists() {
+            shell.println(
+                style("Fresh").dim(),
+                format!("{} [{}]", module, source.meta().name()),
+                Verbosity::Verbose,
+            );
+            built.insert(module.clone());
+        } else {
+            pending.push(module.clone());
+            new_fingerprints.insert(module.clone(), fingerprint);
+        }
+    }
+
+    let mut outputs = Vec::new();
+
+    // Modules are compiled in dependency order: a module only gets compiled once everything it
+    // imports has been, so parallel compilations never fight over the same build files. Any
+    // modules whose imports are all done can be compiled in parallel.
+    let mut ongoing_compilation = Vec::new();
+    loop {
+        let ready = graph.take_ready(
+            &mut pending,
+            &built,
+            bcx.threads as usize,
+            ongoing_compilation.len(),
+        )?;
+        for module in ready {
+            let path = &graph.get(&module).unwrap().path;
+            let fingerprint = new_fingerprints.remove(&module).unwrap();
+
+            shell.println(
+                style("Compiling").cyan(),
+                format!("{} [{}]", module, source.meta().name()),
+                Verbosity::Normal,
+            );
+
+            let compile = invoke_compile(deps, path, build_lib.to_path_buf(), args, bcx, shell);
+            ongoing_compilation.push(Box::pin(async move {
+                compile.await.map(|x| (x, module, fingerprint))
+            }));
+        }
+
+        // Modules which can never be compiled are an error, so once nothing is being compiled,
+        // there's nothing left to compile
+        if ongoing_compilation.is_empty() {
+            break;
+        }
+
+        let (output, _, remaining) = future::select_all(ongoing_compilation).await;
+        ongoing_compilation = remaining;
+        match output {
+            Ok((output, module, fingerprint)) => {
+                outputs.push(output);
+                fingerprints.insert(module.clone(), fingerprint);
+                built.insert(module);
+            }
+            Err(err) => {
+                // Whatever did get checked doesn't need to be checked again next time
+                fingerprints.save()?;
+                bail!(err);
+            }
+        }
+    }
+
+    fingerprints.save()?;
+
+    Ok(outputs)
+}
+
+/// Builds a library by generating an ipkg for it and having the compiler build that, so that the
+/// compiler can decide for itself what needs to be rebuilt.
+///
+/// The compiler only ever builds into the library's build directory in the cache; installing the
+/// package would put it in the compiler's global library directory instead. `--install` just
+/// copies the bytecode that `--build` produced, which `compile_lib` collects from the build
+/// directory itself, so the outputs are the same as with `Driver::Modules`.
+async fn build_ipkg<'a>(
+    source: &'a Source,
+    lib_target: &'a LibTarget,
+    deps: &'a [Binary],
+    args: &'a [String],
+    layout: &'a OutputLayout,
+    bcx: &'a BuildContext,
+    shell: Shell,
+) -> Result<Output> {
+    let flavor = bcx.compiler.flavor();
+    let dialect = if flavor.is_idris2() {
+        Dialect::Idris2
+    } else {
+        Dialect::Idris1
+    };
+
+    // The ipkg only describes the library itself: the sources have already been copied into the
+    // build directory, and dependencies are passed to the compiler by path rather than by name.
+    let mut opts = vec![];
+    if flavor.is_idris1() {
+        for binary in deps {
+            opts.push("-i".to_owned());
+            opts.push(binary.target.path().to_string_lossy().into_owned());
+        }
+    }
+    opts.extend(args.iter().cloned());
+
+    let mut manifest = source.meta().clone();
+    manifest.dependencies.clear();
+    manifest.scripts.clear();
+    manifest.targets.bin.clear();
+    manifest.targets.test.clear();
+    manifest.targets.lib = Some(LibTarget {
+        path: SubPath::from_path(Path::new("."))?,
+        mods: lib_target.mods.clone(),
+        idris_opts: opts,
+        driver: lib_target.driver,
+        ffi: None,
+    });
+    let ipkg = Ipkg::from_manifest(manifest, dialect)?;
+
+    let name = source.meta().name().name();
+    let ipkg_path = layout.build.join("lib").join(format!("{}.ipkg", name));
+    fs::write(&ipkg_path, ipkg.to_string().as_bytes())
+        .with_context(|e| format_err!("couldn't create .ipkg file:\n{}", e))?;
+
+    // Whatever the compiler does to the build files, the fingerprints of the modules can't be
+    // trusted anymore
+    let _ = fs::remove_file(layout.build.join("fingerprints.toml"));
+
+    shell.println(
+        style("Compiling").cyan(),
+        format!("{}.ipkg [{}]", name, source.meta().name()),
+        Verbosity::Normal,
+    );
+
+    invoke_ipkg(deps, &ipkg_path, bcx, shell).await
+}
+
 pub async fn compile_bin<'a>(
     source: &'a Source,
     target: Target,
     deps: &'a [Binary],
+    script: Option<&'a ScriptOutput>,
     layout: &'a OutputLayout,
     bcx: &'a BuildContext,
     shell: Shell,
 ) -> Result<(OutputGroup, Option<PathBuf>)> {
-    if bcx.compiler.flavor().is_idris2() {
-        bail!("The Idris 2 compiler currently can't build executables")
-    }
+    let flavor = bcx.compiler.flavor();
 
     let bin_target = match target {
         Target::Bin(ix) => source.meta().targets.bin[ix].clone(),
@@ -320,15 +503,19 @@ pub async fn compile_bin<'a>(
 
     clear_dir(&layout.build.join("bin"))?;
     copy_dir(&src_path, &layout.build.join("bin"), false)?;
+    if let Some(script) = script {
+        copy_dir(&script.out_dir, &layout.build.join("bin"), false)?;
+    }
 
-    run_prebuild_script(source, &layout.build.join("bin"), shell)?;
+    run_prebuild_script(source, &layout.build.join("bin"), layout, bcx, shell)?;
 
     // The Idris compiler ignores and rebuilds the imported
     // ibc modules if there are idrs match the modules name in
     // the source directory. So we copy the ibcs into the build
-    // directory in advance to avoid that.
+    // directory in advance to avoid that. (Idris 2 keeps its
+    // build files apart from the sources, so this doesn't apply.)
     if let Some(lib_target) = &source.meta().targets.lib {
-        if lib_target.path == bin_target.path {
+        if lib_target.path == bin_target.path && flavor.is_idris1() {
             copy_dir(&layout.lib, &layout.build.join("bin"), false)?;
         }
     }
@@ -341,7 +528,12 @@ pub async fn compile_bin<'a>(
                 .to_string_lossy()
                 .replace("/", ".")
                 .replace("\\", ".");
-            make_main_file(mod_name, &*ext.to_string_lossy(), &layout.build.join("bin"))?
+            make_main_file(
+                mod_name,
+                &*ext.to_string_lossy(),
+                &layout.build.join("bin"),
+                bcx,
+            )?
         } else {
             target_path
         }
@@ -351,6 +543,9 @@ pub async fn compile_bin<'a>(
 
     let mut args = vec![];
     args.extend(bin_target.idris_opts.iter().map(|x| x.to_owned()));
+    if let Some(script) = script {
+        args.extend(script.idris_opts.iter().cloned());
+    }
     args.extend(bcx.opts.iter().cloned());
 
     let module = if target_path.is_absolute() {
@@ -384,7 +579,15 @@ pub async fn compile_bin<'a>(
 
     let mut res = OutputGroup::from(output);
 
-    let target_bin = target_path.with_extension("ibc");
+    // Idris 2 generates code straight from the main module, reusing the .ttc files it just made
+    let target_bin = if flavor.is_idris2() {
+        target_path.clone()
+    } else {
+        layout
+            .build
+            .join("bin")
+            .join(target_path.with_extension("ibc"))
+    };
 
     let name = if let Some(ex) = &bcx.backend.extension {
         let p: PathBuf = bin_target.name.into();
@@ -397,7 +600,7 @@ pub async fn compile_bin<'a>(
         return Ok((res, None));
     }
 
-    let binarys = &[layout.build.join("bin").join(&target_bin)];
+    let binarys = &[target_bin];
     let output_name = &*name.to_string_lossy();
 
     shell.println(
@@ -407,6 +610,7 @@ pub async fn compile_bin<'a>(
     );
 
     let output = invoke_codegen(
+        deps,
         binarys,
         output_name,
         layout.build.join("bin"),
@@ -477,11 +681,16 @@ pub async fn compile_doc<'a>(
 
     clear_dir(&layout.build.join("docs"))?;
 
-    let mut process = bcx.compiler.process();
-    process.current_dir(&layout.build);
-    process.arg("--mkdoc").arg(".ipkg");
+    let mut process: tokio::process::Command = bcx.compiler.process().into();
+    process
+        .kill_on_drop(true)
+        .current_dir(&layout.build)
+        .arg("--mkdoc")
+        .arg(".ipkg");
 
-    let res = process.output()?;
+    let token = bcx.tokens.acquire().await;
+    let res = process.output().await?;
+    drop(token);
     if !res.status.success() {
         bail!(
             "> {:#?}\n--- stdout\n{}\n--- stderr\n{}",
@@ -507,15 +716,63 @@ pub async fn compile_doc<'a>(
     Ok(res.into())
 }
 
-pub fn run_script(root: &Path, cmd: &str) -> Result<OutputGroup> {
+pub fn run_script(root: &Path, cmd: &str, env: &[(&str, OsString)]) -> Result<OutputGroup> {
+    let mut process = script_process(root, cmd, &[], env);
+
+    let res = process.output()?;
+    if !res.status.success() {
+        bail!("> {:#?}\n{}", process, fmt_output(&res))
+    }
+
+    Ok(res.into())
+}
+
+/// Runs a shell command like `run_script`, but lets it use elba's stdin, stdout and stderr, so
+/// its output shows up as it's printed. `args` are passed on to the command as extra arguments.
+pub fn exec_script(
+    root: &Path,
+    cmd: &str,
+    args: &[String],
+    env: &[(&str, OsString)],
+) -> Result<()> {
+    let mut process = script_process(root, cmd, args, env);
+
+    let status = process
+        .status()
+        .with_context(|e| format_err!("couldn't run `{}`: {}", cmd, e))?;
+    if !status.success() {
+        match status.code() {
+            Some(code) => bail!("`{}` exited with code {}", cmd, code),
+            None => bail!("`{}` was terminated by a signal", cmd),
+        }
+    }
+
+    Ok(())
+}
+
+fn script_process(root: &Path, cmd: &str, args: &[String], env: &[(&str, OsString)]) -> Command {
     let mut process = if cfg!(target_os = "windows") {
         let mut p = Command::new("cmd");
-        p.args(&["/C", cmd]);
+        let quoted = args.iter().map(|arg| {
+            if arg.contains(char::is_whitespace) {
+                format!("\"{}\"", arg)
+            } else {
+                arg.clone()
+            }
+        });
+        p.arg("/C")
+            .arg(iter::once(cmd.to_owned()).chain(quoted).join(" "));
         p
     } else {
+        // The arguments become the positional parameters of the shell, which `"$@"` expands to
+        // without any further splitting or globbing.
         let mut p = Command::new("sh");
         p.arg("-c");
-        p.arg(cmd);
+        if args.is_empty() {
+            p.arg(cmd);
+        } else {
+            p.arg(format!("{} \"$@\"", cmd)).arg("sh").args(args);
+        }
         p
     };
 
@@ -525,41 +782,192 @@ pub fn run_script(root: &Path, cmd: &str) -> Result<OutputGroup> {
         process.env("PATH", v);
     }
 
-    let res = process.output()?;
-    if !res.status.success() {
-        bail!("> {:#?}\n{}", process, fmt_output(&res))
+    for (var, val) in env {
+        process.env(var, val);
     }
 
-    Ok(res.into())
+    process
+}
+
+/// A script which elba runs by itself at some point of a command, if the manifest has a script
+/// named after it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hook {
+    /// Run after dependencies are built and before the package is built, from the directory the
+    /// package gets built in
+    PreBuild,
+    /// Run after `elba build` builds the package
+    PostBuild,
+    /// Run after the tests are built but before they're run
+    PreTest,
+    /// Run after every test passed
+    PostTest,
+    /// Run after the package is built and before its binaries are installed
+    PreInstall,
+    /// Run after the binaries are installed
+    PostInstall,
+    /// Run before the package's tarball is made
+    PrePackage,
+    /// Run before the package is added to an index
+    PrePublish,
+}
+
+impl Hook {
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::PreBuild => "prebuild",
+            Hook::PostBuild => "postbuild",
+            Hook::PreTest => "pretest",
+            Hook::PostTest => "posttest",
+            Hook::PreInstall => "preinstall",
+            Hook::PostInstall => "postinstall",
+            Hook::PrePackage => "prepackage",
+            Hook::PrePublish => "prepublish",
+        }
+    }
+}
+
+/// What a hook is told about the package and the build it's part of.
+pub struct HookEnv<'a> {
+    pub manifest: &'a Manifest,
+    /// The root of the package, whose tools can be run by name
+    pub root: &'a Path,
+    /// Where the build outputs go
+    pub target_dir: &'a Path,
+    pub backend: &'a str,
+    pub profile: &'a str,
+}
+
+impl HookEnv<'_> {
+    pub fn vars(&self) -> Result<Vec<(&'static str, OsString)>> {
+        Ok(vec![
+            ("ELBA_PKG_NAME", self.manifest.name().as_str().into()),
+            (
+                "ELBA_PKG_VERSION",
+                self.manifest.version().to_string().into(),
+            ),
+            ("ELBA_TARGET_DIR", self.target_dir.into()),
+            ("ELBA_BACKEND", self.backend.into()),
+            ("ELBA_PROFILE", self.profile.into()),
+            ("PATH", tools_path(self.root)?),
+        ])
+    }
+}
+
+/// Where the tools of the package at `root` get built. The directory is hidden so that it can't
+/// clash with the output directory of a profile.
+pub fn tools_dir(root: &Path) -> PathBuf {
+    root.join("target").join(".tools")
+}
+
+/// The value of `PATH` for running a package's scripts, hooks and tasks, which can run the
+/// binaries of the package's tools by name.
+pub fn tools_path(root: &Path) -> Result<OsString> {
+    let bin_dir = tools_dir(root).join("bin");
+    let existing = env::var_os("PATH");
+    let existing = existing
+        .iter()
+        .flat_map(env::split_paths)
+        .collect::<Vec<_>>();
+
+    let path = env::join_paths(iter::once(bin_dir).chain(existing))
+        .with_context(|e| format_err!("couldn't build PATH: {}", e))?;
+
+    Ok(path)
 }
 
-pub fn run_prebuild_script(source: &Source, root: &Path, shell: Shell) -> Result<()> {
-    if let Some(s) = source.meta().scripts.get("prebuild") {
+/// Runs the script for `hook` from the directory `root`, if the package has one.
+pub fn run_hook(hook: Hook, root: &Path, env: &HookEnv, shell: Shell) -> Result<()> {
+    if !env.manifest.scripts.contains_key(hook.name()) {
+        return Ok(());
+    }
+
+    for cmd in env.manifest.script(hook.name())? {
         shell.println(
             style("Running").dim(),
-            format!("prebuild script > {}", s),
+            format!("{} script > {}", hook.name(), cmd),
             Verbosity::Verbose,
         );
-        shell.println_plain(fmt_multiple(&run_script(root, s)?), Verbosity::Normal);
+        let out = run_script(root, &cmd, &env.vars()?)
+            .with_context(|e| format_err!("{} script failed:\n{}", hook.name(), e))?;
+        shell.println_plain(fmt_multiple(&out), Verbosity::Normal);
     }
 
     Ok(())
 }
 
-fn make_main_file(module: &str, fun: &str, parent: &Path) -> Result<PathBuf> {
+pub fn run_prebuild_script(
+    source: &Source,
+    root: &Path,
+    layout: &OutputLayout,
+    bcx: &BuildContext,
+    shell: Shell,
+) -> Result<()> {
+    let env = HookEnv {
+        manifest: source.meta(),
+        root: source.path(),
+        target_dir: &layout.root,
+        backend: &bcx.backend.name,
+        profile: &bcx.profile,
+    };
+
+    run_hook(Hook::PreBuild, root, &env, shell)
+}
+
+fn make_main_file(module: &str, fun: &str, parent: &Path, bcx: &BuildContext) -> Result<PathBuf> {
     let rstr: String = thread_rng().sample_iter(&Alphanumeric).take(8).collect();
-    let fname = format!("elba-{}.idr", rstr);
+    // Idris 2 insists that module names match file names, but doesn't need the main module to be
+    // called Main.
+    let idris2 = bcx.compiler.flavor().is_idris2();
+    let (fname, main_mod) = if idris2 {
+        let main_mod = format!("Elba{}", rstr);
+        (format!("{}.idr", main_mod), main_mod)
+    } else {
+        (format!("elba-{}.idr", rstr), "Main".to_string())
+    };
     fs::write(
         parent.join(&fname),
         format!(
-            r#"module Main
+            r#"module {}
 
 import {}
 
 main : IO ()
 main = {}"#,
-            module, fun
+            main_mod, module, fun
         ),
     )?;
-    Ok(parent.join(fname))
+
+    // Idris 2 works out module names from paths relative to the source directory
+    if idris2 {
+        Ok(fname.into())
+    } else {
+        Ok(parent.join(fname))
+    }
+}
+
+/// Finds where Idris 2 put the .ttc files under the build directory `build`. Newer versions of
+/// Idris 2 nest them in a directory named after the version of the TTC format.
+pub fn ttc_dir(build: &Path) -> PathBuf {
+    let ttc = build.join("ttc");
+    let versioned = fs::read_dir(&ttc).ok().and_then(|entries| {
+        let dirs = entries
+            .filter_map(|x| x.ok())
+            .map(|x| x.path())
+            .collect::<Vec<_>>();
+        match dirs.as_slice() {
+            [dir]
+                if dir.is_dir()
+                    && dir
+                        .file_name()
+                        .map(|x| x.to_string_lossy().chars().all(|c| c.is_ascii_digit()))
+                        .unwrap_or(false) =>
+            {
+                Some(dir.clone())
+            }
+            _ => None,
+        }
+    });
+
+    versioned.unwrap_or(ttc)
 }
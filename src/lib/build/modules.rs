@@ -0,0 +1,435 @@
+//! Working out the modules of a package and how they depend on each other, so that modules
+//! which haven't changed since the last build don't need to be checked again.
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use failure::bail;
+use indexmap::{IndexMap, IndexSet};
+use itertools::Itertools;
+use sha2::{Digest, Sha256};
+use walkdir::DirEntry;
+
+use crate::{
+    build::{context::CompilerFlavor, ttc_dir},
+    util::{error::Result, parser::find_imports},
+};
+
+/// A module of the package being built.
+#[derive(Debug, Clone)]
+pub struct ModuleInfo {
+    /// The path to the module's source file, relative to the source directory
+    pub path: PathBuf,
+    /// The modules of this package which this module imports. Imports of modules from other
+    /// packages aren't included.
+    pub imports: Vec<String>,
+    /// A hash of the module's source
+    hash: String,
+}
+
+/// Every module of a package reachable from its targets, along with their imports.
+#[derive(Debug, Clone, Default)]
+pub struct ModuleGraph {
+    modules: IndexMap<String, ModuleInfo>,
+}
+
+impl ModuleGraph {
+    /// Finds every module that the module files `roots` (relative to `src`) import, directly or
+    /// not, within `src`.
+    pub fn new(src: &Path, roots: &[PathBuf]) -> Result<Self> {
+        let mut modules = IndexMap::new();
+        let mut queue = roots.to_vec();
+
+        while let Some(path) = queue.pop() {
+            let name = module_name(&path);
+            if modules.contains_key(&name) {
+                continue;
+            }
+
+            let contents = fs::read_to_string(src.join(&path))?;
+            let literate = path.extension().map(|x| x == "lidr").unwrap_or(false);
+
+            let mut imports = vec![];
+            for import in find_imports(&contents, literate) {
+                if let Some(import_path) = module_path(src, &import.0) {
+                    queue.push(import_path);
+                    imports.push(import.0);
+                }
+            }
+
+            let hash = hex::encode(Sha256::digest(contents.as_bytes()));
+            modules.insert(
+                name,
+                ModuleInfo {
+                    path,
+                    imports,
+                    hash,
+                },
+            );
+        }
+
+        Ok(ModuleGraph { modules })
+    }
+
+    pub fn get(&self, module: &str) -> Option<&ModuleInfo> {
+        self.modules.get(module)
+    }
+
+    /// Every module in the graph, sorted by name.
+    pub fn modules(&self) -> impl Iterator<Item = (&String, &ModuleInfo)> {
+        self.modules.iter().sorted_by(|a, b| a.0.cmp(b.0))
+    }
+
+    /// Fingerprints a module: the fingerprint changes whenever the module, anything it imports
+    /// (directly or not), or `salt` changes.
+    pub fn fingerprint(&self, module: &str, salt: &str) -> String {
+        let mut seen = IndexSet::new();
+        self.visit(module, &mut seen);
+
+        // The order doesn't matter, only the contents
+        let mut hashes = seen
+            .iter()
+            .filter_map(|x| self.modules.get(*x))
+            .map(|x| (x.path.to_string_lossy(), &x.hash))
+            .collect::<Vec<_>>();
+        hashes.sort();
+
+        let mut hasher = Sha256::default();
+        hasher.input(salt.as_bytes());
+        for (path, hash) in hashes {
+            hasher.input(path.as_bytes());
+            hasher.input(hash.as_bytes());
+        }
+        hex::encode(hasher.result())
+    }
+
+    /// Takes the modules which can be compiled now out of `pending`: a module is ready once
+    /// everything it imports has been `built`. No more are taken than there are threads left
+    /// over by the `compiling` modules. If nothing is being compiled and none of the pending
+    /// modules are ready, they can never be, since they import each other in a cycle.
+    pub fn take_ready(
+        &self,
+        pending: &mut Vec<String>,
+        built: &HashSet<String>,
+        threads: usize,
+        compiling: usize,
+    ) -> Result<Vec<String>> {
+        let mut ready = vec![];
+        let mut ix = 0;
+        while ix < pending.len() && compiling + ready.len() < threads.max(1) {
+            let imported = self
+                .modules
+                .get(&pending[ix])
+                .map(|info| info.imports.iter().all(|x| built.contains(x)))
+                .unwrap_or(true);
+            if imported {
+                ready.push(pending.remove(ix));
+            } else {
+                ix += 1;
+            }
+        }
+
+        if ready.is_empty() && compiling == 0 && !pending.is_empty() {
+            bail!(
+                "couldn't find an order to compile modules in; there's an import cycle between \
+                 some of these modules: {}",
+                pending.join(", ")
+            );
+        }
+
+        Ok(ready)
+    }
+
+    fn visit<'a>(&'a self, module: &'a str, seen: &mut IndexSet<&'a str>) {
+        if !seen.insert(module) {
+            return;
+        }
+        if let Some(info) = self.modules.get(module) {
+            for import in &info.imports {
+                self.visit(import, seen);
+            }
+        }
+    }
+}
+
+/// The fingerprints of every module as of the last time it was successfully checked.
+#[derive(Debug, Default)]
+pub struct Fingerprints {
+    path: PathBuf,
+    modules: IndexMap<String, String>,
+}
+
+impl Fingerprints {
+    /// Loads the fingerprints stored at `path`. A missing or corrupt file just means that nothing
+    /// is fresh.
+    pub fn load(path: &Path) -> Self {
+        let modules = fs::read_to_string(path)
+            .ok()
+            .and_then(|x| toml::from_str(&x).ok())
+            .unwrap_or_default();
+
+        Fingerprints {
+            path: path.to_path_buf(),
+            modules,
+        }
+    }
+
+    pub fn is_fresh(&self, module: &str, fingerprint: &str) -> bool {
+        self.modules.get(module).map(|x| x.as_str()) == Some(fingerprint)
+    }
+
+    pub fn insert(&mut self, module: String, fingerprint: String) {
+        self.modules.insert(module, fingerprint);
+    }
+
+    pub fn save(&self) -> Result<()> {
+        fs::write(&self.path, toml::to_string(&self.modules)?)?;
+        Ok(())
+    }
+}
+
+/// Converts a path to a module file into the name of the module.
+pub fn module_name(path: &Path) -> String {
+    path.with_extension("")
+        .to_string_lossy()
+        .replace("/", ".")
+        .replace("\\", ".")
+}
+
+/// Finds the source file of a module under `src`, if it's there.
+pub fn module_path(src: &Path, module: &str) -> Option<PathBuf> {
+    let path: PathBuf = module.trim_matches('.').replace(".", "/").into();
+    if src.join(&path).with_extension("idr").exists() {
+        Some(path.with_extension("idr"))
+    } else if src.join(&path).with_extension("lidr").exists() {
+        Some(path.with_extension("lidr"))
+    } else {
+        None
+    }
+}
+
+/// Makes the files under `to` match the files listed by `walker` (which live under one of the
+/// directories in `from`).
+///
+/// Unlike clearing `to` and copying everything over, files which haven't changed are left alone,
+/// so the compiler can tell by their modification times that their build outputs are still good.
+/// Source files which have disappeared are removed along with their build outputs: the `.ibc`
+/// next to them for Idris 1, or the `.ttc` and `.ttm` files under `build/ttc` for Idris 2.
+pub fn sync_sources(
+    walker: impl Iterator<Item = DirEntry>,
+    from: &[&Path],
+    to: &Path,
+    flavor: CompilerFlavor,
+) -> Result<()> {
+    let mut synced = HashSet::new();
+
+    for entry in walker {
+        let rel = from
+            .iter()
+            .find_map(|from| entry.path().strip_prefix(from).ok())
+            .unwrap()
+            .to_path_buf();
+        let dest = to.join(&rel);
+
+        let contents = fs::read(entry.path())?;
+        if fs::read(&dest).ok().as_ref() != Some(&contents) {
+            fs::create_dir_all(dest.parent().unwrap())?;
+            fs::write(&dest, &contents)?;
+        }
+
+        synced.insert(rel);
+    }
+
+    if !to.exists() {
+        return Ok(());
+    }
+
+    for entry in walkdir::WalkDir::new(to).into_iter().filter_map(|x| x.ok()) {
+        let is_source = entry
+            .path()
+            .extension()
+            .map(|x| x == "idr" || x == "lidr")
+            .unwrap_or(false);
+        let rel = entry.path().strip_prefix(to).unwrap();
+        if is_source && !synced.contains(rel) {
+            fs::remove_file(entry.path())?;
+            if flavor.is_idris2() {
+                let output = ttc_dir(&to.join("build")).join(rel);
+                let _ = fs::remove_file(output.with_extension("ttc"));
+                let _ = fs::remove_file(output.with_extension("ttm"));
+            } else {
+                let _ = fs::remove_file(entry.path().with_extension("ibc"));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprints_follow_imports() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let src = tmp.path();
+        fs::create_dir_all(src.join("Data")).unwrap();
+        fs::write(src.join("Main.idr"), "module Main\nimport Data.Tree\n").unwrap();
+        fs::write(
+            src.join("Data/Tree.idr"),
+            "module Data.Tree\nimport Prelude\n",
+        )
+        .unwrap();
+        fs::write(src.join("Other.idr"), "module Other\n").unwrap();
+
+        let roots = vec![PathBuf::from("Main.idr"), PathBuf::from("Other.idr")];
+        let graph = ModuleGraph::new(src, &roots).unwrap();
+        // Imports from outside of the package don't count
+        assert_eq!(graph.get("Data.Tree").unwrap().imports.len(), 0);
+        assert_eq!(graph.get("Main").unwrap().imports, vec!["Data.Tree"]);
+
+        let main = graph.fingerprint("Main", "");
+        let other = graph.fingerprint("Other", "");
+
+        fs::write(src.join("Data/Tree.idr"), "module Data.Tree\n\nleaf : ()\n").unwrap();
+        let graph = ModuleGraph::new(src, &roots).unwrap();
+        assert_ne!(graph.fingerprint("Main", ""), main);
+        assert_eq!(graph.fingerprint("Other", ""), other);
+        assert_ne!(graph.fingerprint("Other", "--total"), other);
+    }
+
+    fn sync(src: &Path, to: &Path, flavor: CompilerFlavor) {
+        let walker = walkdir::WalkDir::new(src)
+            .into_iter()
+            .filter_map(|x| x.ok())
+            .filter(|x| x.file_type().is_file());
+        sync_sources(walker, &[src], to, flavor).unwrap();
+    }
+
+    #[test]
+    fn sync_removes_stale_outputs() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let src = tmp.path().join("src");
+        let to = tmp.path().join("build");
+        fs::create_dir_all(src.join("Data")).unwrap();
+        fs::write(src.join("Main.idr"), "module Main\n").unwrap();
+        fs::write(src.join("Data/Tree.idr"), "module Data.Tree\n").unwrap();
+        sync(&src, &to, CompilerFlavor::Idris1);
+        assert!(to.join("Data/Tree.idr").exists());
+
+        fs::write(to.join("Main.ibc"), "").unwrap();
+        fs::write(to.join("Data/Tree.ibc"), "").unwrap();
+        fs::remove_file(src.join("Data/Tree.idr")).unwrap();
+        sync(&src, &to, CompilerFlavor::Idris1);
+        assert!(!to.join("Data/Tree.idr").exists());
+        assert!(!to.join("Data/Tree.ibc").exists());
+        assert!(to.join("Main.ibc").exists());
+
+        // Idris 2 keeps its outputs under build/ttc, possibly in a TTC version directory
+        for ttc in &["build/ttc", "build/ttc/2021"] {
+            let ttc = to.join(ttc);
+            fs::write(src.join("Data/Tree.idr"), "module Data.Tree\n").unwrap();
+            sync(&src, &to, CompilerFlavor::Idris2);
+            fs::create_dir_all(ttc.join("Data")).unwrap();
+            for output in &["Main.ttc", "Main.ttm", "Data/Tree.ttc", "Data/Tree.ttm"] {
+                fs::write(ttc.join(output), "").unwrap();
+            }
+
+            fs::remove_file(src.join("Data/Tree.idr")).unwrap();
+            sync(&src, &to, CompilerFlavor::Idris2);
+            assert!(!ttc.join("Data/Tree.ttc").exists());
+            assert!(!ttc.join("Data/Tree.ttm").exists());
+            assert!(ttc.join("Main.ttc").exists());
+            assert!(ttc.join("Main.ttm").exists());
+            fs::remove_dir_all(to.join("build")).unwrap();
+        }
+    }
+
+    fn graph(modules: &[(&str, &[&str])]) -> ModuleGraph {
+        let modules = modules
+            .iter()
+            .map(|(name, imports)| {
+                let info = ModuleInfo {
+                    path: PathBuf::from(format!("{}.idr", name)),
+                    imports: imports.iter().map(|x| x.to_string()).collect(),
+                    hash: String::new(),
+                };
+                (name.to_string(), info)
+            })
+            .collect();
+        ModuleGraph { modules }
+    }
+
+    fn names(modules: &[&str]) -> Vec<String> {
+        modules.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn ready_modules_follow_imports() {
+        let graph = graph(&[
+            ("Main", &["Data.Tree", "Data.List"]),
+            ("Data.Tree", &["Data.Core"]),
+            ("Data.List", &["Data.Core"]),
+            ("Data.Core", &[]),
+        ]);
+        let mut pending = names(&["Data.Core", "Data.List", "Data.Tree", "Main"]);
+        let mut built = HashSet::new();
+
+        let ready = graph.take_ready(&mut pending, &built, 4, 0).unwrap();
+        assert_eq!(ready, names(&["Data.Core"]));
+        // Nothing else is ready until the module being compiled is done
+        assert!(graph
+            .take_ready(&mut pending, &built, 4, 1)
+            .unwrap()
+            .is_empty());
+
+        built.extend(ready);
+        let ready = graph.take_ready(&mut pending, &built, 4, 0).unwrap();
+        assert_eq!(ready, names(&["Data.List", "Data.Tree"]));
+
+        built.extend(ready);
+        let ready = graph.take_ready(&mut pending, &built, 4, 0).unwrap();
+        assert_eq!(ready, names(&["Main"]));
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn ready_modules_capped_by_threads() {
+        let graph = graph(&[("A", &[]), ("B", &[]), ("C", &[]), ("D", &[])]);
+        let mut pending = names(&["A", "B", "C", "D"]);
+        let built = HashSet::new();
+
+        assert_eq!(
+            graph.take_ready(&mut pending, &built, 2, 0).unwrap(),
+            names(&["A", "B"])
+        );
+        assert!(graph
+            .take_ready(&mut pending, &built, 2, 2)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            graph.take_ready(&mut pending, &built, 2, 1).unwrap(),
+            names(&["C"])
+        );
+        assert_eq!(pending, names(&["D"]));
+    }
+
+    #[test]
+    fn ready_modules_import_cycle() {
+        let graph = graph(&[("A", &["B"]), ("B", &["A"]), ("C", &[])]);
+        let mut pending = names(&["A", "B", "C"]);
+        let mut built = HashSet::new();
+
+        let ready = graph.take_ready(&mut pending, &built, 2, 0).unwrap();
+        assert_eq!(ready, names(&["C"]));
+        built.extend(ready);
+
+        let err = graph.take_ready(&mut pending, &built, 2, 0).unwrap_err();
+        assert!(err.to_string().contains("import cycle"));
+        assert!(err.to_string().ends_with("A, B"));
+    }
+}
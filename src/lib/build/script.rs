@@ -0,0 +1,357 @@
+//! Build scripts: Idris programs which are compiled and run before a package is built.
+//!
+//! A build script runs from the root of its package, with the following environment variables
+//! set:
+//!
+//! - `ELBA_OUT_DIR`: where the script should put any modules it generates
+//! - `ELBA_PKG_NAME` and `ELBA_PKG_VERSION`: the name and version of the package
+//! - `ELBA_BACKEND`: the name of the codegen backend the package is being built with
+//! - `ELBA_PROFILE`: the build profile
+//!
+//! It tells elba what to do with its results by printing lines to stdout:
+//!
+//! - `elba:module=A.B` makes the generated module `A.B` part of the library
+//! - `elba:idris-opt=--opt` passes `--opt` to the compiler when building the package
+//!
+//! Everything a build script tells elba, along with the contents of the modules it generates, is
+//! part of the package's build hash; builds only get reused if the build script did the same thing.
+//!
+//! A build script is only run again once the package's source or the compiler, backend or profile
+//! it's built with changes; until then, what it did last time gets reused.
+
+use std::{collections::HashMap, fs, path::PathBuf, process::Command};
+
+use console::style;
+use failure::{bail, format_err, ResultExt};
+use petgraph::graph::NodeIndex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::runtime::Runtime;
+use walkdir::WalkDir;
+
+use super::{
+    context::BuildContext,
+    invoke::{invoke_codegen, invoke_compile},
+};
+use crate::{
+    retrieve::cache::{BuildHash, OutputLayout, Source},
+    util::{
+        clear_dir,
+        error::Result,
+        fmt_output,
+        graph::Graph,
+        shell::{Shell, Verbosity},
+        valid_file,
+    },
+};
+
+/// What a package's build script left behind for the build.
+#[derive(Debug, Clone)]
+pub struct ScriptOutput {
+    /// The directory the generated modules are in
+    pub out_dir: PathBuf,
+    /// Generated modules which should be part of the library
+    pub modules: Vec<String>,
+    pub idris_opts: Vec<String>,
+    /// A digest of everything the build script produced
+    pub hash: String,
+    // The script's output directory is kept locked until the package is built
+    _layout: OutputLayout,
+}
+
+/// What a build script did the last time it ran successfully, kept in its output directory.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct ScriptRecord {
+    modules: Vec<String>,
+    idris_opts: Vec<String>,
+    hash: String,
+}
+
+impl ScriptRecord {
+    const FILE: &'static str = "script.toml";
+
+    /// Loads the record of the last run, if the last run was made with the same `key`.
+    fn load(layout: &OutputLayout, key: &BuildHash) -> Option<Self> {
+        if !layout.is_built(key) {
+            return None;
+        }
+        let contents = fs::read_to_string(layout.root.join(Self::FILE)).ok()?;
+        toml::from_str(&contents).ok()
+    }
+
+    fn store(&self, layout: &OutputLayout, key: &BuildHash) -> Result<()> {
+        fs::write(layout.root.join(Self::FILE), toml::to_string(self)?)
+            .context(format_err!("couldn't write build script record"))?;
+        // The hash goes last, so that a half-written record never gets loaded
+        layout.write_hash(key)
+    }
+}
+
+/// The key a build script's output is reused under: the package's source, along with everything
+/// about the build context which the script gets to see.
+fn script_key(source: &Source, bcx: &BuildContext) -> BuildHash {
+    let mut hasher = Sha256::default();
+    hasher.input(b"build script");
+    hasher.input(source.hash().as_bytes());
+    if let Ok(ver) = bcx.compiler.version() {
+        hasher.input(ver.as_bytes());
+    }
+    hasher.input(bcx.profile.as_bytes());
+    hasher.input(bcx.backend.name.as_bytes());
+    for opt in &bcx.backend.opts {
+        hasher.input(opt.as_bytes());
+    }
+    for x in bcx
+        .backend
+        .extension
+        .iter()
+        .chain(bcx.backend.runner.iter())
+    {
+        hasher.input(x.as_bytes());
+    }
+
+    BuildHash(hex::encode(hasher.result()))
+}
+
+/// Runs the build scripts of every package in `sources` which has one.
+pub fn run_build_scripts(
+    sources: &Graph<Source>,
+    bcx: &BuildContext,
+    shell: Shell,
+) -> Result<HashMap<NodeIndex, ScriptOutput>> {
+    let scripts = sources
+        .inner
+        .node_indices()
+        .filter(|&node| sources[node].meta().package.build.is_some())
+        .collect::<Vec<_>>();
+
+    if scripts.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let mut rt = Runtime::new().with_context(|_| format_err!("Couldn't start parallel runtime"))?;
+    rt.block_on(async {
+        let mut res = HashMap::new();
+        for node in scripts {
+            let source = &sources[node];
+            let output = run_build_script(source, bcx, shell)
+                .await
+                .with_context(|e| {
+                    format!(
+                        "Couldn't run build script for {}\n{}",
+                        source.pretty_summary(),
+                        e
+                    )
+                })?;
+            res.insert(node, output);
+        }
+
+        Ok(res)
+    })
+}
+
+async fn run_build_script(
+    source: &Source,
+    bcx: &BuildContext,
+    shell: Shell,
+) -> Result<ScriptOutput> {
+    let script = source.meta().package.build.as_ref().unwrap();
+    let script_path = source.path().join(&script.0);
+    let file_name = PathBuf::from(script.0.file_name().unwrap());
+
+    let key = script_key(source, bcx);
+    let layout = bcx.cache.checkout_script(&key)?;
+    let out_dir = layout.artifacts.join("out");
+
+    if let Some(record) = ScriptRecord::load(&layout, &key) {
+        shell.println(
+            style("Fresh").dim(),
+            format!("build script [{}]", source.meta().name()),
+            Verbosity::Verbose,
+        );
+        return Ok(ScriptOutput {
+            out_dir,
+            modules: record.modules,
+            idris_opts: record.idris_opts,
+            hash: record.hash,
+            _layout: layout,
+        });
+    }
+
+    let build = layout.build.join("script");
+    clear_dir(&build)?;
+    fs::copy(&script_path, build.join(&file_name)).with_context(|e| {
+        format_err!(
+            "couldn't copy build script {}: {}",
+            script_path.display(),
+            e
+        )
+    })?;
+
+    shell.println(
+        style("Compiling").cyan(),
+        format!("{} [{}]", script.0.display(), source.meta().name()),
+        Verbosity::Normal,
+    );
+
+    invoke_compile(&[], &file_name, build.clone(), &[], bcx, shell).await?;
+
+    // Idris 2 generates code straight from the source file
+    let bin = if bcx.compiler.flavor().is_idris2() {
+        file_name.clone()
+    } else {
+        build.join(&file_name).with_extension("ibc")
+    };
+    let name = match &bcx.backend.extension {
+        Some(ext) => PathBuf::from("build").with_extension(ext),
+        None => PathBuf::from("build"),
+    };
+    invoke_codegen(
+        &[],
+        &[bin],
+        &name.to_string_lossy(),
+        build,
+        layout.bin.clone(),
+        false,
+        &[],
+        bcx,
+        shell,
+    )
+    .await?;
+
+    let exe = layout.bin.join(&name);
+    let exe = if !exe.exists() && exe.with_extension("exe").exists() {
+        exe.with_extension("exe")
+    } else {
+        exe
+    };
+
+    clear_dir(&out_dir)?;
+
+    let mut process = if let Some(runner) = &bcx.backend.runner {
+        let mut p = Command::new(runner);
+        p.arg(&exe);
+        p
+    } else {
+        Command::new(&exe)
+    };
+    process
+        .current_dir(source.path())
+        .env("ELBA_OUT_DIR", &out_dir)
+        .env("ELBA_PKG_NAME", source.meta().name().as_str())
+        .env("ELBA_PKG_VERSION", source.meta().version().to_string())
+        .env("ELBA_BACKEND", &bcx.backend.name)
+        .env("ELBA_PROFILE", &bcx.profile);
+
+    shell.println(
+        style("Running").cyan(),
+        format!("build script [{}]", source.meta().name()),
+        Verbosity::Normal,
+    );
+    shell.println_plain(format!("> {:#?}", process), Verbosity::Verbose);
+
+    // The script could be doing just as much work as a compiler
+    let _token = bcx.tokens.acquire().await;
+    let output = process
+        .output()
+        .with_context(|e| format_err!("couldn't run build script {}: {}", exe.display(), e))?;
+    if !output.status.success() {
+        bail!("> {:#?}\n{}", process, fmt_output(&output))
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let (modules, idris_opts) = parse_instructions(&stdout, shell)?;
+
+    let mut hasher = Sha256::default();
+    for x in modules.iter().chain(idris_opts.iter()) {
+        hasher.input(x.as_bytes());
+        hasher.input(b"\n");
+    }
+    let generated = WalkDir::new(&out_dir)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_map(|x| x.ok().filter(valid_file));
+    for entry in generated {
+        let rel = entry.path().strip_prefix(&out_dir).unwrap();
+        hasher.input(rel.to_string_lossy().as_bytes());
+        hasher.input(&fs::read(entry.path())?);
+    }
+
+    let record = ScriptRecord {
+        modules,
+        idris_opts,
+        hash: hex::encode(hasher.result()),
+    };
+    record.store(&layout, &key)?;
+
+    Ok(ScriptOutput {
+        out_dir,
+        modules: record.modules,
+        idris_opts: record.idris_opts,
+        hash: record.hash,
+        _layout: layout,
+    })
+}
+
+/// Picks out the generated modules and compiler options from the output of a build script. Any
+/// other output is only shown when being verbose.
+fn parse_instructions(stdout: &str, shell: Shell) -> Result<(Vec<String>, Vec<String>)> {
+    let mut modules = vec![];
+    let mut idris_opts = vec![];
+    for line in stdout.lines() {
+        let line = line.trim();
+        if let Some(instr) = line.strip_prefix("elba:") {
+            let mut split = instr.splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some("module"), Some(module)) => modules.push(module.trim().to_owned()),
+                (Some("idris-opt"), Some(opt)) => idris_opts.push(opt.to_owned()),
+                _ => bail!("the build script printed an invalid instruction: {}", line),
+            }
+        } else {
+            shell.println_plain(line, Verbosity::Verbose);
+        }
+    }
+
+    Ok((modules, idris_opts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_script_instructions() {
+        let stdout = "checking for libfoo... yes\n\
+                      elba:module=Gen.Config\n\
+                      elba:idris-opt=--cg-opt=-lfoo\n  \
+                      elba:module= Gen.Paths \n";
+        let (modules, opts) = parse_instructions(stdout, Shell::default()).unwrap();
+        assert_eq!(modules, vec!["Gen.Config", "Gen.Paths"]);
+        assert_eq!(opts, vec!["--cg-opt=-lfoo"]);
+
+        assert!(parse_instructions("elba:rerun-if-changed=x", Shell::default()).is_err());
+    }
+
+    #[test]
+    fn build_script_record() {
+        extern crate tempdir;
+        use crate::util::lock::DirLock;
+
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let layout = || OutputLayout::new(DirLock::acquire(tmp.path()).unwrap()).unwrap();
+        let key = BuildHash("abc".to_owned());
+        let record = ScriptRecord {
+            modules: vec!["Gen.Config".to_owned()],
+            idris_opts: vec!["--cg-opt=-lfoo".to_owned()],
+            hash: "def".to_owned(),
+        };
+
+        assert_eq!(ScriptRecord::load(&layout(), &key), None);
+        record.store(&layout(), &key).unwrap();
+        assert_eq!(ScriptRecord::load(&layout(), &key), Some(record));
+        assert_eq!(
+            ScriptRecord::load(&layout(), &BuildHash("xyz".to_owned())),
+            None
+        );
+    }
+}
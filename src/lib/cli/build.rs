@@ -2,7 +2,7 @@ use std::{
     convert::TryInto,
     env, fs,
     io::{prelude::*, Seek, SeekFrom},
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     process::Command,
     str::FromStr,
 };
@@ -10,34 +10,44 @@ use std::{
 use console::style;
 use crossbeam::queue::MsQueue;
 use failure::{bail, format_err, ResultExt};
-use indexmap::IndexMap;
-use itertools::Either::{self, Left, Right};
+use indexmap::{indexmap, IndexMap};
+use itertools::{
+    Either::{self, Left, Right},
+    Itertools,
+};
 use petgraph::{graph::NodeIndex, visit::Dfs};
 use scoped_threadpool::Pool;
+use serde::Serialize;
 use slog::Logger;
 use toml;
 use toml_edit;
 
 use crate::{
     build::{
-        context::{BuildContext, Compiler},
+        context::{BuildContext, Compiler, JobTokens},
+        invoke::idris2_path,
         job::{Job, JobQueue},
-        Target, Targets,
+        run_hook, tools_dir, Hook, HookEnv, Target, Targets,
     },
     package::{
         ipkg::Ipkg,
         lockfile::LockfileToml,
-        manifest::{BinTarget, Manifest},
+        manifest::{BinTarget, Manifest, Profile},
         PackageId, Spec, Summary,
     },
-    remote::resolution::{DirectRes, IndexRes, Resolution},
+    remote::{
+        resolution::{DirectRes, IndexRes, Resolution},
+        Indices,
+    },
     resolve::Resolver,
     retrieve::{
         cache::{Cache, Layout, OutputLayout},
         Retriever,
     },
     util::{
-        config::Backend,
+        clear_dir,
+        config::{Backend, Driver, Http, Net},
+        copy_dir,
         error::Result,
         fmt_output,
         graph::Graph,
@@ -50,10 +60,22 @@ pub struct BuildCtx {
     pub compiler: String,
     pub indices: IndexMap<String, IndexRes>,
     pub global_cache: Layout,
+    pub net: Net,
+    pub http: Http,
     pub logger: Logger,
     pub threads: u32,
+    /// The tokens every compiler process started by this invocation has to hold, no matter which
+    /// package or tool it's building
+    pub tokens: JobTokens,
     pub shell: Shell,
     pub offline: bool,
+    pub keep_going: bool,
+    /// The name of the build profile to use
+    pub profile: String,
+    /// How libraries get built, unless their manifest says otherwise
+    pub driver: Driver,
+    /// Whether to skip the package's hooks (other than prebuild)
+    pub no_hooks: bool,
     pub opts: Vec<String>,
 }
 
@@ -65,12 +87,14 @@ pub fn test(
     test_threads: u32,
 ) -> Result<String> {
     let (project, manifest) = find_manifest(project, true, None)?;
+    let profile = manifest.profile(&ctx.profile)?;
 
     if manifest.targets.test.is_empty() {
         bail!("at least one test must be defined")
     }
 
     solve_local(&ctx, &project, 3, None, |cache, mut retriever, solve| {
+        let (solve, _) = split_tools(&manifest, solve);
         let sources = retriever
             .retrieve_packages(&solve)
             .context(format_err!("package retrieval failed"))?;
@@ -84,9 +108,13 @@ pub fn test(
             backend: backend.clone(),
             codegen: true,
             compiler: Compiler::new(&ctx.compiler)?,
-            opts: ctx.opts.clone(),
+            opts: profile_opts(&profile, ctx),
             cache: cache.clone(),
             threads: ctx.threads,
+            tokens: ctx.tokens.clone(),
+            keep_going: ctx.keep_going,
+            driver: ctx.driver,
+            profile: ctx.profile.clone(),
         };
 
         ctx.shell.println(
@@ -96,7 +124,7 @@ pub fn test(
         );
 
         // We want to store the outputs of our labor in a local target directory.
-        let lock = DirLock::acquire(&project.join("target"))?;
+        let lock = DirLock::acquire(&project.join("target").join(&ctx.profile))?;
         let layout = OutputLayout::new(lock).context("could not create local target directory")?;
 
         let bin_dir = layout.bin.clone();
@@ -119,10 +147,21 @@ pub fn test(
             }
         }
 
+        let target_dir = layout.root.clone();
+        let env = HookEnv {
+            manifest: &manifest,
+            root: &project,
+            target_dir: &target_dir,
+            backend: &backend.name,
+            profile: &ctx.profile,
+        };
+
         let root = Targets::new(root);
         let q = JobQueue::new(sources, &root, Some(layout), bctx, &ctx.logger, ctx.shell)?;
         q.exec()?;
 
+        hook(ctx, Hook::PreTest, &project, &env)?;
+
         ctx.shell.println(
             style("[3/3]").dim().bold(),
             "Running tests...",
@@ -212,6 +251,7 @@ pub fn test(
                 errs
             ))
         } else {
+            hook(ctx, Hook::PostTest, &project, &env)?;
             Ok(format!("{} test binaries executed", root.len()))
         }
     })
@@ -221,10 +261,21 @@ pub fn install(
     ctx: &BuildCtx,
     name: Either<Spec, PathBuf>,
     targets: &[&str],
-    backend: &Backend,
+    mut backend: impl FnMut(&Profile) -> Backend,
     force: bool,
 ) -> Result<String> {
+    // The tools of a local package get resolved along with it, but they aren't part of what
+    // gets installed
+    let local = match &name {
+        Left(_) => None,
+        Right(path) => Some(find_manifest(path, true, None)?.1),
+    };
+
     let f = |cache: &Cache, mut retriever: Retriever, solve| -> Result<String> {
+        let solve = match &local {
+            Some(manifest) => split_tools(manifest, solve).0,
+            None => solve,
+        };
         let sources = retriever
             .retrieve_packages(&solve)
             .context(format_err!("package retrieval failed"))?;
@@ -239,6 +290,10 @@ pub fn install(
             .context(format_err!("failed to read manifest file (elba.toml)"))?;
         manifest.read_to_string(&mut contents)?;
         let manifest = Manifest::from_str(&contents)?;
+        // A remote package's profiles can't be seen before it's been retrieved, so this is
+        // where the backend gets picked too
+        let profile = manifest.profile(&ctx.profile)?;
+        let backend = backend(&profile);
 
         // By default, we build all bin targets.
         let mut root = vec![];
@@ -249,14 +304,19 @@ pub fn install(
             }
         }
         let root = Targets::new(root);
+        let root_path = sources[NodeIndex::new(0)].path().to_path_buf();
 
         let bctx = BuildContext {
             backend: backend.clone(),
             codegen: true,
             compiler: Compiler::new(&ctx.compiler)?,
-            opts: ctx.opts.clone(),
+            opts: profile_opts(&profile, ctx),
             cache: cache.clone(),
             threads: ctx.threads,
+            tokens: ctx.tokens.clone(),
+            keep_going: ctx.keep_going,
+            driver: ctx.driver,
+            profile: ctx.profile.clone(),
         };
 
         ctx.shell.println(
@@ -274,6 +334,15 @@ pub fn install(
         let bins = q.exec()?.1;
         let binc = bins.len();
 
+        let env = HookEnv {
+            manifest: &manifest,
+            root: &root_path,
+            target_dir: &cache.layout.bin,
+            backend: &backend.name,
+            profile: &ctx.profile,
+        };
+        hook(ctx, Hook::PreInstall, &root_path, &env)?;
+
         ctx.shell.println(
             style("[3/3]").dim().bold(),
             "Installing binaries...",
@@ -281,6 +350,8 @@ pub fn install(
         );
         cache.store_bins(&bins, force)?;
 
+        hook(ctx, Hook::PostInstall, &root_path, &env)?;
+
         Ok(format!(
             "{} binaries installed into {}",
             binc,
@@ -309,13 +380,14 @@ pub fn repl(
     interactivity: Interactivity,
 ) -> Result<String> {
     let (project, manifest) = find_manifest(project, true, None)?;
+    let profile = manifest.profile(&ctx.profile)?;
 
     env::set_current_dir(&project)?;
 
     let mut parents = vec![];
     let mut paths = vec![];
 
-    if let Some(lib) = manifest.targets.lib {
+    if let Some(lib) = &manifest.targets.lib {
         if targets.1.is_none() || targets.0 {
             let src_path = lib.path.0.clone();
             let new_paths = lib
@@ -340,7 +412,7 @@ pub fn repl(
         }
     }
 
-    for bin in manifest.targets.bin {
+    for bin in &manifest.targets.bin {
         if let Some(v) = targets.1.as_ref() {
             if v.contains(&bin.name.as_ref()) {
                 let resolved = bin.resolve_bin(Path::new(".")).ok_or_else(|| {
@@ -367,6 +439,7 @@ pub fn repl(
     }
 
     solve_local(ctx, &project, 3, None, |cache, mut retriever, solve| {
+        let (solve, _) = split_tools(&manifest, solve);
         let sources = retriever
             .retrieve_packages(&solve)
             .context(format_err!("package retrieval failed"))?;
@@ -385,9 +458,13 @@ pub fn repl(
             backend: backend.clone(),
             codegen: true,
             compiler: Compiler::new(&ctx.compiler)?,
-            opts: ctx.opts.clone(),
+            opts: profile_opts(&profile, ctx),
             cache: cache.clone(),
             threads: ctx.threads,
+            tokens: ctx.tokens.clone(),
+            keep_going: ctx.keep_going,
+            driver: ctx.driver,
+            profile: ctx.profile.clone(),
         };
 
         ctx.shell.println(
@@ -413,28 +490,81 @@ pub fn repl(
             Verbosity::Quiet,
         );
 
-        if bctx.compiler.flavor().is_idris2() {
-            bail!("The Idris 2 compiler doesn't currently support custom source paths, needed for the REPL.")
-        }
+        let idris2 = bctx.compiler.flavor().is_idris2();
+        // Idris 2 puts its build files in a directory of their own, which we throw away afterwards
+        let repl_build = project.join("target").join(&ctx.profile).join("repl");
 
         let mut process = bctx.compiler.process();
-        for binary in deps {
-            // We assume that deps have already been compiled
-            process.arg("-i").arg(binary);
-        }
+        let mut files = paths
+            .iter()
+            .map(|(parent, path)| (&parents[*parent], path))
+            .collect::<Vec<_>>();
+
+        if idris2 {
+            process.env(
+                "IDRIS2_PATH",
+                idris2_path(deps.iter().map(|x| x.as_path()))?,
+            );
 
-        for path in &parents {
-            process.arg("--sourcepath").arg(path);
-            process.arg("-i").arg(path);
+            // Idris 2 only takes one source directory
+            let srcs = parents
+                .iter()
+                .map(|x| {
+                    x.components()
+                        .filter(|x| *x != Component::CurDir)
+                        .collect::<PathBuf>()
+                })
+                .unique()
+                .collect::<Vec<_>>();
+            match srcs.as_slice() {
+                [] => {}
+                [src] => {
+                    process.arg("--source-dir").arg(src);
+                }
+                _ => bail!(
+                    "the selected targets live in different directories ({}), but Idris 2 can only \
+                     load files from one source directory; pick targets which share one",
+                    srcs.iter().map(|x| x.display()).join(", ")
+                ),
+            }
+            process.arg("--build-dir").arg(&repl_build);
+
+            // ...and only loads one file
+            if files.len() > 1 {
+                ctx.shell.println(
+                    style("[warn]").yellow().bold(),
+                    format!(
+                        "Idris 2 can only load one file into the REPL; loading {}",
+                        files[0].1.display()
+                    ),
+                    Verbosity::Normal,
+                );
+                files.truncate(1);
+            }
+        } else {
+            for binary in deps {
+                // We assume that deps have already been compiled
+                process.arg("-i").arg(binary);
+            }
+
+            for path in &parents {
+                process.arg("--sourcepath").arg(path);
+                process.arg("-i").arg(path);
+            }
         }
 
         // We add the arguments in the build context at the end so that any
         // conflicting flags will be ignored (idris chooses the earliest flags first)
-        process.args(&ctx.opts);
+        process.args(&bctx.opts);
 
         // Add the files we want to make available for the repl
-        for target in &paths {
-            process.arg(&target.1);
+        for (parent, path) in &files {
+            if idris2 {
+                // Idris 2 wants paths to files, not paths relative to the source directory
+                process.arg(parent.join(path));
+            } else {
+                process.arg(path);
+            }
         }
 
         match interactivity {
@@ -457,14 +587,25 @@ pub fn repl(
             .with_context(|e| format_err!("misc. repl failure:\n{}", e))?;
 
         // Clean up after ourselves
-        for target in &paths {
-            let src_path = &parents[target.0];
-            let bin = src_path.join(&target.1).with_extension("ibc");
-            if bin.exists() {
-                fs::remove_file(&bin).with_context(|e| {
-                    format_err!("couldn't remove ibc file {}:\n{}", bin.display(), e)
+        if idris2 {
+            if repl_build.exists() {
+                remove_dir_all::remove_dir_all(&repl_build).with_context(|e| {
+                    format_err!(
+                        "couldn't remove build directory {}:\n{}",
+                        repl_build.display(),
+                        e
+                    )
                 })?;
             }
+        } else {
+            for (parent, path) in &files {
+                let bin = parent.join(path).with_extension("ibc");
+                if bin.exists() {
+                    fs::remove_file(&bin).with_context(|e| {
+                        format_err!("couldn't remove ibc file {}:\n{}", bin.display(), e)
+                    })?;
+                }
+            }
         }
 
         Ok("finished repl session".to_string())
@@ -473,6 +614,7 @@ pub fn repl(
 
 pub fn doc(ctx: &BuildCtx, project: &Path) -> Result<String> {
     let (project, manifest) = find_manifest(project, true, None)?;
+    let profile = manifest.profile(&ctx.profile)?;
 
     // By default, we build all lib and bin targets.
     let mut root = vec![];
@@ -486,6 +628,7 @@ pub fn doc(ctx: &BuildCtx, project: &Path) -> Result<String> {
     let root = Targets::new(root);
 
     solve_local(ctx, &project, 2, None, |cache, mut retriever, solve| {
+        let (solve, _) = split_tools(&manifest, solve);
         let sources = retriever
             .retrieve_packages(&solve)
             .context(format_err!("package retrieval failed"))?;
@@ -502,9 +645,13 @@ pub fn doc(ctx: &BuildCtx, project: &Path) -> Result<String> {
             backend,
             codegen: true,
             compiler: Compiler::new(&ctx.compiler)?,
-            opts: ctx.opts.clone(),
+            opts: profile_opts(&profile, ctx),
             cache: cache.clone(),
             threads: ctx.threads,
+            tokens: ctx.tokens.clone(),
+            keep_going: ctx.keep_going,
+            driver: ctx.driver,
+            profile: ctx.profile.clone(),
         };
 
         ctx.shell.println(
@@ -514,7 +661,7 @@ pub fn doc(ctx: &BuildCtx, project: &Path) -> Result<String> {
         );
 
         // We want to store the outputs of our labor in a local target directory.
-        let lock = DirLock::acquire(&project.join("target"))?;
+        let lock = DirLock::acquire(&project.join("target").join(&ctx.profile))?;
         let layout = OutputLayout::new(lock).context("could not create local target directory")?;
 
         let q = JobQueue::new(sources, &root, Some(layout), bctx, &ctx.logger, ctx.shell)?;
@@ -522,7 +669,10 @@ pub fn doc(ctx: &BuildCtx, project: &Path) -> Result<String> {
         // process. Yay abstraction!
         q.exec()?;
 
-        Ok("docs output available at `./target/docs`".to_string())
+        Ok(format!(
+            "docs output available at `./target/{}/docs`",
+            ctx.profile
+        ))
     })
 }
 
@@ -534,6 +684,7 @@ pub fn build(
     backend: &Backend,
 ) -> Result<String> {
     let (project, manifest) = find_manifest(project, true, None)?;
+    let profile = manifest.profile(&ctx.profile)?;
 
     // By default, we build all lib and bin targets.
     let mut root = vec![];
@@ -577,10 +728,16 @@ pub fn build(
 
     let root = Targets::new(root);
     solve_local(ctx, &project, 2, None, |cache, mut retriever, solve| {
+        let (solve, tools) = split_tools(&manifest, solve);
         let sources = retriever
             .retrieve_packages(&solve)
             .context(format_err!("package retrieval failed"))?;
 
+        // The package's tools go in target/.tools, where scripts and `elba task` can find them
+        if codegen && !tools.is_empty() {
+            build_tools(ctx, &project, cache, &mut retriever, &tools, backend)?;
+        }
+
         // We drop the Retriever because we want to release our lock on the Indices as soon as we
         // can to avoid stopping other instances of elba from downloading and resolving (even
         // though we don't even need the Retriever anymore).
@@ -590,9 +747,13 @@ pub fn build(
             backend: backend.clone(),
             codegen,
             compiler: Compiler::new(&ctx.compiler)?,
-            opts: ctx.opts.clone(),
+            opts: profile_opts(&profile, ctx),
             cache: cache.clone(),
             threads: ctx.threads,
+            tokens: ctx.tokens.clone(),
+            keep_going: ctx.keep_going,
+            driver: ctx.driver,
+            profile: ctx.profile.clone(),
         };
 
         ctx.shell.println(
@@ -602,18 +763,204 @@ pub fn build(
         );
 
         // We want to store the outputs of our labor in a local target directory.
-        let lock = DirLock::acquire(&project.join("target"))?;
+        let lock = DirLock::acquire(&project.join("target").join(&ctx.profile))?;
         let layout = OutputLayout::new(lock).context("could not create local target directory")?;
+        let target_dir = layout.root.clone();
 
         let q = JobQueue::new(sources, &root, Some(layout), bctx, &ctx.logger, ctx.shell)?;
-        // Because we're just building, we don't need to do anything after executing the build
-        // process. Yay abstraction!
         q.exec()?;
 
-        Ok("build output available at `./target`".to_string())
+        // Checking a package doesn't build anything worth hooking into
+        if codegen {
+            let env = HookEnv {
+                manifest: &manifest,
+                root: &project,
+                target_dir: &target_dir,
+                backend: &backend.name,
+                profile: &ctx.profile,
+            };
+            hook(ctx, Hook::PostBuild, &project, &env)?;
+        }
+
+        Ok(format!(
+            "build output available at `./target/{}`",
+            ctx.profile
+        ))
+    })
+}
+
+/// Builds the root package's tools, then runs the binary called `name` with `args` from the
+/// current directory.
+pub fn task(
+    ctx: &BuildCtx,
+    project: &Path,
+    name: &str,
+    args: &[String],
+    backend: &Backend,
+) -> Result<String> {
+    let (project, manifest) = find_manifest(project, true, None)?;
+
+    if manifest.tool_dependencies.is_empty() {
+        bail!("the package doesn't have any tool dependencies")
+    }
+
+    solve_local(ctx, &project, 3, None, |cache, mut retriever, solve| {
+        let (_, tools) = split_tools(&manifest, solve);
+
+        ctx.shell.println(
+            style("[2/3]").dim().bold(),
+            "Building tools...",
+            Verbosity::Quiet,
+        );
+
+        let bin_dir = build_tools(ctx, &project, cache, &mut retriever, &tools, backend)?;
+        drop(retriever);
+
+        // Binaries might have an extension, depending on the backend
+        let bin = fs::read_dir(&bin_dir)?
+            .filter_map(|x| x.ok())
+            .map(|x| x.path())
+            .find(|x| x.file_stem().map(|x| x == name).unwrap_or(false))
+            .ok_or_else(|| {
+                format_err!("none of the package's tools have a binary named `{}`", name)
+            })?;
+
+        ctx.shell.println(
+            style("[3/3]").dim().bold(),
+            format!("Running task `{}`...", name),
+            Verbosity::Quiet,
+        );
+
+        let mut process = if let Some(runner) = &backend.runner {
+            let mut p = Command::new(runner);
+            p.arg(&bin);
+            p
+        } else {
+            Command::new(&bin)
+        };
+        process.args(args);
+
+        let target_dir = project.join("target").join(&ctx.profile);
+        let env = HookEnv {
+            manifest: &manifest,
+            root: &project,
+            target_dir: &target_dir,
+            backend: &backend.name,
+            profile: &ctx.profile,
+        };
+        for (var, val) in env.vars()? {
+            process.env(var, val);
+        }
+
+        ctx.shell
+            .println_plain(format!("> {:#?}", process), Verbosity::Verbose);
+
+        let status = process
+            .status()
+            .with_context(|e| format_err!("couldn't run {}: {}", bin.display(), e))?;
+        if !status.success() {
+            match status.code() {
+                Some(code) => bail!("task `{}` exited with code {}", name, code),
+                None => bail!("task `{}` was terminated by a signal", name),
+            }
+        }
+
+        Ok(format!("task `{}` finished", name))
     })
 }
 
+/// Separates the root package's tool dependencies from the rest of a solve. Tools aren't part of
+/// the package's build, so the first graph only has what the package's targets need, unless a
+/// tool is also an ordinary dependency. The rest are the graphs of each tool, rooted at the tool.
+pub fn split_tools(
+    manifest: &Manifest,
+    solve: Graph<Summary>,
+) -> (Graph<Summary>, Vec<Graph<Summary>>) {
+    if manifest.tool_dependencies.is_empty() {
+        return (solve, vec![]);
+    }
+
+    let root = NodeIndex::new(0);
+    let tools = solve
+        .children(root)
+        .filter(|(_, sum)| manifest.tool_dependencies.contains_key(sum.name()))
+        .map(|(ix, _)| ix)
+        .collect::<Vec<_>>();
+    let tool_graphs = tools.iter().map(|&ix| solve.sub_graph(ix)).collect();
+
+    let mut deps = solve;
+    for ix in tools {
+        let name = deps[ix].name();
+        if manifest.dependencies.contains_key(name) || manifest.dev_dependencies.contains_key(name)
+        {
+            continue;
+        }
+        if let Some(edge) = deps.inner.find_edge(root, ix) {
+            deps.inner.remove_edge(edge);
+        }
+    }
+
+    (deps.sub_graph(root), tool_graphs)
+}
+
+/// Builds the binaries of the root package's tools. Each tool is built in its own directory under
+/// `target/.tools`, then every binary gets copied into `target/.tools/bin`, which is returned.
+fn build_tools(
+    ctx: &BuildCtx,
+    project: &Path,
+    cache: &Cache,
+    retriever: &mut Retriever,
+    tools: &[Graph<Summary>],
+    backend: &Backend,
+) -> Result<PathBuf> {
+    let tools_dir = tools_dir(project);
+    let bin_dir = tools_dir.join("bin");
+    clear_dir(&bin_dir)?;
+
+    for tool in tools {
+        let sources = retriever
+            .retrieve_packages(tool)
+            .context(format_err!("package retrieval failed"))?;
+
+        let manifest = sources[NodeIndex::new(0)].meta().clone();
+        let profile = manifest.profile(&ctx.profile)?;
+        if manifest.targets.bin.is_empty() {
+            bail!(
+                "tool dependency {} doesn't have any binary targets",
+                manifest.name()
+            )
+        }
+
+        let bctx = BuildContext {
+            backend: backend.clone(),
+            codegen: true,
+            compiler: Compiler::new(&ctx.compiler)?,
+            opts: profile_opts(&profile, ctx),
+            cache: cache.clone(),
+            threads: ctx.threads,
+            tokens: ctx.tokens.clone(),
+            keep_going: ctx.keep_going,
+            driver: ctx.driver,
+            profile: ctx.profile.clone(),
+        };
+
+        // Every tool has an output directory of its own, so they only get rebuilt when something
+        // about them changes
+        let lock =
+            DirLock::acquire(&tools_dir.join(manifest.name().as_normalized().replace("/", "_")))?;
+        let layout = OutputLayout::new(lock).context("could not create tool directory")?;
+        let tool_bins = layout.bin.clone();
+
+        let root = Targets::new((0..manifest.targets.bin.len()).map(Target::Bin).collect());
+        let q = JobQueue::new(sources, &root, Some(layout), bctx, &ctx.logger, ctx.shell)?;
+        q.exec()?;
+
+        copy_dir(&tool_bins, &bin_dir, false)?;
+    }
+
+    Ok(bin_dir)
+}
+
 pub fn update(ctx: &BuildCtx, project: &Path, ignore: Option<&[Spec]>) -> Result<String> {
     let (project, _) = find_manifest(project, true, None)?;
 
@@ -689,7 +1036,13 @@ pub fn add(ctx: &BuildCtx, project: &Path, spec: &Spec, dev: bool) -> Result<Str
         .parse::<toml_edit::Document>()
         .with_context(|e| format!("invalid manifest toml format: {}", e))?;
 
-    let cache = Cache::from_disk(&ctx.logger, ctx.global_cache.clone(), ctx.shell)?;
+    let cache = Cache::from_disk(
+        &ctx.logger,
+        ctx.global_cache.clone(),
+        ctx.net.clone(),
+        &ctx.http,
+        ctx.shell,
+    )?;
     let indices = ctx
         .indices
         .values()
@@ -736,7 +1089,33 @@ pub fn solve_local<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String>
     ignore: Option<&[Spec]>,
     mut f: F,
 ) -> Result<String> {
-    let (project, manifest) = find_manifest(project, true, Some(ctx.shell))?;
+    let cache = Cache::from_disk(
+        &ctx.logger,
+        ctx.global_cache.clone(),
+        ctx.net.clone(),
+        &ctx.http,
+        ctx.shell,
+    )?;
+
+    // The Idris 2 `depends` of a lone `.ipkg` file become dependencies of the package if they can
+    // be found in the configured indices, so those have to be loaded before anything else.
+    let mut loaded = None;
+    let (project, manifest) = find_manifest_with(project, true, Some(ctx.shell), |ipkg| {
+        if ipkg.depends().is_empty() {
+            return ipkg.try_into();
+        }
+
+        let reses = ctx
+            .indices
+            .values()
+            .cloned()
+            .map(|x| x.res)
+            .collect::<Vec<_>>();
+        let indices = cache.get_indices(&reses, false, ctx.offline);
+        let manifest = ipkg.into_manifest(Some(&indices));
+        loaded = Some(indices);
+        manifest
+    })?;
 
     let op = || -> Result<Graph<Summary>> {
         let mut f = fs::File::open(&project.join("elba.lock"))?;
@@ -808,8 +1187,7 @@ pub fn solve_local<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String>
             }
         })
         .collect::<Vec<_>>();
-
-    let cache = Cache::from_disk(&ctx.logger, ctx.global_cache.clone(), ctx.shell)?;
+    let indices = loaded.unwrap_or_else(|| cache.get_indices(&dreses, false, ctx.offline));
 
     ctx.shell.println(
         style(format!("[1/{}]", total)).dim().bold(),
@@ -817,12 +1195,16 @@ pub fn solve_local<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String>
         Verbosity::Quiet,
     );
 
+    // A package loaded from an ipkg file might have dependencies which only exist because we
+    // looked them up in the indices, so the Retriever can't load it from disk all over again
+    let root_source = cache.local_source(&root.id, &project, manifest)?;
     let mut retriever = Retriever::new(
         &cache.logger,
         &cache,
         root,
         deps,
-        Left(dreses),
+        Some(root_source),
+        Left((dreses, indices)),
         lock,
         &ctx.indices,
         ctx.shell,
@@ -858,7 +1240,13 @@ pub fn solve_remote<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String
     total: u8,
     mut f: F,
 ) -> Result<String> {
-    let cache = Cache::from_disk(&ctx.logger, ctx.global_cache.clone(), ctx.shell)?;
+    let cache = Cache::from_disk(
+        &ctx.logger,
+        ctx.global_cache.clone(),
+        ctx.net.clone(),
+        &ctx.http,
+        ctx.shell,
+    )?;
     ctx.shell.println(
         style(format!("[1/{}]", total)).dim().bold(),
         "Resolving dependencies...",
@@ -895,6 +1283,7 @@ pub fn solve_remote<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String
         &cache,
         root,
         deps,
+        None,
         Right(indices),
         lock,
         &ctx.indices,
@@ -906,10 +1295,147 @@ pub fn solve_remote<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String
     f(&cache, retriever, solve)
 }
 
+/// Runs one of the root package's hooks, unless hooks have been turned off.
+pub fn hook(ctx: &BuildCtx, hook: Hook, root: &Path, env: &HookEnv) -> Result<()> {
+    if ctx.no_hooks {
+        return Ok(());
+    }
+
+    run_hook(hook, root, env, ctx.shell)
+}
+
+/// The options to pass to the compiler: the profile's come first, so that the ones given on the
+/// command line or through `IDRIS_OPTS` can override them.
+fn profile_opts(profile: &Profile, ctx: &BuildCtx) -> Vec<String> {
+    profile
+        .idris_opts
+        .iter()
+        .chain(ctx.opts.iter())
+        .cloned()
+        .collect()
+}
+
+/// Whether `find_manifest` has a package to find at `path`: a manifest in it or any of its parents,
+/// or an ipkg file right in it.
+pub fn manifest_exists(path: &Path) -> bool {
+    path.ancestors().any(|p| p.join("elba.toml").exists())
+        || find_ipkgs(path).map(|x| !x.is_empty()).unwrap_or(false)
+}
+
 pub fn find_manifest(
     path: &Path,
     allow_ipkg: bool,
     shell: Option<Shell>,
+) -> Result<(PathBuf, Manifest)> {
+    find_manifest_with(path, allow_ipkg, shell, |ipkg| ipkg.try_into())
+}
+
+/// Loads the configured indices, without updating them.
+fn load_indices(ctx: &BuildCtx) -> Result<Indices> {
+    let cache = Cache::from_disk(
+        &ctx.logger,
+        ctx.global_cache.clone(),
+        ctx.net.clone(),
+        &ctx.http,
+        ctx.shell,
+    )?;
+    let indices = ctx
+        .indices
+        .values()
+        .cloned()
+        .map(|x| x.res)
+        .collect::<Vec<_>>();
+    Ok(cache.get_indices(&indices, false, ctx.offline))
+}
+
+/// Serializes `value` as the section `outer` (or `outer.inner`) of a TOML document.
+fn toml_section<T: Serialize>(outer: &str, inner: Option<&str>, value: &T) -> Result<String> {
+    let section = match inner {
+        Some(inner) => toml::to_string(&indexmap!(outer => indexmap!(inner => value)))?,
+        None => toml::to_string(&indexmap!(outer => value))?,
+    };
+    Ok(section)
+}
+
+/// Lists the `.ipkg` files directly inside of `path`.
+fn find_ipkgs(path: &Path) -> Result<Vec<PathBuf>> {
+    Ok(fs::read_dir(path)?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|p| p.extension().and_then(|p| p.to_str()) == Some("ipkg"))
+        .collect())
+}
+
+/// Turns the lone `.ipkg` file in `project` into an `elba.toml` manifest for a package in
+/// `group`, reporting anything that couldn't be translated.
+pub fn migrate(ctx: &BuildCtx, project: &Path, group: &str) -> Result<String> {
+    if project.join("elba.toml").exists() {
+        bail!(
+            "a manifest file (elba.toml) already exists in {}",
+            project.display()
+        )
+    }
+
+    let ipkgs = find_ipkgs(project)?;
+    let ipkg_path = match ipkgs.as_slice() {
+        [ipkg_path] => ipkg_path,
+        [] => bail!("no ipkg file exists in the current directory"),
+        _ => bail!("multiple ipkg files are found (only one can be migrated)"),
+    };
+    let contents =
+        fs::read_to_string(ipkg_path).context(format_err!("failed to read ipkg file"))?;
+    let ipkg = Ipkg::from_str(&contents).context(format_err!("while parsing ipkg file"))?;
+
+    let indices = load_indices(ctx)?;
+    let (manifest, untranslated) = ipkg.migrate(group, &indices)?;
+    manifest.validate()?;
+
+    // Serializing the whole manifest at once would put empty target lists before the targets
+    // which are tables, which TOML doesn't allow, so each section is written out on its own.
+    let mut contents = vec![toml_section("package", None, &manifest.package)?];
+    if !manifest.dependencies.is_empty() {
+        contents.push(toml_section("dependencies", None, &manifest.dependencies)?);
+    }
+    if let Some(lib) = &manifest.targets.lib {
+        contents.push(toml_section("targets", Some("lib"), lib)?);
+    }
+    if !manifest.targets.bin.is_empty() {
+        contents.push(toml_section("targets", Some("bin"), &manifest.targets.bin)?);
+    }
+    if !manifest.targets.test.is_empty() {
+        contents.push(toml_section(
+            "targets",
+            Some("test"),
+            &manifest.targets.test,
+        )?);
+    }
+    if !manifest.scripts.is_empty() {
+        contents.push(toml_section("scripts", None, &manifest.scripts)?);
+    }
+    fs::write(project.join("elba.toml"), contents.join("\n").as_bytes())
+        .context(format_err!("could not write to elba.toml"))?;
+
+    let ipkg_name = ipkg_path.file_name().unwrap().to_string_lossy();
+    for note in &untranslated {
+        ctx.shell.println(
+            style("[warn]").yellow().bold(),
+            format!("{}: {}", ipkg_name, note),
+            Verbosity::Quiet,
+        );
+    }
+
+    Ok(format!(
+        "migrated {} to elba.toml as package {} {}",
+        ipkg_name,
+        manifest.name(),
+        manifest.version()
+    ))
+}
+
+fn find_manifest_with(
+    path: &Path,
+    allow_ipkg: bool,
+    shell: Option<Shell>,
+    convert: impl FnOnce(Ipkg) -> Result<Manifest>,
 ) -> Result<(PathBuf, Manifest)> {
     let root = path.ancestors().find(|p| p.join("elba.toml").exists());
     match root {
@@ -925,11 +1451,7 @@ pub fn find_manifest(
             Ok((root.to_path_buf(), manifest))
         }
         None if allow_ipkg => {
-            let ipkgs: Vec<PathBuf> = fs::read_dir(path)?
-                .into_iter()
-                .filter_map(|entry| Some(entry.ok()?.path().to_path_buf()))
-                .filter(|p| p.extension().map(|p| p.to_str()).flatten() == Some("ipkg"))
-                .collect();
+            let ipkgs = find_ipkgs(path)?;
             if ipkgs.len() == 0 {
                 return Err(format_err!(
                     "no manifest file (elba.toml) exists in any parent directory and \
@@ -947,7 +1469,7 @@ pub fn find_manifest(
             let mut contents = String::new();
             file.read_to_string(&mut contents)?;
             let ipkg = Ipkg::from_str(&contents).context(format_err!("while parsing ipkg file"))?;
-            let manifest: Manifest = ipkg.try_into()?;
+            let manifest = convert(ipkg)?;
 
             if let Some(shell) = shell {
                 shell.println(
@@ -967,3 +1489,71 @@ pub fn find_manifest(
         )),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use semver::Version;
+
+    fn sum(name: &str) -> Summary {
+        let pid = PackageId::from_str(&format!("{}@dir+/{}", name, name)).unwrap();
+        Summary::new(pid, Version::new(1, 0, 0))
+    }
+
+    fn names(graph: &Graph<Summary>) -> Vec<String> {
+        graph
+            .inner
+            .node_indices()
+            .map(|ix| graph[ix].name().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn split_tool_deps() {
+        let manifest = Manifest::from_str(
+            r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[dependencies]
+'great/c' = { path = 'deps/c' }
+
+[tool-dependencies]
+'gen/lexer' = { path = 'tools/lexer' }
+'great/c' = { path = 'deps/c' }
+"#,
+        )
+        .unwrap();
+
+        let mut inner = petgraph::Graph::new();
+        let root = inner.add_node(sum("ring_ding/test"));
+        let c = inner.add_node(sum("great/c"));
+        let lexer = inner.add_node(sum("gen/lexer"));
+        let combinators = inner.add_node(sum("parse/combinators"));
+        let util = inner.add_node(sum("base/util"));
+        inner.add_edge(root, c, ());
+        inner.add_edge(root, lexer, ());
+        inner.add_edge(lexer, combinators, ());
+        inner.add_edge(lexer, util, ());
+        inner.add_edge(c, util, ());
+
+        let (deps, tools) = split_tools(&manifest, Graph::new(inner));
+
+        // A tool which is also an ordinary dependency stays in the build, but anything only the
+        // other tools need is pruned
+        assert_eq!(names(&deps), vec!["ring_ding/test", "great/c", "base/util"]);
+
+        // Every tool's graph is rooted at the tool
+        let mut tools = tools.iter().map(names).collect::<Vec<_>>();
+        tools.sort();
+        assert_eq!(
+            tools,
+            vec![
+                vec!["gen/lexer", "base/util", "parse/combinators"],
+                vec!["great/c", "base/util"],
+            ]
+        );
+    }
+}
@@ -0,0 +1,429 @@
+//! Commands for inspecting and cleaning up the global cache.
+
+use std::{
+    fs::{self, File},
+    io::{Read, Write},
+    path::Path,
+    process,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use console::style;
+use failure::{bail, format_err, ResultExt};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use tar;
+
+use super::build::BuildCtx;
+use crate::{
+    build::context::Compiler,
+    package::manifest::Manifest,
+    retrieve::cache::{
+        build_files, BuildHash, BuildsManifest, Cache, CacheEntry, EntryKind, BUILDS_MANIFEST,
+    },
+    util::{
+        copy_dir_iter,
+        error::Result,
+        lock::{DirLock, LockKind},
+        shell::Verbosity,
+    },
+};
+
+/// The criteria by which `gc` decides what to delete.
+#[derive(Debug, Clone, Default)]
+pub struct GcOpts {
+    /// Evict the least recently used entries until the cache is at most this many bytes.
+    pub max_size: Option<u64>,
+    /// Evict every entry which hasn't been used for this long.
+    pub older_than: Option<Duration>,
+}
+
+fn cache(bcx: &BuildCtx) -> Result<Cache> {
+    Cache::from_disk(
+        &bcx.logger,
+        bcx.global_cache.clone(),
+        bcx.net.clone(),
+        &bcx.http,
+        bcx.shell,
+    )
+}
+
+pub fn list(bcx: &BuildCtx) -> Result<String> {
+    let mut entries = cache(bcx)?.entries()?;
+    entries.sort_by(|a, b| (a.kind, &a.name).cmp(&(b.kind, &b.name)));
+
+    let mut res = String::new();
+    let mut total = 0;
+    for entry in &entries {
+        total += entry.size;
+        res.push_str(&format!(
+            "{:<8} {:<48} {:>10} {}\n",
+            entry.kind.to_string(),
+            describe(entry),
+            fmt_size(entry.size),
+            fmt_age(entry.last_used),
+        ));
+    }
+    res.push_str(&format!(
+        "{} entries, {} total",
+        entries.len(),
+        fmt_size(total)
+    ));
+
+    Ok(res)
+}
+
+pub fn verify(bcx: &BuildCtx) -> Result<String> {
+    let cache = cache(bcx)?;
+    let mut removed = 0;
+
+    for entry in cache.entries()? {
+        if let Some(false) = cache.verify_entry(&entry)? {
+            bcx.shell.println(
+                style("Removing").yellow().bold(),
+                format!("{} (contents don't match recorded hash)", describe(&entry)),
+                Verbosity::Normal,
+            );
+            if cache.remove_entry(&entry)? {
+                removed += 1;
+            } else {
+                bcx.shell.println(
+                    style("[warn]").yellow().bold(),
+                    format!("{} is in use; skipping", entry.path.display()),
+                    Verbosity::Quiet,
+                );
+            }
+        }
+    }
+
+    Ok(format!(
+        "verified cache; removed {} corrupt entries",
+        removed
+    ))
+}
+
+pub fn gc(bcx: &BuildCtx, opts: &GcOpts) -> Result<String> {
+    if opts.max_size.is_none() && opts.older_than.is_none() {
+        bail!("nothing to collect; pass --max-size and/or --older-than")
+    }
+
+    let cache = cache(bcx)?;
+    let mut entries = cache.entries()?;
+    // Least recently used first
+    entries.sort_by_key(|x| x.last_used);
+
+    let now = SystemTime::now();
+    let mut size: u64 = entries.iter().map(|x| x.size).sum();
+    let (mut removed, mut freed) = (0, 0);
+
+    for entry in &entries {
+        let age = now.duration_since(entry.last_used).unwrap_or_default();
+        let too_old = opts.older_than.map(|x| age > x).unwrap_or(false);
+        let too_big = opts.max_size.map(|x| size > x).unwrap_or(false);
+
+        if !too_old && !too_big {
+            continue;
+        }
+
+        // Whatever's in use right now obviously isn't worth evicting
+        let desc = describe(entry);
+        if !cache.remove_entry(entry)? {
+            continue;
+        }
+        bcx.shell.println(
+            style("Removed").dim(),
+            format!("{} {}", entry.kind, desc),
+            Verbosity::Verbose,
+        );
+        size -= entry.size;
+        freed += entry.size;
+        removed += 1;
+    }
+
+    Ok(format!(
+        "removed {} cache entries, freeing {}",
+        removed,
+        fmt_size(freed)
+    ))
+}
+
+/// Exports every build in the cache, along with a manifest of what they were built with, to
+/// either a directory or a tarball (if `to` ends in `.tar`, `.tar.gz` or `.tgz`).
+///
+/// Exporting into a directory which already has exported builds in it adds to them, so that a
+/// single directory can collect builds from several machines.
+pub fn export(bcx: &BuildCtx, to: &Path) -> Result<String> {
+    let cache = cache(bcx)?;
+    let builds = cache
+        .entries()?
+        .into_iter()
+        .filter(|x| x.kind == EntryKind::Build)
+        .collect::<Vec<_>>();
+
+    let mut manifest = if !is_tarball(to) && to.join(BUILDS_MANIFEST).exists() {
+        BuildsManifest::from_dir(to)?
+    } else {
+        BuildsManifest::default()
+    };
+
+    if is_tarball(to) {
+        let file = File::create(to)
+            .with_context(|e| format_err!("couldn't create {}: {}", to.display(), e))?;
+        let writer: Box<dyn Write> = if is_gzipped(to) {
+            Box::new(GzEncoder::new(file, Compression::default()))
+        } else {
+            Box::new(file)
+        };
+        let mut tar = tar::Builder::new(writer);
+
+        for build in &builds {
+            let lock = DirLock::acquire_with(&build.path, LockKind::Shared, None, bcx.shell)?;
+            for file in build_files(lock.path()) {
+                let suffix = file.path().strip_prefix(lock.path()).unwrap();
+                tar.append_path_with_name(file.path(), Path::new(&build.name).join(suffix))?;
+            }
+            manifest
+                .builds
+                .insert(build.name.clone(), build.build.clone().unwrap_or_default());
+        }
+
+        let contents = toml::to_string(&manifest)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, BUILDS_MANIFEST, contents.as_bytes())?;
+        tar.into_inner()?.flush()?;
+    } else {
+        fs::create_dir_all(to)?;
+        for build in &builds {
+            let lock = DirLock::acquire_with(&build.path, LockKind::Shared, None, bcx.shell)?;
+            copy_dir_iter(build_files(lock.path()), lock.path(), &to.join(&build.name))?;
+            manifest
+                .builds
+                .insert(build.name.clone(), build.build.clone().unwrap_or_default());
+        }
+        manifest.write(to)?;
+    }
+
+    Ok(format!(
+        "exported {} builds to {}",
+        builds.len(),
+        to.display()
+    ))
+}
+
+/// Imports builds exported by `export` into the cache. Builds made with a different compiler
+/// version than ours would never get used, so they're skipped.
+pub fn import(bcx: &BuildCtx, from: &Path) -> Result<String> {
+    let cache = cache(bcx)?;
+
+    if !is_tarball(from) {
+        return import_dir(bcx, &cache, from);
+    }
+
+    let tmp = DirLock::acquire_with(
+        &bcx.global_cache
+            .tmp
+            .join(format!("import-{}", process::id())),
+        LockKind::Exclusive,
+        None,
+        bcx.shell,
+    )?;
+    tmp.clear()?;
+
+    let file = File::open(from)
+        .with_context(|e| format_err!("couldn't open {}: {}", from.display(), e))?;
+    let reader: Box<dyn Read> = if is_gzipped(from) {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    tar::Archive::new(reader)
+        .unpack(tmp.path())
+        .with_context(|e| format_err!("couldn't unpack {}: {}", from.display(), e))?;
+
+    let res = import_dir(bcx, &cache, tmp.path());
+
+    let path = tmp.path().to_path_buf();
+    drop(tmp);
+    remove_dir_all::remove_dir_all(path)?;
+
+    res
+}
+
+fn import_dir(bcx: &BuildCtx, cache: &Cache, dir: &Path) -> Result<String> {
+    let manifest = BuildsManifest::from_dir(dir)?;
+    // If we can't run the compiler, we can't know which builds are useless, so we take everything.
+    let compiler = Compiler::new(&bcx.compiler).and_then(|x| x.version()).ok();
+
+    let (mut imported, mut skipped) = (0, 0);
+    for (hash, meta) in &manifest.builds {
+        if !is_build_hash(hash) {
+            bail!("invalid build hash `{}` in {}", hash, BUILDS_MANIFEST)
+        }
+
+        if compiler
+            .as_ref()
+            .map(|x| x != &meta.compiler)
+            .unwrap_or(false)
+        {
+            skipped += 1;
+            continue;
+        }
+
+        if cache.import_build(&dir.join(hash), &BuildHash(hash.clone()), meta)? {
+            bcx.shell.println(
+                style("Imported").dim(),
+                format!("build {}", hash),
+                Verbosity::Verbose,
+            );
+            imported += 1;
+        }
+    }
+
+    Ok(format!(
+        "imported {} builds ({} were built with a different compiler and were skipped)",
+        imported, skipped
+    ))
+}
+
+/// Whether `hash` looks like a build hash: a hex SHA-256 digest. Hashes name directories, so
+/// anything else could point outside of the archive.
+fn is_build_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn is_tarball(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    name.ends_with(".tar") || is_gzipped(path)
+}
+
+fn is_gzipped(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    name.ends_with(".tar.gz") || name.ends_with(".tgz")
+}
+
+/// Describes a cache entry by the package it contains, if possible.
+fn describe(entry: &CacheEntry) -> String {
+    if entry.kind == EntryKind::Src {
+        let manifest = fs::read_to_string(entry.path.join("elba.toml"))
+            .ok()
+            .and_then(|x| Manifest::from_str(&x).ok());
+        if let Some(manifest) = manifest {
+            return format!("{} {}", manifest.name(), manifest.version());
+        }
+    }
+
+    entry.name.clone()
+}
+
+fn fmt_size(size: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", size, units[unit])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+fn fmt_age(time: SystemTime) -> String {
+    let secs = SystemTime::now()
+        .duration_since(time)
+        .unwrap_or_default()
+        .as_secs();
+
+    match secs {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+/// Parses a size like `500M` or `2GiB` into a number of bytes. Units are powers of 1024.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: f64 = match num.parse() {
+        Ok(x) => x,
+        Err(_) => bail!("invalid size `{}`", s),
+    };
+
+    let unit = unit.trim().to_ascii_uppercase();
+    let unit = unit.trim_end_matches('B').trim_end_matches('I');
+    let mult: u64 = match unit {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => bail!("invalid size `{}`: unknown unit", s),
+    };
+
+    Ok((num * mult as f64) as u64)
+}
+
+/// Parses a duration like `30d` or `12h`. Supported units are `s`, `m`, `h`, `d` and `w`.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: u64 = match num.parse() {
+        Ok(x) => x,
+        Err(_) => bail!("invalid duration `{}`", s),
+    };
+
+    let mult = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" | "" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        _ => bail!("invalid duration `{}`: unknown unit", s),
+    };
+
+    Ok(Duration::from_secs(num * mult))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+        assert_eq!(parse_size("2K").unwrap(), 2048);
+        assert_eq!(parse_size("500MB").unwrap(), 500 << 20);
+        assert_eq!(parse_size("1.5GiB").unwrap(), 3 << 29);
+        assert!(parse_size("12Q").is_err());
+        assert!(parse_size("big").is_err());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30d").unwrap().as_secs(), 30 * 86400);
+        assert_eq!(parse_duration("12h").unwrap().as_secs(), 12 * 3600);
+        assert_eq!(parse_duration("2w").unwrap().as_secs(), 14 * 86400);
+        assert!(parse_duration("3y").is_err());
+    }
+
+    #[test]
+    fn build_hashes() {
+        assert!(is_build_hash(&"0123456789abcdef".repeat(4)));
+        assert!(!is_build_hash(""));
+        assert!(!is_build_hash("abc123"));
+        assert!(!is_build_hash(&"a".repeat(65)));
+        assert!(!is_build_hash(&format!("../{}", "a".repeat(61))));
+    }
+}
@@ -1,25 +1,44 @@
 //! Registry-related commands: publishing, yanking, etc.
 
 use std::{
-    fs::{create_dir_all, File},
+    fs::{self, create_dir_all, File, OpenOptions},
+    io::Write,
     path::{Path, PathBuf},
     str::{self},
 };
 
+use failure::{bail, format_err, ResultExt};
 use flate2::{write::GzEncoder, Compression};
+use indexmap::IndexMap;
+use serde_json;
 use tar;
+use url::Url;
 
 use super::build;
 use crate::{
-    cli::build::find_manifest,
-    package::manifest::Manifest,
+    build::{Hook, HookEnv},
+    cli::build::{find_manifest, hook, BuildCtx},
+    package::{manifest::Manifest, Checksum, PackageId},
+    remote::{
+        resolution::{DirectRes, IndexRes, Resolution},
+        Dep, Index, RawEntry,
+    },
     retrieve::Cache,
-    util::{error::Result, valid_file},
+    util::{config::Backend, error::Result, lock::DirLock, valid_file},
 };
 
-pub fn package(project: &Path) -> Result<(PathBuf, Manifest)> {
+pub fn package(ctx: &BuildCtx, project: &Path, backend: &Backend) -> Result<(PathBuf, Manifest)> {
     let (project, manifest) = find_manifest(project, false, None)?;
 
+    let env = HookEnv {
+        manifest: &manifest,
+        root: &project,
+        target_dir: &project.join("target").join(&ctx.profile),
+        backend: &backend.name,
+        profile: &ctx.profile,
+    };
+    hook(ctx, Hook::PrePackage, &project, &env)?;
+
     let gz_name = format!(
         "target/{}_{}-{}.tar.gz",
         manifest.name().group(),
@@ -49,8 +68,141 @@ pub fn package(project: &Path) -> Result<(PathBuf, Manifest)> {
     Ok((project.join(&gz_name), manifest))
 }
 
+/// Packages the project and adds it to a local index as a tarball which will be available at
+/// `url`. The tarball's checksum is recorded in the format the index prefers.
+pub fn add(
+    ctx: &BuildCtx,
+    project: &Path,
+    index: &IndexRes,
+    url: Url,
+    backend: &Backend,
+) -> Result<String> {
+    let dir = match &index.res {
+        DirectRes::Dir { path } => path.clone(),
+        _ => bail!(
+            "packages can only be added to indices on disk; add it to a local copy of {} instead",
+            index
+        ),
+    };
+
+    let (project, _) = find_manifest(project, false, None)?;
+    let (tarball, manifest) = package(ctx, &project, backend)?;
+
+    let env = HookEnv {
+        manifest: &manifest,
+        root: &project,
+        target_dir: &project.join("target").join(&ctx.profile),
+        backend: &backend.name,
+        profile: &ctx.profile,
+    };
+    hook(ctx, Hook::PrePublish, &project, &env)?;
+
+    let index = Index::from_disk(index.res.clone(), DirLock::acquire(&dir)?)?;
+    let entry = entry(&manifest, &index, &ctx.indices, url, &tarball)?;
+
+    let path = dir.join(manifest.name().as_normalized());
+    if path.exists() {
+        let existing = fs::read_to_string(&path)?;
+        for line in existing.lines() {
+            let other: RawEntry = serde_json::from_str(line)
+                .with_context(|e| format_err!("invalid entry in {}: {}", path.display(), e))?;
+            if other.version == entry.version {
+                bail!("{} {} is already in the index", entry.name, entry.version)
+            }
+        }
+    }
+
+    create_dir_all(path.parent().unwrap())?;
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+
+    Ok(format!(
+        "added {} {} to {}",
+        entry.name,
+        entry.version,
+        dir.display()
+    ))
+}
+
+/// The index entry of a package which will be available as the tarball at `url`. Every
+/// dependency has to come from either `index` itself or one of the indices it depends on.
+fn entry(
+    manifest: &Manifest,
+    index: &Index,
+    ixmap: &IndexMap<String, IndexRes>,
+    url: Url,
+    tarball: &Path,
+) -> Result<RawEntry> {
+    let fmt = index.config.index.checksum;
+    let cksum = Checksum::compute(fmt, File::open(tarball)?)
+        .with_context(|e| format_err!("couldn't hash {}: {}", tarball.display(), e))?;
+
+    let parent = PackageId::new(
+        manifest.name().clone(),
+        DirectRes::Dir {
+            path: PathBuf::new(),
+        }
+        .into(),
+    );
+    let dependencies = manifest
+        .deps(ixmap, &parent, false)?
+        .into_iter()
+        .map(|(pkg, req)| {
+            let dep_index = match pkg.resolution() {
+                Resolution::Index(ix) => ix,
+                Resolution::Direct(_) => bail!(
+                    "{} isn't from an index; packages in an index can only depend on packages in indices",
+                    pkg.name()
+                ),
+            };
+            let index_name = if dep_index == &index.id {
+                None
+            } else {
+                let name = index
+                    .config
+                    .index
+                    .dependencies
+                    .iter()
+                    .find(|(_, ix)| *ix == dep_index)
+                    .map(|(name, _)| name.clone())
+                    .ok_or_else(|| {
+                        format_err!(
+                            "{} is from {}, which the index doesn't depend on",
+                            pkg.name(),
+                            dep_index
+                        )
+                    })?;
+                Some(name)
+            };
+
+            Ok(Dep {
+                name: pkg.name().clone(),
+                index: index_name,
+                req,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(RawEntry {
+        name: manifest.name().clone(),
+        version: manifest.version().clone(),
+        dependencies,
+        yanked: false,
+        location: Some(DirectRes::Tar {
+            url,
+            cksum: Some(cksum),
+        }),
+    })
+}
+
 pub fn search(bcx: &build::BuildCtx, query: &str) -> Result<String> {
-    let cache = Cache::from_disk(&bcx.logger, bcx.global_cache.clone(), bcx.shell)?;
+    let cache = Cache::from_disk(
+        &bcx.logger,
+        bcx.global_cache.clone(),
+        bcx.net.clone(),
+        &bcx.http,
+        bcx.shell,
+    )?;
     let ixs = bcx
         .indices
         .values()
@@ -75,3 +227,60 @@ pub fn search(bcx: &build::BuildCtx, query: &str) -> Result<String> {
 
     Ok(res)
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use super::*;
+    use crate::package::ChecksumFmt;
+    use std::str::FromStr;
+
+    #[test]
+    fn index_entry_checksum() {
+        let dir = tempdir::TempDir::new("elba").unwrap();
+        fs::write(
+            dir.path().join("index.toml"),
+            "[index]\nsecure = false\nchecksum = \"sha512\"\n\n[index.dependencies]\n",
+        )
+        .unwrap();
+        let res = DirectRes::Dir {
+            path: dir.path().to_path_buf(),
+        };
+        let index = Index::from_disk(res, DirLock::acquire(dir.path()).unwrap()).unwrap();
+
+        let mut ixmap = IndexMap::new();
+        ixmap.insert("local".to_owned(), index.id.clone());
+
+        let manifest = Manifest::from_str(
+            r#"
+[package]
+name = "test/pkg"
+version = "0.1.0"
+authors = []
+
+[dependencies]
+"test/dep" = "1.0"
+
+[targets.lib]
+mods = ["Pkg"]
+            "#,
+        )
+        .unwrap();
+
+        let tarball = dir.path().join("pkg.tar.gz");
+        fs::write(&tarball, b"not really a tarball").unwrap();
+        let url = Url::parse("https://example.com/pkg.tar.gz").unwrap();
+
+        let entry = entry(&manifest, &index, &ixmap, url, &tarball).unwrap();
+        let hash = ChecksumFmt::Sha512
+            .digest(&b"not really a tarball"[..])
+            .unwrap();
+        assert_eq!(
+            entry.location.unwrap().to_string(),
+            format!("tar+https://example.com/pkg.tar.gz#sha512={}", hash)
+        );
+        assert_eq!(entry.dependencies.len(), 1);
+        assert_eq!(entry.dependencies[0].index, None);
+    }
+}
@@ -1,5 +1,6 @@
 //! Handlers for all of the command-line actions of the binary.
 
 pub mod build;
+pub mod cache;
 pub mod index;
 pub mod new;
@@ -1,26 +1,63 @@
-use std::{convert::TryFrom, str::FromStr};
+use std::{convert::TryFrom, fmt, str::FromStr};
 
 use failure::format_err;
 
 use indexmap::IndexMap;
 
+use itertools::Itertools;
+
 use semver::Version;
 
+use semver_constraints::{Constraint, Interval};
+
 use serde::Deserialize;
 
 use super::*;
-use crate::package::manifest::{BinTarget, LibTarget, Manifest, PackageInfo, Targets, TestTarget};
+use crate::{
+    package::manifest::{
+        BinTarget, DepReq, FfiTarget, LibTarget, Manifest, PackageInfo, Targets, TestTarget,
+    },
+    remote::Indices,
+};
+
+/// The packages which ship with Idris 1 or Idris 2. These are always passed to the compiler with
+/// `-p`, even if an index happens to have a package with the same name.
+const BUILTINS: &[&str] = &[
+    "prelude", "base", "contrib", "effects", "pruviloj", "network", "test", "linear", "papers",
+    "idris2",
+];
+
+/// The version of Idris a package file is written for. Idris 2 replaced `pkgs` with `depends`
+/// (which can carry version bounds), pluralized `author` and `maintainer`, quotes its strings,
+/// and added a few fields of its own.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    Idris1,
+    Idris2,
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Dialect::Idris1
+    }
+}
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct Ipkg {
+    /// The dialect the package file is written in.
+    dialect: Dialect,
     /// Name associated with a package.
     name: String,
-    /// List of packages this package depends on.
+    /// List of packages this package depends on (Idris 1).
     pkgs: Vec<String>,
+    /// List of packages this package depends on, along with their version bounds (Idris 2).
+    depends: Vec<(String, Option<String>)>,
     /// Brief description of the package.
     brief: Option<String>,
     /// Version string to associate with the package.
     version: Option<String>,
+    /// The versions of Idris 2 the package can be built with.
+    langversion: Option<String>,
     /// Location of the README file.
     readme: Option<String>,
     /// Description of the licensing information.
@@ -41,6 +78,10 @@ pub struct Ipkg {
     objs: Vec<String>,
     /// Makefile used to build external code. Used as part of the FFI process.
     makefile: Option<String>,
+    /// Command to run before building the package (Idris 2).
+    prebuild: Option<String>,
+    /// Command to run after building the package (Idris 2).
+    postbuild: Option<String>,
     /// List of options to give the compiler.
     opts: Vec<String>,
     /// Source directory for Idris files.
@@ -55,50 +96,167 @@ pub struct Ipkg {
     tests: Vec<String>,
 }
 
-impl TryFrom<Ipkg> for Manifest {
-    type Error = failure::Error;
+impl Ipkg {
+    pub fn dialect(&self) -> Dialect {
+        self.dialect
+    }
+
+    /// Changes the dialect the package file is written out in.
+    pub fn with_dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    pub fn depends(&self) -> &[(String, Option<String>)] {
+        &self.depends
+    }
+
+    /// Converts the package file into a manifest. Idris 2 `depends` which can be found in
+    /// `indices` become dependencies of the package; the rest are passed to the compiler with
+    /// `-p`, like Idris 1 `pkgs`.
+    pub fn into_manifest(self, indices: Option<&Indices>) -> Result<Manifest> {
+        self.convert("legacy", indices, false)
+            .map(|(manifest, _)| manifest)
+    }
+
+    /// Converts the package file into the manifest of a package in `group`, to be written out as
+    /// its `elba.toml`. Unlike `into_manifest`, Idris 1 `pkgs` are looked up in `indices` too.
+    /// Along with the manifest, returns a description of everything that couldn't be translated.
+    pub fn migrate(self, group: &str, indices: &Indices) -> Result<(Manifest, Vec<String>)> {
+        self.convert(group, Some(indices), true)
+    }
+
+    fn convert(
+        self,
+        group: &str,
+        indices: Option<&Indices>,
+        migrating: bool,
+    ) -> Result<(Manifest, Vec<String>)> {
+        let mut untranslated = Vec::new();
+
+        // Idris 1 versions can be anything, so legacy packages have always been at 0.0.0
+        let version = match &self.version {
+            Some(version) if migrating || self.dialect == Dialect::Idris2 => {
+                let parsed = parse_version(version);
+                if parsed.is_none() {
+                    untranslated.push(format!(
+                        "the version `{}` isn't a semantic version, so 0.0.0 is used instead",
+                        version
+                    ));
+                }
+                parsed
+            }
+            _ => None,
+        };
 
-    fn try_from(ipkg: Ipkg) -> Result<Self> {
         let package = PackageInfo {
-            name: Name::new("legacy".to_string(), ipkg.name)?,
-            version: Version::new(0, 0, 0),
-            authors: [ipkg.author, ipkg.maintainer]
+            name: Name::new(group.to_string(), self.name)?,
+            version: version.unwrap_or_else(|| Version::new(0, 0, 0)),
+            authors: [self.author, self.maintainer]
                 .iter()
                 .filter_map(|id| id.clone())
                 .into_iter()
                 .collect(),
-            description: ipkg.brief,
+            description: self.brief,
             keywords: Vec::new(),
-            homepage: ipkg.homepage,
-            repository: ipkg.sourceloc,
-            readme: ipkg.readme.map(|readme| readme.parse()).transpose()?,
-            license: ipkg.license,
+            homepage: self.homepage,
+            repository: self.sourceloc,
+            readme: self.readme.map(|readme| readme.parse()).transpose()?,
+            license: self.license,
             exclude: None,
+            build: None,
         };
 
+        let mut dependencies = IndexMap::new();
+        let (mut pkgs, wanted) = if migrating {
+            let wanted = self.pkgs.into_iter().map(|pkg| (pkg, None));
+            (vec![], wanted.chain(self.depends).collect::<Vec<_>>())
+        } else {
+            (self.pkgs, self.depends)
+        };
+        for (name, bounds) in wanted {
+            if BUILTINS.contains(&name.as_str()) {
+                pkgs.push(name);
+                continue;
+            }
+            let indices = match indices {
+                Some(indices) => indices,
+                None => {
+                    pkgs.push(name);
+                    continue;
+                }
+            };
+
+            let found = indices.find_by_name(&name);
+            if found.len() == 1 {
+                let (dep, ir) = &found[0];
+                let version = match &bounds {
+                    Some(bounds) => idris2_constraint(bounds)?,
+                    None => Constraint::any(),
+                };
+                let req = if indices.indices.get_index(0).map(|x| x.0) == Some(*ir) {
+                    DepReq::Registry(version)
+                } else {
+                    DepReq::RegLong {
+                        version,
+                        index: ir.to_string(),
+                    }
+                };
+                dependencies.insert(dep.clone(), req);
+            } else {
+                if found.is_empty() {
+                    untranslated.push(format!(
+                        "the package `{}` isn't in any index, so it's passed to the compiler \
+                         with `-p` instead",
+                        name
+                    ));
+                } else {
+                    untranslated.push(format!(
+                        "the package `{}` could be any of {}, so it's passed to the compiler \
+                         with `-p` instead",
+                        name,
+                        found.iter().map(|(dep, _)| dep).join(", ")
+                    ));
+                }
+                pkgs.push(name);
+            }
+        }
+
         let mut idris_opts = Vec::new();
-        for pkg in ipkg.pkgs {
+        for pkg in pkgs {
             idris_opts.push("-p".to_owned());
             idris_opts.push(pkg);
         }
-        idris_opts.extend(ipkg.opts);
+        idris_opts.extend(self.opts);
 
-        let lib_target = if ipkg.modules.is_empty() {
-            None
+        // Only a library can bring C libraries along with it
+        let (lib_target, libs) = if self.modules.is_empty() {
+            (None, self.libs)
         } else {
-            Some(LibTarget {
-                path: ipkg.sourcedir.parse()?,
-                mods: ipkg.modules,
+            let ffi = if self.libs.is_empty() {
+                None
+            } else {
+                Some(FfiTarget {
+                    link: self.libs,
+                    ..FfiTarget::default()
+                })
+            };
+            let lib_target = LibTarget {
+                path: self.sourcedir.parse()?,
+                mods: self.modules,
                 idris_opts: idris_opts.clone(),
-            })
+                driver: None,
+                ffi,
+            };
+            (Some(lib_target), Vec::new())
         };
 
-        let bin_target = if let Some(main) = ipkg.main {
+        let bin_target = if let Some(main) = self.main {
             vec![BinTarget {
-                name: ipkg
+                name: self
                     .executable
                     .unwrap_or(main.rsplit(".").next().unwrap_or("output").to_owned()),
-                path: ipkg.sourcedir.parse()?,
+                path: self.sourcedir.parse()?,
                 main: main.parse()?,
                 idris_opts: idris_opts.clone(),
             }]
@@ -107,24 +265,44 @@ impl TryFrom<Ipkg> for Manifest {
         };
 
         let mut test_targets = Vec::new();
-        for test in ipkg.tests {
+        for test in self.tests {
             test_targets.push(TestTarget {
                 name: None,
-                path: ipkg.sourcedir.parse()?,
+                path: self.sourcedir.parse()?,
                 main: test.parse()?,
                 idris_opts: idris_opts.clone(),
             })
         }
 
         let mut scripts = IndexMap::new();
-        if let Some(makefile) = ipkg.makefile {
-            scripts.insert("prebuild".to_owned(), format!("make -f {}", makefile));
+        if let Some(makefile) = self.makefile {
+            scripts.insert(
+                "prebuild".to_owned(),
+                format!("make -f {}", makefile).into(),
+            );
+        }
+        if let Some(prebuild) = self.prebuild {
+            scripts.insert("prebuild".to_owned(), prebuild.into());
+        }
+        if let Some(postbuild) = self.postbuild {
+            scripts.insert("postbuild".to_owned(), postbuild.into());
+        }
+
+        let dropped = [
+            ("libs", libs.join(", ")),
+            ("objs", self.objs.join(", ")),
+            ("bugtracker", self.bugtracker.unwrap_or_default()),
+            ("langversion", self.langversion.unwrap_or_default()),
+        ];
+        for (key, val) in dropped.iter().filter(|(_, val)| !val.is_empty()) {
+            untranslated.push(format!("`{}` ({}) has no equivalent in elba", key, val));
         }
 
-        Ok(Manifest {
+        let manifest = Manifest {
             package,
-            dependencies: IndexMap::new(),
+            dependencies,
             dev_dependencies: IndexMap::new(),
+            tool_dependencies: IndexMap::new(),
             targets: Targets {
                 lib: lib_target,
                 bin: bin_target,
@@ -132,47 +310,117 @@ impl TryFrom<Ipkg> for Manifest {
             },
             workspace: IndexMap::new(),
             scripts,
-        })
+            profile: IndexMap::new(),
+        };
+
+        Ok((manifest, untranslated))
     }
-}
 
-impl TryFrom<Manifest> for Ipkg {
-    type Error = failure::Error;
+    /// Converts a manifest into a package file written in `dialect`.
+    pub fn from_manifest(manifest: Manifest, dialect: Dialect) -> Result<Self> {
+        let sourcedir = {
+            let mut paths = manifest
+                .targets
+                .lib
+                .iter()
+                .map(|lib_target| &lib_target.path)
+                .chain(
+                    manifest
+                        .targets
+                        .bin
+                        .iter()
+                        .map(|bin_target| &bin_target.path),
+                )
+                .chain(
+                    manifest
+                        .targets
+                        .test
+                        .iter()
+                        .map(|test_target| &test_target.path),
+                );
+            let first = paths
+                .next()
+                .ok_or_else(|| format_err!("ipkg must have a lib or bin target"))?;
+            for path in paths {
+                if path != first {
+                    bail!(format_err!(
+                        "ipkg must have a unambigious sourcepath, while {} and {} are found",
+                        path.0.display(),
+                        first.0.display(),
+                    ))
+                }
+            }
+            first.0.to_str().unwrap().to_owned()
+        };
 
-    fn try_from(manifest: Manifest) -> Result<Self> {
-        if manifest.targets.lib.is_none() {
-            bail!(format_err!("ipkg must have a lib target"))
-        }
-        for path in manifest
+        let (pkgs, mut depends) = match dialect {
+            Dialect::Idris1 => (
+                manifest
+                    .dependencies
+                    .keys()
+                    .map(|name| name.name().to_owned())
+                    .collect(),
+                Vec::new(),
+            ),
+            Dialect::Idris2 => (
+                Vec::new(),
+                manifest
+                    .dependencies
+                    .iter()
+                    .map(|(name, req)| {
+                        let bounds = match req {
+                            DepReq::Registry(version) | DepReq::RegLong { version, .. } => {
+                                idris2_bounds(version)
+                            }
+                            _ => None,
+                        };
+                        (name.name().to_owned(), bounds)
+                    })
+                    .collect(),
+            ),
+        };
+
+        let opts = manifest
             .targets
-            .bin
-            .iter()
-            .map(|bin_target| &bin_target.path)
-            .chain(
+            .lib
+            .as_ref()
+            .map(|lib_target| lib_target.idris_opts.clone())
+            .or_else(|| {
                 manifest
                     .targets
-                    .test
-                    .iter()
-                    .map(|test_target| &test_target.path),
-            )
-        {
-            if path != &manifest.targets.lib.as_ref().unwrap().path {
-                bail!(format_err!(
-                    "ipkg must have a unambigious sourcepath, while {} and {} are found",
-                    path.0.display(),
-                    manifest.targets.lib.unwrap().path.0.display(),
-                ))
+                    .bin
+                    .get(0)
+                    .map(|bin| bin.idris_opts.clone())
+            })
+            .unwrap_or_default();
+
+        // Idris 2 wants packages to be listed under `depends` rather than passed with `-p`
+        let opts = if dialect == Dialect::Idris2 {
+            let mut rest = vec![];
+            let mut opts = opts.into_iter();
+            while let Some(opt) = opts.next() {
+                match (opt.as_str(), opts.as_slice().first()) {
+                    ("-p", Some(_)) => depends.push((opts.next().unwrap(), None)),
+                    _ => rest.push(opt),
+                }
             }
-        }
+            rest
+        } else {
+            opts
+        };
 
         Ok(Ipkg {
+            dialect,
             name: manifest.package.name.name().to_owned(),
-            pkgs: Vec::new(),
+            pkgs,
+            depends,
             brief: manifest.package.description.clone(),
             version: Some(manifest.package.version.to_string()),
+            langversion: None,
             readme: manifest
                 .package
                 .readme
+                .as_ref()
                 .map(|path| path.0.to_str().unwrap().to_owned()),
             license: manifest.package.license.clone(),
             author: manifest.package.authors.get(0).cloned(),
@@ -180,33 +428,71 @@ impl TryFrom<Manifest> for Ipkg {
             homepage: manifest.package.homepage.clone(),
             sourceloc: manifest.package.repository.clone(),
             bugtracker: None,
-            libs: Vec::new(),
+            libs: match dialect {
+                Dialect::Idris1 => manifest
+                    .targets
+                    .lib
+                    .as_ref()
+                    .and_then(|lib_target| lib_target.ffi.as_ref())
+                    .map(|ffi| ffi.link.clone())
+                    .unwrap_or_default(),
+                Dialect::Idris2 => Vec::new(),
+            },
             objs: Vec::new(),
             makefile: None,
-            opts: manifest.targets.lib.as_ref().unwrap().idris_opts.clone(),
-            sourcedir: manifest
+            prebuild: match dialect {
+                Dialect::Idris1 => None,
+                Dialect::Idris2 => manifest
+                    .script("prebuild")
+                    .ok()
+                    .map(|cmds| cmds.join(" && ")),
+            },
+            postbuild: match dialect {
+                Dialect::Idris1 => None,
+                Dialect::Idris2 => manifest
+                    .script("postbuild")
+                    .ok()
+                    .map(|cmds| cmds.join(" && ")),
+            },
+            opts,
+            sourcedir,
+            modules: manifest
                 .targets
                 .lib
-                .as_ref()
-                .unwrap()
-                .path
-                .0
-                .to_str()
-                .unwrap()
-                .to_owned(),
-            modules: manifest.targets.lib.unwrap().mods,
+                .map(|lib_target| lib_target.mods)
+                .unwrap_or_default(),
             main: manifest.targets.bin.get(0).map(|bin| bin.main.to_owned()),
             executable: manifest.targets.bin.get(0).map(|bin| bin.name.to_owned()),
-            tests: manifest
-                .targets
-                .test
-                .iter()
-                .map(|test| test.main.to_owned())
-                .collect(),
+            tests: match dialect {
+                Dialect::Idris1 => manifest
+                    .targets
+                    .test
+                    .iter()
+                    .map(|test| test.main.to_owned())
+                    .collect(),
+                // Idris 2 package files don't have tests
+                Dialect::Idris2 => Vec::new(),
+            },
         })
     }
 }
 
+impl TryFrom<Ipkg> for Manifest {
+    type Error = failure::Error;
+
+    fn try_from(ipkg: Ipkg) -> Result<Self> {
+        ipkg.into_manifest(None)
+    }
+}
+
+impl TryFrom<Manifest> for Ipkg {
+    type Error = failure::Error;
+
+    fn try_from(manifest: Manifest) -> Result<Self> {
+        Ipkg::from_manifest(manifest, Dialect::Idris1)
+    }
+}
+
 impl FromStr for Ipkg {
     type Err = failure::Error;
 
@@ -216,6 +502,14 @@ impl FromStr for Ipkg {
         for item in items {
             match item {
                 parse::IpkgItem::PackageName(name) => ipkg.name = name,
+                parse::IpkgItem::Depends(depends) => {
+                    ipkg.dialect = Dialect::Idris2;
+                    ipkg.depends = depends;
+                }
+                parse::IpkgItem::LangVersion(bounds) => {
+                    ipkg.dialect = Dialect::Idris2;
+                    ipkg.langversion = Some(bounds);
+                }
                 parse::IpkgItem::Vec(key, mut val) => match key.as_str() {
                     "pkgs" => ipkg.pkgs = val,
                     "brief" => ipkg.brief = val.pop(),
@@ -223,14 +517,37 @@ impl FromStr for Ipkg {
                     "readme" => ipkg.readme = val.pop(),
                     "license" => ipkg.license = val.pop(),
                     "author" => ipkg.author = val.pop(),
+                    "authors" => {
+                        ipkg.dialect = Dialect::Idris2;
+                        ipkg.author = val.pop();
+                    }
                     "maintainer" => ipkg.maintainer = val.pop(),
+                    "maintainers" => {
+                        ipkg.dialect = Dialect::Idris2;
+                        ipkg.maintainer = val.pop();
+                    }
                     "homepage" => ipkg.homepage = val.pop(),
                     "sourceloc" => ipkg.sourceloc = val.pop(),
                     "bugtracker" => ipkg.bugtracker = val.pop(),
                     "libs" => ipkg.libs = val,
                     "objs" => ipkg.objs = val,
                     "makefile" => ipkg.makefile = val.pop(),
-                    "opts" => ipkg.opts = val,
+                    "prebuild" => {
+                        ipkg.dialect = Dialect::Idris2;
+                        ipkg.prebuild = val.pop();
+                    }
+                    "postbuild" => {
+                        ipkg.dialect = Dialect::Idris2;
+                        ipkg.postbuild = val.pop();
+                    }
+                    // Options are given as one string, which the compiler splits on whitespace
+                    "opts" => {
+                        ipkg.opts = val
+                            .iter()
+                            .flat_map(|opts| opts.split_whitespace())
+                            .map(|opt| opt.to_owned())
+                            .collect()
+                    }
                     "sourcedir" => {
                         ipkg.sourcedir = val
                             .pop()
@@ -248,10 +565,186 @@ impl FromStr for Ipkg {
     }
 }
 
+impl fmt::Display for Ipkg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let idris2 = self.dialect == Dialect::Idris2;
+        // Idris 2 wants every string quoted, while Idris 1 only needs quotes around strings with
+        // spaces in them
+        let string = |s: &str| {
+            if idris2 || s.contains(char::is_whitespace) {
+                format!("\"{}\"", s)
+            } else {
+                s.to_owned()
+            }
+        };
+
+        writeln!(f, "package {}", self.name)?;
+        writeln!(f)?;
+
+        if let Some(version) = &self.version {
+            if idris2 {
+                writeln!(f, "version = {}", version)?;
+            } else {
+                writeln!(f, "version = {}", string(version))?;
+            }
+        }
+        if let Some(langversion) = self.langversion.as_ref().filter(|_| idris2) {
+            writeln!(f, "langversion {}", langversion)?;
+        }
+        let fields = [
+            ("brief", &self.brief),
+            (if idris2 { "authors" } else { "author" }, &self.author),
+            (
+                if idris2 { "maintainers" } else { "maintainer" },
+                &self.maintainer,
+            ),
+            ("license", &self.license),
+            ("readme", &self.readme),
+            ("homepage", &self.homepage),
+            ("sourceloc", &self.sourceloc),
+            ("bugtracker", &self.bugtracker),
+        ];
+        for (key, val) in fields.iter() {
+            if let Some(val) = val {
+                writeln!(f, "{} = {}", key, string(val))?;
+            }
+        }
+        writeln!(f)?;
+
+        if idris2 {
+            if !self.depends.is_empty() || !self.pkgs.is_empty() {
+                let depends = self
+                    .pkgs
+                    .iter()
+                    .map(|pkg| pkg.to_owned())
+                    .chain(self.depends.iter().map(|(name, bounds)| match bounds {
+                        Some(bounds) => format!("{} {}", name, bounds),
+                        None => name.to_owned(),
+                    }))
+                    .join(", ");
+                writeln!(f, "depends = {}", depends)?;
+            }
+        } else if !self.pkgs.is_empty() || !self.depends.is_empty() {
+            let pkgs = self
+                .pkgs
+                .iter()
+                .chain(self.depends.iter().map(|(name, _)| name))
+                .join(", ");
+            writeln!(f, "pkgs = {}", pkgs)?;
+        }
+
+        writeln!(f, "sourcedir = {}", string(&self.sourcedir))?;
+        if !self.opts.is_empty() {
+            writeln!(f, "opts = \"{}\"", self.opts.join(" "))?;
+        }
+        if !self.modules.is_empty() {
+            writeln!(f, "modules = {}", self.modules.join(", "))?;
+        }
+        if let Some(main) = &self.main {
+            writeln!(f, "main = {}", main)?;
+        }
+        if let Some(executable) = &self.executable {
+            writeln!(f, "executable = {}", string(executable))?;
+        }
+
+        if idris2 {
+            if let Some(prebuild) = &self.prebuild {
+                writeln!(f, "prebuild = {}", string(prebuild))?;
+            }
+            if let Some(postbuild) = &self.postbuild {
+                writeln!(f, "postbuild = {}", string(postbuild))?;
+            }
+        } else {
+            if let Some(makefile) = &self.makefile {
+                writeln!(f, "makefile = {}", string(makefile))?;
+            }
+            if !self.libs.is_empty() {
+                writeln!(f, "libs = {}", self.libs.join(", "))?;
+            }
+            if !self.objs.is_empty() {
+                writeln!(f, "objs = {}", self.objs.join(", "))?;
+            }
+            if !self.tests.is_empty() {
+                writeln!(f, "tests = {}", self.tests.join(", "))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses an Idris 2 package version, which can leave out its minor and patch numbers.
+fn parse_version(version: &str) -> Option<Version> {
+    let mut parts = version.split('.').collect::<Vec<_>>();
+    if parts.len() > 3 {
+        return None;
+    }
+    parts.resize(3, "0");
+    Version::parse(&parts.join(".")).ok()
+}
+
+/// Converts Idris 2 version bounds (e.g. `>= 0.3 && < 0.4`) into a constraint.
+fn idris2_constraint(bounds: &str) -> Result<Constraint> {
+    let mut lower = None;
+    let mut upper = None;
+    for bound in bounds
+        .split("&&")
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+    {
+        let ver = bound.trim_start_matches(&['<', '>', '='][..]);
+        let op = &bound[..bound.len() - ver.len()];
+        let ver = ver.trim();
+        match op {
+            ">=" | ">" => lower = Some(format!("{}{}", op, ver)),
+            "<=" | "<" => upper = Some(format!("{}{}", op, ver)),
+            "==" => {
+                lower = Some(format!(">={}", ver));
+                upper = Some(format!("<={}", ver));
+            }
+            _ => bail!("invalid version bound `{}`", bound),
+        }
+    }
+
+    let constraint = if lower.is_none() && upper.is_none() {
+        "any".to_owned()
+    } else {
+        lower.into_iter().chain(upper).join(" ")
+    };
+    Constraint::from_str(&constraint)
+        .map_err(|_| format_err!("invalid version bounds `{}`", bounds))
+}
+
+/// Converts a constraint into Idris 2 version bounds. Idris 2 can't express a union of ranges,
+/// so those are left unbounded.
+fn idris2_bounds(constraint: &Constraint) -> Option<String> {
+    let ranges = constraint.retrieve();
+    if ranges.len() != 1 {
+        return None;
+    }
+    let range = ranges.iter().next()?;
+
+    let bound = |interval: &Interval, lower: bool| match interval {
+        Interval::Unbounded => None,
+        Interval::Closed(v, _) => Some(format!("{} {}", if lower { ">=" } else { "<=" }, v)),
+        Interval::Open(v, _) => Some(format!("{} {}", if lower { ">" } else { "<" }, v)),
+    };
+    let bounds = bound(range.lower(), true)
+        .into_iter()
+        .chain(bound(range.upper(), false))
+        .join(" && ");
+
+    if bounds.is_empty() {
+        None
+    } else {
+        Some(bounds)
+    }
+}
+
 mod parse {
     use nom::{
         branch::alt,
-        bytes::complete::{is_not, tag, take, take_until, take_while1},
+        bytes::complete::{is_not, tag, take, take_until, take_while, take_while1},
         character::complete::{multispace0, space0, space1},
         combinator::not,
         multi::{many0_count, many1, separated_list},
@@ -263,10 +756,14 @@ mod parse {
     pub enum IpkgItem {
         PackageName(String),
         Vec(String, Vec<String>),
+        /// Idris 2 dependencies, along with their version bounds
+        Depends(Vec<(String, Option<String>)>),
+        /// The Idris 2 versions a package can be built with
+        LangVersion(String),
     }
 
     pub fn parse_items(i: &str) -> IResult<&str, Vec<IpkgItem>> {
-        let (i, items) = many1(alt((package_name, key_vec)))(i)?;
+        let (i, items) = many1(alt((package_name, depends, langversion, key_vec)))(i)?;
         let (i, _) = multispace0(i)?;
         let (i, _) = not(take(1usize))(i)?;
         Ok((i, items))
@@ -287,6 +784,33 @@ mod parse {
         Ok((i, (IpkgItem::Vec(key.to_owned(), vec))))
     }
 
+    fn depends(i: &str) -> IResult<&str, IpkgItem> {
+        let (i, _) = many0_count(comment)(i)?;
+        let (i, _) = multispace0(i)?;
+        let (i, _) = tag("depends")(i)?;
+        let (i, _) = space0(i)?;
+        let (i, _) = tag("=")(i)?;
+        let (i, deps) = separated_list(tuple((multispace0, tag(","), multispace0)), depend)(i)?;
+        Ok((i, IpkgItem::Depends(deps)))
+    }
+
+    fn depend(i: &str) -> IResult<&str, (String, Option<String>)> {
+        let (i, _) = multispace0(i)?;
+        let (i, name) = take_while1(|c: char| valid_name_char(c))(i)?;
+        let (i, bounds) = take_while(|c: char| valid_bound_char(c))(i)?;
+        let bounds = Some(bounds.trim().to_owned()).filter(|x| !x.is_empty());
+        Ok((i, (name.to_owned(), bounds)))
+    }
+
+    // Unlike every other field, `langversion` doesn't have an equals sign: `langversion >= 0.5`
+    fn langversion(i: &str) -> IResult<&str, IpkgItem> {
+        let (i, _) = many0_count(comment)(i)?;
+        let (i, _) = multispace0(i)?;
+        let (i, _) = tag("langversion")(i)?;
+        let (i, bounds) = take_while1(|c: char| valid_bound_char(c))(i)?;
+        Ok((i, IpkgItem::LangVersion(bounds.trim().to_owned())))
+    }
+
     fn key(i: &str) -> IResult<&str, String> {
         let (i, _) = many0_count(comment)(i)?;
         let (i, _) = multispace0(i)?;
@@ -336,6 +860,10 @@ mod parse {
         c.is_ascii_alphanumeric() || c == '_' || c == '-'
     }
 
+    fn valid_bound_char(c: char) -> bool {
+        c.is_ascii_digit() || " \t<>=&.".contains(c)
+    }
+
     #[test]
     fn test_parse() {
         let input = r#"
@@ -379,3 +907,157 @@ tests = Test.testDouble
         assert_eq!(items, expected);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDRIS2: &str = r#"
+package hello-world
+
+version = 0.2
+authors = "Someone"
+langversion >= 0.5.1
+depends = base, contrib
+        , lightyear >= 0.3 && < 0.4
+
+sourcedir = "src"
+modules = Hello
+main = Main
+executable = "hello"
+prebuild = "make -C c"
+"#;
+
+    #[test]
+    fn ipkg_idris2_to_manifest() {
+        let ipkg = Ipkg::from_str(IDRIS2).unwrap();
+        assert_eq!(ipkg.dialect(), Dialect::Idris2);
+        assert_eq!(ipkg.langversion.as_deref(), Some(">= 0.5.1"));
+
+        // Without any indices, every dependency has to come from the compiler
+        let manifest = Manifest::try_from(ipkg).unwrap();
+        assert_eq!(manifest.version(), &Version::new(0, 2, 0));
+        assert!(manifest.dependencies.is_empty());
+        assert_eq!(
+            manifest.targets.bin[0].idris_opts,
+            vec!["-p", "base", "-p", "contrib", "-p", "lightyear"]
+        );
+        assert_eq!(manifest.script("prebuild").unwrap(), vec!["make -C c"]);
+    }
+
+    #[test]
+    fn ipkg_migrate() {
+        let ipkg = Ipkg::from_str(
+            r#"
+package idris-maths
+version = "0.1"
+sourcedir = src
+pkgs = contrib, lightyear
+modules = NumOps
+libs = m
+tests = Test.testDouble
+postbuild = "make docs"
+"#,
+        )
+        .unwrap();
+
+        let (manifest, untranslated) = ipkg.migrate("maths", &Indices::default()).unwrap();
+        assert_eq!(manifest.name().as_str(), "maths/idris-maths");
+        assert_eq!(manifest.version(), &Version::new(0, 1, 0));
+        assert_eq!(manifest.targets.test[0].main, "Test.testDouble");
+        assert_eq!(
+            manifest.targets.lib.as_ref().unwrap().idris_opts,
+            vec!["-p", "contrib", "-p", "lightyear"]
+        );
+        assert_eq!(
+            manifest
+                .targets
+                .lib
+                .as_ref()
+                .unwrap()
+                .ffi
+                .as_ref()
+                .unwrap()
+                .link,
+            vec!["m"]
+        );
+        assert_eq!(manifest.script("postbuild").unwrap(), vec!["make docs"]);
+        // Built-in packages aren't worth mentioning, and hooks carry over as they are
+        assert_eq!(untranslated.len(), 1);
+        assert!(untranslated[0].contains("`lightyear` isn't in any index"));
+    }
+
+    #[test]
+    fn ipkg_idris2_bounds() {
+        let constraint = idris2_constraint(">= 0.3 && < 0.4").unwrap();
+        assert_eq!(constraint, Constraint::from_str(">=0.3 <0.4").unwrap());
+        assert_eq!(idris2_bounds(&constraint).unwrap(), ">= 0.3.0 && < 0.4.0");
+
+        let exact = idris2_constraint("== 1.0.2").unwrap();
+        assert_eq!(idris2_bounds(&exact).unwrap(), ">= 1.0.2 && <= 1.0.2");
+
+        assert!(idris2_constraint("~ 1.0").is_err());
+        assert_eq!(idris2_bounds(&Constraint::any()), None);
+    }
+
+    #[test]
+    fn ipkg_emit_dialects() {
+        let manifest = Manifest::from_str(
+            r#"
+[package]
+name = "grp/hello"
+version = "0.1.0"
+authors = ["Someone"]
+
+[dependencies]
+"grp/lightyear" = "0.3"
+
+[targets.lib]
+path = "src"
+mods = ["Hello"]
+idris_opts = ["-p", "contrib", "--total"]
+driver = "ipkg"
+
+[[targets.test]]
+path = "src"
+main = "Test.Hello"
+
+[scripts]
+prebuild = "make -C c"
+"#,
+        )
+        .unwrap();
+
+        let idris1 = Ipkg::try_from(manifest.clone()).unwrap().to_string();
+        assert!(idris1.contains("pkgs = lightyear\n"));
+        assert!(idris1.contains("author = Someone\n"));
+        assert!(idris1.contains("tests = Test.Hello\n"));
+        assert!(idris1.contains("opts = \"-p contrib --total\"\n"));
+        assert!(!idris1.contains("prebuild"));
+
+        let idris2 = Ipkg::from_manifest(manifest, Dialect::Idris2)
+            .unwrap()
+            .to_string();
+        assert!(idris2.contains("depends = lightyear >= 0.3.0 && < 0.4.0, contrib\n"));
+        assert!(idris2.contains("opts = \"--total\"\n"));
+        assert!(idris2.contains("authors = \"Someone\"\n"));
+        assert!(idris2.contains("sourcedir = \"src\"\n"));
+        assert!(idris2.contains("prebuild = \"make -C c\"\n"));
+        assert!(!idris2.contains("tests"));
+
+        // What we write out, we can read back in
+        let ipkg = Ipkg::from_str(&idris2).unwrap();
+        assert_eq!(ipkg.dialect(), Dialect::Idris2);
+        assert_eq!(
+            ipkg.depends(),
+            &[
+                (
+                    "lightyear".to_owned(),
+                    Some(">= 0.3.0 && < 0.4.0".to_owned())
+                ),
+                ("contrib".to_owned(), None)
+            ]
+        );
+        assert_eq!(ipkg.modules, vec!["Hello"]);
+    }
+}
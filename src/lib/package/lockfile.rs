@@ -91,6 +91,7 @@ impl From<LockfileToml> for Graph<Summary> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::remote::resolution::DirectRes;
 
     #[test]
     fn valid_lockfile() {
@@ -109,4 +110,36 @@ dependencies = [
 
         assert!(LockfileToml::from_str(lockfile).is_ok());
     }
+
+    #[test]
+    fn lockfile_checksums() {
+        let lockfile = r#"
+[[packages]]
+id = "terminator/one@tar+https://elba.io/one.tar.gz#sha512=cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e"
+version = "0.1.4"
+
+[[packages]]
+id = "terminator/two@tar+https://elba.io/two.tar.gz#blake2b=786a02f742015903c6c6fd852552d272912f4740e15847618a86e217f71f5419d25e1031afee585313896444934eb04b903a685b1448b755d56f701afe9be2ce"
+version = "0.1.4"
+        "#;
+
+        let toml = LockfileToml::from_str(lockfile).unwrap();
+        let fmts = toml
+            .packages
+            .iter()
+            .map(|pkg| match pkg.sum.id.resolution() {
+                Resolution::Direct(DirectRes::Tar {
+                    cksum: Some(cksum), ..
+                }) => cksum.fmt,
+                _ => panic!("expected a tarball with a checksum"),
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(fmts, vec![ChecksumFmt::Sha512, ChecksumFmt::Blake2b]);
+
+        // And the formats survive a round trip.
+        let out = toml::to_string(&toml).unwrap();
+        assert!(out.contains("#sha512=cf83e135"));
+        assert!(out.contains("#blake2b=786a02f7"));
+    }
 }
@@ -18,7 +18,7 @@ use walkdir::{DirEntry, WalkDir};
 use super::*;
 use crate::{
     remote::resolution::{DirectRes, IndexRes},
-    util::{valid_file, SubPath},
+    util::{config::Driver, valid_file, SubPath},
 };
 
 // TODO: Package aliasing. Have dummy alias files in the root target folder.
@@ -47,12 +47,21 @@ pub struct Manifest {
     pub dependencies: IndexMap<Name, DepReq>,
     #[serde(default = "IndexMap::new")]
     pub dev_dependencies: IndexMap<Name, DepReq>,
+    /// Packages whose binaries are used while working on this package, through `elba task`
+    #[serde(
+        default = "IndexMap::new",
+        rename = "tool-dependencies",
+        alias = "tool_dependencies"
+    )]
+    pub tool_dependencies: IndexMap<Name, DepReq>,
     #[serde(default)]
     pub targets: Targets,
     #[serde(default)]
     pub workspace: IndexMap<Name, SubPath>,
     #[serde(default)]
-    pub scripts: IndexMap<String, String>,
+    pub scripts: IndexMap<String, Script>,
+    #[serde(default)]
+    pub profile: IndexMap<String, Profile>,
 }
 
 impl Manifest {
@@ -74,11 +83,55 @@ impl Manifest {
         &self.package.name
     }
 
+    /// Looks up the build profile called `name`. The `dev` and `release` profiles always exist,
+    /// even if the manifest doesn't mention them.
+    pub fn profile(&self, name: &str) -> Result<Profile> {
+        match self.profile.get(name) {
+            Some(profile) => Ok(profile.clone()),
+            None => Profile::builtin(name),
+        }
+    }
+
+    /// The shell commands which make up the script called `name`, with references to other
+    /// scripts replaced by their commands.
+    pub fn script(&self, name: &str) -> Result<Vec<String>> {
+        self.script_inner(name, &mut vec![])
+    }
+
+    fn script_inner<'a>(&'a self, name: &'a str, stack: &mut Vec<&'a str>) -> Result<Vec<String>> {
+        if stack.contains(&name) {
+            bail!(format_err!(
+                "script `{}` refers to itself: {} > {}",
+                name,
+                stack.join(" > "),
+                name
+            ));
+        }
+        let script = self
+            .scripts
+            .get(name)
+            .ok_or_else(|| format_err!("script `{}` not found", name))?;
+
+        stack.push(name);
+        let mut cmds = vec![];
+        for cmd in script.commands() {
+            match cmd.strip_prefix('@') {
+                Some(other) => cmds.extend(self.script_inner(other.trim(), stack)?),
+                None => cmds.push(cmd.clone()),
+            }
+        }
+        stack.pop();
+
+        Ok(cmds)
+    }
+
+    /// The dependencies of this package. Dev and tool dependencies only matter to the root
+    /// package, so they're only included if `root` is set.
     pub fn deps(
         &self,
         ixmap: &IndexMap<String, IndexRes>,
         parent_pkg: &PackageId,
-        dev_deps: bool,
+        root: bool,
     ) -> Result<IndexMap<PackageId, Constraint>> {
         let mut deps = IndexMap::new();
         for (n, dep) in &self.dependencies {
@@ -87,8 +140,8 @@ impl Manifest {
             deps.insert(pid, c);
         }
 
-        if dev_deps {
-            for (n, dep) in &self.dev_dependencies {
+        if root {
+            for (n, dep) in self.dev_dependencies.iter().chain(&self.tool_dependencies) {
                 let dep = dep.clone();
                 let (pid, c) = dep.into_dep(ixmap, parent_pkg, n.clone())?;
                 deps.insert(pid, c);
@@ -177,6 +230,25 @@ impl Manifest {
         {
             bail!(format_err!("one of the keywords contains whitespace"));
         }
+        if let Some(name) = self.profile.keys().find(|name| !valid_profile_name(name)) {
+            bail!(format_err!(
+                "profile name `{}` can only contain letters, numbers, dashes and underscores",
+                name
+            ));
+        }
+        if let Some(ffi) = self.targets.lib.as_ref().and_then(|lib| lib.ffi.as_ref()) {
+            if let Some(lib) = ffi.link.iter().find(|lib| {
+                lib.is_empty() || lib.starts_with('-') || lib.contains(char::is_whitespace)
+            }) {
+                bail!(format_err!(
+                    "ffi library `{}` should be the name of a single library, without `-l`",
+                    lib
+                ));
+            }
+        }
+        for name in self.scripts.keys() {
+            self.script(name)?;
+        }
         Ok(())
     }
 }
@@ -193,6 +265,30 @@ impl FromStr for Manifest {
     }
 }
 
+/// A script in the `[scripts]` section: either a single shell command, or a list of them which are
+/// run one after the other. A command of the form `@name` runs the script called `name`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Script {
+    Command(String),
+    Commands(Vec<String>),
+}
+
+impl Script {
+    pub fn commands(&self) -> &[String] {
+        match self {
+            Script::Command(cmd) => std::slice::from_ref(cmd),
+            Script::Commands(cmds) => cmds,
+        }
+    }
+}
+
+impl From<String> for Script {
+    fn from(cmd: String) -> Self {
+        Script::Command(cmd)
+    }
+}
+
 #[serde(deny_unknown_fields)]
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct PackageInfo {
@@ -207,6 +303,8 @@ pub struct PackageInfo {
     pub readme: Option<SubPath>,
     pub license: Option<String>,
     pub exclude: Option<Vec<String>>,
+    /// An Idris file which is compiled and run before the package is built
+    pub build: Option<SubPath>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -224,6 +322,8 @@ pub enum DepReq {
         git: Url,
         #[serde(default = "default_tag")]
         tag: String,
+        #[serde(default = "default_submodules")]
+        submodules: bool,
     },
 }
 
@@ -231,6 +331,10 @@ fn default_tag() -> String {
     "master".to_owned()
 }
 
+fn default_submodules() -> bool {
+    true
+}
+
 impl DepReq {
     pub fn into_dep(
         self,
@@ -274,8 +378,16 @@ impl DepReq {
                     ))
                 }
             }
-            DepReq::Git { git, tag } => {
-                let res = DirectRes::Git { repo: git, tag };
+            DepReq::Git {
+                git,
+                tag,
+                submodules,
+            } => {
+                let res = DirectRes::Git {
+                    repo: git,
+                    tag,
+                    submodules,
+                };
                 let pi = PackageId::new(n, res.into());
                 Ok((pi, Constraint::any()))
             }
@@ -283,6 +395,43 @@ impl DepReq {
     }
 }
 
+/// A set of build settings which can be picked with `--release` or `--profile <name>`.
+///
+/// The profile of the root package applies to every package in the build.
+#[serde(deny_unknown_fields)]
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    /// Options passed to the Idris compiler for every module
+    #[serde(default)]
+    pub idris_opts: Vec<String>,
+    /// The codegen backend to use, unless one is given on the command line
+    pub backend: Option<String>,
+    /// Options passed to the codegen backend
+    #[serde(default)]
+    pub backend_opts: Vec<String>,
+    /// Whether to treat the codegen backend as portable, unless the command line says otherwise
+    pub portable: Option<bool>,
+}
+
+impl Profile {
+    /// The profiles which exist even if a manifest doesn't define them.
+    pub fn builtin(name: &str) -> Result<Self> {
+        if name == "dev" || name == "release" {
+            Ok(Profile::default())
+        } else {
+            bail!("the profile `{}` isn't defined in the manifest", name)
+        }
+    }
+}
+
+/// Profile names end up as directory names under `target/`, so they have to be tame.
+pub fn valid_profile_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
 #[serde(deny_unknown_fields)]
 #[derive(Deserialize, Serialize, Default, Debug, Clone)]
 pub struct Targets {
@@ -301,6 +450,26 @@ pub struct LibTarget {
     pub mods: Vec<String>,
     #[serde(default)]
     pub idris_opts: Vec<String>,
+    /// How the library should be built, if it shouldn't be built the way the configuration says
+    pub driver: Option<Driver>,
+    /// C code which the library's foreign functions need
+    pub ffi: Option<FfiTarget>,
+}
+
+#[serde(deny_unknown_fields)]
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct FfiTarget {
+    /// C files which get compiled and linked into any executable using the library
+    #[serde(default)]
+    pub c_sources: Vec<SubPath>,
+    /// Directories with headers, for the C sources of this library and of anything depending on it
+    #[serde(default)]
+    pub include_dirs: Vec<SubPath>,
+    #[serde(default)]
+    pub cflags: Vec<String>,
+    /// Libraries to link against, as they would be passed to `-l`
+    #[serde(default)]
+    pub link: Vec<String>,
 }
 
 fn default_lib_subpath() -> SubPath {
@@ -542,4 +711,142 @@ mods = [
 
         assert!(Manifest::from_str(manifest).is_err());
     }
+
+    #[test]
+    fn manifest_ffi() {
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[targets.lib]
+mods = ["RingDing.Test"]
+
+[targets.lib.ffi]
+c_sources = ["c/ring.c"]
+include_dirs = ["c/include"]
+cflags = ["-O2"]
+link = ["m", "curl"]
+"#;
+
+        let parsed = Manifest::from_str(manifest).unwrap();
+        let ffi = parsed.targets.lib.unwrap().ffi.unwrap();
+        assert_eq!(ffi.c_sources[0].0, Path::new("c/ring.c"));
+        assert_eq!(ffi.link, vec!["m", "curl"]);
+
+        assert!(Manifest::from_str(&manifest.replace(r#""curl""#, r#""-lcurl""#)).is_err());
+        assert!(Manifest::from_str(&manifest.replace("c/ring.c", "../ring.c")).is_err());
+    }
+
+    #[test]
+    fn manifest_tool_deps() {
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[dependencies]
+'great/c' = { path = 'deps/c' }
+
+[tool-dependencies]
+'gen/lexer' = { path = 'tools/lexer' }
+"#;
+
+        let parsed = Manifest::from_str(manifest).unwrap();
+        let root = PackageId::new(
+            parsed.name().clone(),
+            DirectRes::Dir {
+                path: PathBuf::from("/ring_ding"),
+            }
+            .into(),
+        );
+        let names = |root_deps| {
+            parsed
+                .deps(&IndexMap::new(), &root, root_deps)
+                .unwrap()
+                .keys()
+                .map(|pkg| pkg.name().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(true), vec!["great/c", "gen/lexer"]);
+        assert_eq!(names(false), vec!["great/c"]);
+    }
+
+    #[test]
+    fn manifest_scripts() {
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[scripts]
+gen = "make -C c"
+fmt = ["idris2 --format A.idr", "idris2 --format B.idr"]
+check = ["@gen", "@fmt", "./check.sh"]
+"#;
+
+        let parsed = Manifest::from_str(manifest).unwrap();
+        assert_eq!(parsed.script("gen").unwrap(), vec!["make -C c"]);
+        assert_eq!(
+            parsed.script("check").unwrap(),
+            vec![
+                "make -C c",
+                "idris2 --format A.idr",
+                "idris2 --format B.idr",
+                "./check.sh"
+            ]
+        );
+        assert!(parsed.script("nope").is_err());
+
+        assert!(Manifest::from_str(&manifest.replace("@fmt", "@lint")).is_err());
+        assert!(Manifest::from_str(&manifest.replace("make -C c", "@check")).is_err());
+    }
+
+    #[test]
+    fn manifest_profiles() {
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[profile.release]
+idris_opts = ["--total"]
+backend_opts = ["-O2"]
+
+[profile.bench]
+backend = "javascript"
+portable = true
+"#;
+
+        let manifest = Manifest::from_str(manifest).unwrap();
+        assert_eq!(manifest.profile("dev").unwrap(), Profile::default());
+        assert_eq!(
+            manifest.profile("release").unwrap().idris_opts,
+            vec!["--total"]
+        );
+        assert_eq!(
+            manifest
+                .profile("bench")
+                .unwrap()
+                .backend
+                .as_ref()
+                .map(|x| x.as_str()),
+            Some("javascript")
+        );
+        assert!(manifest.profile("nope").is_err());
+
+        let bad = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[profile."../oops"]
+"#;
+        assert!(Manifest::from_str(bad).is_err());
+    }
 }
@@ -11,9 +11,11 @@ use crate::{
 use failure::{bail, format_err};
 use semver::Version;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256, Sha512};
 use std::{
     fmt,
     hash::{Hash, Hasher},
+    io::{self, Read},
     str::FromStr,
     sync::Arc,
 };
@@ -264,6 +266,37 @@ impl Serialize for PackageId {
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum ChecksumFmt {
     Sha256,
+    Sha512,
+    Blake2b,
+}
+
+impl ChecksumFmt {
+    /// Hashes everything that can be read from `r`, returning the hex-encoded digest.
+    pub fn digest(self, mut r: impl Read) -> io::Result<String> {
+        match self {
+            ChecksumFmt::Sha256 => {
+                let mut hash = Sha256::new();
+                io::copy(&mut r, &mut hash)?;
+                Ok(hex::encode(hash.result()))
+            }
+            ChecksumFmt::Sha512 => {
+                let mut hash = Sha512::new();
+                io::copy(&mut r, &mut hash)?;
+                Ok(hex::encode(hash.result()))
+            }
+            ChecksumFmt::Blake2b => {
+                let mut hash = blake2b_simd::State::new();
+                io::copy(&mut r, &mut hash)?;
+                Ok(hash.finalize().to_hex().to_string())
+            }
+        }
+    }
+}
+
+impl Default for ChecksumFmt {
+    fn default() -> Self {
+        ChecksumFmt::Sha256
+    }
 }
 
 impl FromStr for ChecksumFmt {
@@ -272,6 +305,8 @@ impl FromStr for ChecksumFmt {
     fn from_str(s: &str) -> Result<Self> {
         match s {
             "sha256" => Ok(ChecksumFmt::Sha256),
+            "sha512" => Ok(ChecksumFmt::Sha512),
+            "blake2b" => Ok(ChecksumFmt::Blake2b),
             _ => Err(Error::InvalidSourceUrl)?,
         }
     }
@@ -281,16 +316,59 @@ impl fmt::Display for ChecksumFmt {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             ChecksumFmt::Sha256 => write!(f, "sha256"),
+            ChecksumFmt::Sha512 => write!(f, "sha512"),
+            ChecksumFmt::Blake2b => write!(f, "blake2b"),
         }
     }
 }
 
+impl<'de> Deserialize<'de> for ChecksumFmt {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for ChecksumFmt {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Checksum {
     pub fmt: ChecksumFmt,
     pub hash: String,
 }
 
+impl Checksum {
+    /// Computes the checksum of everything that can be read from `r` in the given format.
+    pub fn compute(fmt: ChecksumFmt, r: impl Read) -> io::Result<Self> {
+        Ok(Checksum {
+            fmt,
+            hash: fmt.digest(r)?,
+        })
+    }
+
+    /// Checks that the contents of `r` match this checksum.
+    pub fn verify(&self, r: impl Read) -> Result<()> {
+        let real = self.fmt.digest(r)?;
+        if !real.eq_ignore_ascii_case(&self.hash) {
+            bail!(
+                "{} checksum doesn't match: expected {}, got {}",
+                self.fmt,
+                self.hash,
+                real
+            )
+        }
+
+        Ok(())
+    }
+}
+
 impl FromStr for Checksum {
     type Err = failure::Error;
 
@@ -464,3 +542,36 @@ impl FromStr for Spec {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_formats() {
+        let empty = [
+            (
+                ChecksumFmt::Sha256,
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+            (
+                ChecksumFmt::Sha512,
+                "cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce\
+                 47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e",
+            ),
+            (
+                ChecksumFmt::Blake2b,
+                "786a02f742015903c6c6fd852552d272912f4740e15847618a86e217f71f5419\
+                 d25e1031afee585313896444934eb04b903a685b1448b755d56f701afe9be2ce",
+            ),
+        ];
+
+        for (fmt, hash) in empty.iter() {
+            let cksum = Checksum::compute(*fmt, &b""[..]).unwrap();
+            assert_eq!(&cksum.hash, hash);
+            assert_eq!(Checksum::from_str(&cksum.to_string()).unwrap(), cksum);
+            assert!(cksum.verify(&b""[..]).is_ok());
+            assert!(cksum.verify(&b"not empty"[..]).is_err());
+        }
+    }
+}
@@ -61,6 +61,10 @@ impl FromStr for IndexConfig {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct IndexConfInner {
     pub secure: bool,
+    /// The checksum format which should be used when recording the checksums of this index's
+    /// tarballs.
+    #[serde(default)]
+    pub checksum: ChecksumFmt,
     pub dependencies: IndexMap<String, IndexRes>,
 }
 
@@ -68,6 +72,7 @@ impl Default for IndexConfInner {
     fn default() -> Self {
         IndexConfInner {
             secure: false,
+            checksum: ChecksumFmt::default(),
             dependencies: IndexMap::new(),
         }
     }
@@ -202,6 +207,18 @@ impl Indices {
             })
             .collect::<Result<Vec<_>>>()
     }
+
+    /// Finds every package whose name (ignoring the group) is `name`, for package formats which
+    /// don't have groups.
+    pub fn find_by_name(&self, name: &str) -> Vec<(Name, &IndexRes)> {
+        let normalized = name.to_lowercase().replace("_", "-");
+        self.indices
+            .iter()
+            .flat_map(|(ir, ix)| ix.packages().map(move |p| (p, ir)))
+            .filter_map(|(p, ir)| Some((Name::from_str(&p).ok()?, ir)))
+            .filter(|(n, _)| n.normalized_name() == normalized)
+            .collect()
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
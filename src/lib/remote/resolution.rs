@@ -1,13 +1,14 @@
-use std::io;
-
-use std::{fmt, fs, io::BufReader, path::PathBuf, str::FromStr};
+use std::{
+    fmt, fs,
+    io::{BufReader, Seek, SeekFrom},
+    path::PathBuf,
+    str::FromStr,
+};
 
 use failure::{bail, format_err, ResultExt};
 use flate2::read::GzDecoder;
 use git2::{BranchType, Repository, Sort};
-use reqwest::blocking::Client;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
-use sha2::{Digest, Sha256};
 use tar::Archive;
 use url::Url;
 
@@ -17,6 +18,7 @@ use crate::{
         clear_dir,
         error::{Error, Result},
         git::{clone, fetch, reset, update_submodules},
+        http::Client,
         lock::DirLock,
     },
 };
@@ -127,7 +129,14 @@ impl Resolution {
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum DirectRes {
     /// Git: the package originated from a git repository.
-    Git { repo: Url, tag: String },
+    ///
+    /// If `submodules` is false, the submodules of the repository won't be checked out. This is
+    /// stored as a `submodules=false` query parameter in the resolution url.
+    Git {
+        repo: Url,
+        tag: String,
+        submodules: bool,
+    },
     /// Dir: the package is on disk in a folder directory.
     Dir { path: PathBuf },
     /// Tar: the package is an archive stored somewhere.
@@ -155,19 +164,12 @@ fn retrieve_tar(
     target: &DirLock,
     cksum: Option<&Checksum>,
 ) -> Result<()> {
-    let mut resp = client
-        .get(url.as_str())
-        .send()
-        .and_then(|resp| resp.error_for_status())?;
+    let buf = client.get(&url)?;
 
-    let mut buf: Vec<u8> = vec![];
-    resp.copy_to(&mut buf)?;
-
-    let hash = hex::encode(Sha256::digest(&buf[..]).as_slice());
     if let Some(cksum) = cksum {
-        if cksum.hash != hash {
-            bail!(format_err!("tarball checksum doesn't match real checksum"));
-        }
+        cksum
+            .verify(&buf[..])
+            .with_context(|e| format_err!("invalid tarball {}: {}", url, e))?;
     }
 
     let archive = GzDecoder::new(&buf[..]);
@@ -199,19 +201,16 @@ impl DirectRes {
                 }
                 "file" => {
                     dl_f(false)?;
-                    let mut archive =
-                        fs::File::open(target.path()).context(Error::CannotDownload)?;
-
-                    let mut hash = Sha256::new();
-                    io::copy(&mut archive, &mut hash).context(Error::CannotDownload)?;
-                    let hash = hex::encode(hash.result());
+                    let path = url
+                        .to_file_path()
+                        .map_err(|_| format_err!("invalid tarball path {}", url))?;
+                    let mut archive = fs::File::open(&path).context(Error::CannotDownload)?;
 
                     if let Some(cksum) = cksum {
-                        if cksum.hash != hash {
-                            return Err(format_err!(
-                                "tarball checksum doesn't match real checksum"
-                            ))?;
-                        }
+                        cksum.verify(&mut archive).with_context(|e| {
+                            format_err!("invalid tarball {}: {}", path.display(), e)
+                        })?;
+                        archive.seek(SeekFrom::Start(0))?;
                     }
 
                     let archive = BufReader::new(archive);
@@ -228,7 +227,11 @@ impl DirectRes {
                 }
                 _ => unreachable!(),
             },
-            DirectRes::Git { repo: url, tag } => {
+            DirectRes::Git {
+                repo: url,
+                tag,
+                submodules,
+            } => {
                 // If we find a directory which already has a repo, we just check out the correct
                 // version of it. Whether or not a new dir is created isn't our job, that's for the
                 // Cache. If the Cache points to a directory that already exists, it means that the
@@ -258,6 +261,7 @@ impl DirectRes {
                                         return Ok(Some(DirectRes::Git {
                                             repo: url.clone(),
                                             tag: cur.id().to_string(),
+                                            submodules: *submodules,
                                         }));
                                     }
                                 }
@@ -280,6 +284,7 @@ impl DirectRes {
                                             return Ok(Some(DirectRes::Git {
                                                 repo: url.clone(),
                                                 tag: c.id().to_string(),
+                                                submodules: *submodules,
                                             }));
                                         }
                                     } else {
@@ -299,6 +304,7 @@ impl DirectRes {
                                             return Ok(Some(DirectRes::Git {
                                                 repo: url.clone(),
                                                 tag: obj.id().to_string(),
+                                                submodules: *submodules,
                                             }));
                                         }
                                     }
@@ -309,7 +315,7 @@ impl DirectRes {
                         // Get everything!!
                         dl_f(true)?;
                         let refspec = "refs/heads/*:refs/heads/*";
-                        fetch(&mut repo, &url, refspec).with_context(|e| {
+                        fetch(&mut repo, &url, &[refspec], client).with_context(|e| {
                             format_err!("couldn't fetch git repo {}: {}", url, e)
                         })?;
                         repo
@@ -317,7 +323,7 @@ impl DirectRes {
                     Err(_) => {
                         clear_dir(target.path())?;
                         dl_f(true)?;
-                        clone(url, target.path()).with_context(|e| {
+                        clone(url, target.path(), client).with_context(|e| {
                             format_err!("couldn't fetch git repo {}:\n{}", url, e)
                         })?
                     }
@@ -326,15 +332,18 @@ impl DirectRes {
                 let obj = repo.revparse_single(&tag).context(Error::CannotDownload)?;
                 reset(&repo, &obj)
                     .with_context(|e| format_err!("couldn't fetch git repo {}:\n{}", url, e))?;
-                update_submodules(&repo).with_context(|e| {
-                    format_err!("couldn't update submodules for git repo {}:\n{}", url, e)
-                })?;
+                if *submodules {
+                    update_submodules(&repo, client).with_context(|e| {
+                        format_err!("couldn't update submodules for git repo {}:\n{}", url, e)
+                    })?;
+                }
 
                 let id = obj.peel_to_commit()?.id().to_string();
 
                 Ok(Some(DirectRes::Git {
                     repo: url.clone(),
                     tag: id,
+                    submodules: *submodules,
                 }))
             }
             DirectRes::Dir { path } => {
@@ -393,8 +402,31 @@ impl FromStr for DirectRes {
                 let mut url = Url::parse(rest).context(Error::InvalidSourceUrl)?;
                 let tag = url.fragment().unwrap_or_else(|| "master").to_owned();
 
+                let mut submodules = true;
+                let query = url
+                    .query_pairs()
+                    .filter(|(k, v)| {
+                        if k == "submodules" {
+                            submodules = v != "false";
+                            false
+                        } else {
+                            true
+                        }
+                    })
+                    .map(|(k, v)| (k.into_owned(), v.into_owned()))
+                    .collect::<Vec<_>>();
+                if query.is_empty() {
+                    url.set_query(None);
+                } else {
+                    url.query_pairs_mut().clear().extend_pairs(query);
+                }
+
                 url.set_fragment(None);
-                Ok(DirectRes::Git { repo: url, tag })
+                Ok(DirectRes::Git {
+                    repo: url,
+                    tag,
+                    submodules,
+                })
             }
             "dir" => {
                 let path = PathBuf::from(rest);
@@ -405,7 +437,7 @@ impl FromStr for DirectRes {
                 if url.scheme() != "http" && url.scheme() != "https" && url.scheme() != "file" {
                     return Err(Error::InvalidSourceUrl)?;
                 }
-                let cksum = url.fragment().and_then(|x| Checksum::from_str(x).ok());
+                let cksum = url.fragment().map(Checksum::from_str).transpose()?;
                 url.set_fragment(None);
                 Ok(DirectRes::Tar { url, cksum })
             }
@@ -417,7 +449,17 @@ impl FromStr for DirectRes {
 impl fmt::Display for DirectRes {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            DirectRes::Git { repo, tag } => write!(f, "git+{}#{}", repo, tag),
+            DirectRes::Git {
+                repo,
+                tag,
+                submodules,
+            } => {
+                let mut repo = repo.clone();
+                if !submodules {
+                    repo.query_pairs_mut().append_pair("submodules", "false");
+                }
+                write!(f, "git+{}#{}", repo, tag)
+            }
             DirectRes::Dir { path } => write!(f, "dir+{}", path.display()),
             DirectRes::Tar { url, cksum } => {
                 let url = url.as_str();
@@ -49,26 +49,31 @@
 //! If we want to cache builds, we can just have a separate subfolder for ibcs.
 
 use std::{
-    collections::VecDeque,
+    collections::HashMap,
+    fmt,
     fs::{self, File},
     io::{self, prelude::*, BufReader},
     path::{Path, PathBuf},
     sync::Arc,
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use console::style;
 use failure::{bail, format_err, ResultExt};
+use git2::{Oid, Repository};
 use indexmap::{IndexMap, IndexSet};
 use itertools::Itertools;
-use reqwest::blocking::Client;
+use petgraph::graph::NodeIndex;
+use scoped_threadpool::Pool;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use slog::{debug, o, Logger};
 use toml;
+use url::Url;
 use walkdir::WalkDir;
 
 use crate::{
-    build::{context::BuildContext, Targets},
+    build::{context::BuildContext, script::ScriptOutput, Targets},
     cli::build::find_manifest,
     package::{manifest::Manifest, PackageId, Spec},
     remote::{
@@ -76,10 +81,13 @@ use crate::{
         Index, Indices,
     },
     util::{
-        clear_dir, copy_dir,
+        config::{Http, Net},
+        copy_dir_iter,
         error::Result,
+        git,
         graph::Graph,
-        lock::DirLock,
+        http::{Client, Credentials},
+        lock::{is_lockfile, DirLock, LockKind},
         shell::{Shell, Verbosity},
         valid_file,
     },
@@ -95,25 +103,42 @@ use crate::{
 pub struct Cache {
     pub layout: Layout,
     client: Client,
+    pub net: Net,
     pub logger: Logger,
     pub shell: Shell,
 }
 
 impl Cache {
-    pub fn from_disk(plog: &Logger, layout: Layout, shell: Shell) -> Result<Self> {
+    pub fn from_disk(
+        plog: &Logger,
+        layout: Layout,
+        net: Net,
+        http: &Http,
+        shell: Shell,
+    ) -> Result<Self> {
         layout.init()?;
 
-        let client = Client::builder().timeout(Duration::from_secs(10)).build()?;
+        let client = Client::new(http, Credentials::load(&layout.logins))?;
         let logger = plog.new(o!("phase" => "cache"));
 
         Ok(Cache {
             layout,
             client,
+            net,
             logger,
             shell,
         })
     }
 
+    /// Loads the local package at `path`, using `manifest` instead of whatever's on disk.
+    pub fn local_source(&self, pkg: &PackageId, path: &Path, manifest: Manifest) -> Result<Source> {
+        let lock = self.lock(path, LockKind::Shared)?;
+        let location = DirectRes::Dir {
+            path: path.to_path_buf(),
+        };
+        Source::new(pkg, lock, location, manifest)
+    }
+
     /// Retrieve the metadata of a package, loading it into the cache if necessary.
     pub fn checkout_source(
         &self,
@@ -154,87 +179,354 @@ impl Cache {
     ) -> Result<(Option<DirectRes>, DirLock)> {
         if let DirectRes::Dir { path } = loc {
             debug!(self.logger, "loaded source"; "cause" => "dir", "pkg" => pkg.to_string());
-            return Ok((None, DirLock::acquire(&path)?));
+            return Ok((None, self.lock(&path, LockKind::Shared)?));
         }
 
         let eager = if offline { false } else { eager };
 
+        if loc.is_git() {
+            return self.load_git_source(pkg, loc, eager, offline, dl_f);
+        }
+
+        // At this point, we're only dealing with tarball resolutions. If we've already extracted
+        // the tarball, we can stop immediately.
         let new_dir = self.layout.src.join(Self::get_source_dir(loc, true));
-        // We record first if the directory existed before the retrieval process
-
-        // If it does exist, we can stop immediately
-        if loc.is_tar() && new_dir.exists() {
-            debug!(
-                self.logger, "loaded source";
-                "cause" => "exists",
-                "pkg" => pkg.to_string(),
-                "dir" => new_dir.display()
-            );
-            return Ok((None, DirLock::acquire(&new_dir)?));
+        let mut res = None;
+        let mut retrieved = false;
+        loop {
+            if let Some(lock) = self.lock_source(&new_dir)? {
+                debug!(
+                    self.logger, "loaded source";
+                    "cause" => if retrieved { "retrieved_new" } else { "exists" },
+                    "pkg" => pkg.to_string(),
+                    "loc" => loc.to_string(),
+                    "dir" => new_dir.display()
+                );
+                self.touch(EntryKind::Src, &new_dir);
+                return Ok((res, lock));
+            }
+
+            let dir = self.lock(&new_dir, LockKind::Exclusive)?;
+            // Someone else might have retrieved it while we were waiting for the lock
+            if self.is_stored(&new_dir) {
+                continue;
+            }
+            // Get rid of anything left over from an earlier retrieval that didn't finish
+            dir.clear()?;
+            res = loc.retrieve(&self.client, &dir, eager, |dl_online| {
+                if offline && dl_online {
+                    return Err(format_err!("Can't download package in offline mode"));
+                }
+                dl_f();
+                Ok(())
+            })?;
+            self.record_source(dir.path())?;
+            retrieved = true;
         }
+    }
+
+    /// Takes a shared lock on the cached source at `dir`, as long as it's all there. Locking a
+    /// directory creates it, so if it was removed just before we got to it, we end up with an
+    /// empty directory, which we mustn't mistake for the source.
+    fn lock_source(&self, dir: &Path) -> Result<Option<DirLock>> {
+        let lock = self.lock(dir, LockKind::Shared)?;
+        Ok(if self.is_stored(dir) {
+            Some(lock)
+        } else {
+            None
+        })
+    }
+
+    /// Whether the source at `dir` was stored completely: its hash gets recorded once it's all in
+    /// place. Sources from before we kept records have to make do with having a manifest.
+    fn is_stored(&self, dir: &Path) -> bool {
+        let name = dir.file_name().unwrap().to_string_lossy();
+        let recorded = self
+            .read_record(EntryKind::Src, &name)
+            .map(|x| x.hash.is_some())
+            .unwrap_or(false);
+        recorded || dir.join("elba.toml").exists()
+    }
+
+    /// Loads a git repository into the cache.
+    ///
+    /// Every repository gets a single bare repository (its "db") in the git directory of the
+    /// cache, keyed by its url, which all fetches go into. The sources themselves are plain
+    /// checkouts of a single commit from that db, keyed by the resolution pinned to that commit;
+    /// once a checkout exists, it never changes, so loading it again is free.
+    fn load_git_source(
+        &self,
+        pkg: &PackageId,
+        loc: &DirectRes,
+        eager: bool,
+        offline: bool,
+        dl_f: impl Fn(),
+    ) -> Result<(Option<DirectRes>, DirLock)> {
+        let (url, tag, submodules) = match loc {
+            DirectRes::Git {
+                repo,
+                tag,
+                submodules,
+            } => (repo, tag, *submodules),
+            _ => unreachable!(),
+        };
 
-        let new_f = |dl_online| {
-            if offline && dl_online {
-                return Err(format_err!("Can't download package in offline mode"));
+        let db_lock = self.lock(
+            &self.layout.git.join(Self::get_source_dir(loc, false)),
+            LockKind::Exclusive,
+        )?;
+        let mut db = git::open_db(db_lock.path())?;
+        self.migrate_git_source(loc, &mut db)?;
+
+        let mut fetched = false;
+        let mut fetch = |db: &mut Repository, rev: &str| -> Result<Oid> {
+            if offline {
+                bail!("Can't download package in offline mode")
             }
-            dl_f();
-            Ok(())
+            if !fetched {
+                dl_f();
+                fetched = true;
+            }
+            self.fetch_rev(db, url, rev)
         };
 
-        // At this point, we're only dealing with all git resolutions and tarball resolutions
-        // which don't exist yet.
-        // If we're in "offline" mode, we immediately return an error from here because we
-        // won't be able to download anything anyways.
-        let dir = DirLock::acquire(&self.layout.src.join(Self::get_source_dir(loc, true)))?;
-        let res = if let Resolution::Direct(g) = pkg.resolution() {
-            // For a git repository, if the DirectRes and the PackageId don't match, we should try to
-            // retrieve the locked variant (the DirectRes) and then update with the latest variant
-            // (the PackageId).
-            // The only difference between the two should be the `tag`.
-            // The worst case performance-wise for this operation is if a repository doesn't exist
-            // in the cache and the lockfile and manifest are out-of-sync, which will result in
-            // two fetch operations.
-            if g.is_git() && g != loc {
-                debug_assert!(loc.is_git());
-                loc.retrieve(&self.client, &dir, eager, new_f)
-                    .and_then(|_| {
-                        g.retrieve(&self.client, &dir, false, |dl_online| {
-                            if offline && dl_online {
-                                Err(format_err!("Can't download package in offline mode"))
-                            } else {
-                                Ok(())
-                            }
-                        })
-                    })
-            } else {
-                loc.retrieve(&self.client, &dir, eager, new_f)
+        // We only go to the network if we have to: if the db doesn't know about the revision we
+        // want, or if we're being eager and that revision is something that can move.
+        let mut commit = match git::resolve(&db, tag) {
+            Some(id) if !eager || git::is_commit_hash(tag) => id,
+            _ => fetch(&mut db, tag)?,
+        };
+
+        // If the manifest and the lockfile disagree on the revision to use (e.g. the manifest
+        // asks for a branch and the lockfile has the commit it was at), we stick with the locked
+        // commit as long as it's still part of the history of what the manifest wants.
+        if let Resolution::Direct(DirectRes::Git { tag: wanted, .. }) = pkg.resolution() {
+            if wanted != tag {
+                let contains = |db: &Repository, latest: Oid| {
+                    latest == commit || db.graph_descendant_of(latest, commit).unwrap_or(false)
+                };
+
+                let latest = git::resolve(&db, wanted).filter(|&x| contains(&db, x));
+                commit = match latest {
+                    Some(_) => commit,
+                    None => {
+                        let latest = fetch(&mut db, wanted)?;
+                        if contains(&db, latest) {
+                            commit
+                        } else {
+                            latest
+                        }
+                    }
+                };
             }
-        } else {
-            loc.retrieve(&self.client, &dir, eager, new_f)
-        }?;
-
-        let new_dir = self.layout.src.join(&Self::get_source_dir(
-            if let Some(r) = res.as_ref() { r } else { &loc },
-            true,
-        ));
-        let dir = if new_dir != dir.path() {
-            if !new_dir.exists() {
-                copy_dir(dir.path(), &new_dir, true)?;
+        }
+
+        let res = DirectRes::Git {
+            repo: url.clone(),
+            tag: commit.to_string(),
+            submodules,
+        };
+        let dir = self.layout.src.join(Self::get_source_dir(&res, true));
+        let source = loop {
+            if let Some(lock) = self.lock_source(&dir)? {
+                break lock;
             }
-            DirLock::acquire(&new_dir)?
-        } else {
-            dir
+
+            let lock = self.lock(&dir, LockKind::Exclusive)?;
+            if self.is_stored(&dir) {
+                continue;
+            }
+            // Get rid of anything left over from an earlier checkout that didn't finish
+            lock.clear()?;
+            self.checkout_git(&db, url, commit, lock.path(), submodules, offline, &dl_f)
+                .with_context(|e| {
+                    format_err!(
+                        "couldn't check out {} from git repo {}:\n{}",
+                        commit,
+                        url,
+                        e
+                    )
+                })?;
+            self.record_source(&dir)?;
         };
+        self.touch(EntryKind::Src, &dir);
+        self.touch(EntryKind::Git, db_lock.path());
+        drop(db_lock);
 
         debug!(
             self.logger, "loaded source";
-            "cause" => "retrieved_new",
+            "cause" => if fetched { "retrieved_new" } else { "exists" },
             "pkg" => pkg.to_string(),
             "loc" => loc.to_string(),
-            "dir" => dir.path().display()
+            "dir" => dir.display()
         );
 
-        Ok((res, dir))
+        let res = if &res == loc { None } else { Some(res) };
+        Ok((res, source))
+    }
+
+    /// Fetches `rev` from the repository at `url` into `db`, returning the commit it points to.
+    ///
+    /// Commits and tags are fetched on their own, and shallowly if we're using the git cli (the
+    /// libgit2 we use can neither do shallow fetches nor fetch a commit by itself). If that
+    /// doesn't work out, or `rev` is a branch, we fall back to fetching every branch; since that
+    /// can be a lot more than was asked for, we warn about it when `rev` is a commit.
+    fn fetch_rev(&self, db: &mut Repository, url: &Url, rev: &str) -> Result<Oid> {
+        // Each pin is the refspec to fetch, and what we expect to find once we've fetched it.
+        let pinned = if !git::is_commit_hash(rev) {
+            let tag = format!("refs/tags/{}", rev);
+            Some((format!("+{0}:{0}", tag), tag))
+        } else if self.net.git_fetch_with_cli {
+            Some((rev.to_owned(), rev.to_owned()))
+        } else {
+            None
+        };
+
+        if let Some((refspec, target)) = pinned {
+            if self.fetch_db(db, url, &[&refspec], true).is_ok() {
+                if let Some(id) = git::resolve(db, &target) {
+                    return Ok(id);
+                }
+            }
+            if git::is_commit_hash(rev) {
+                self.shell.println(
+                    style("[warn]").yellow().bold(),
+                    format!(
+                        "Couldn't fetch commit {} of {} by itself; fetching every branch instead",
+                        rev, url
+                    ),
+                    Verbosity::Normal,
+                );
+            }
+        } else {
+            self.shell.println(
+                style("[warn]").yellow().bold(),
+                format!(
+                    "Fetching every branch of {} to find commit {}, since shallow fetches need \
+                     `net.git_fetch_with_cli`",
+                    url, rev
+                ),
+                Verbosity::Normal,
+            );
+        }
+
+        self.fetch_db(db, url, &["+refs/heads/*:refs/heads/*"], false)
+            .with_context(|e| format_err!("couldn't fetch git repo {}:\n{}", url, e))?;
+        git::resolve(db, rev)
+            .ok_or_else(|| format_err!("couldn't find {} in git repo {}", rev, url))
+    }
+
+    fn fetch_db(
+        &self,
+        db: &mut Repository,
+        url: &Url,
+        refspecs: &[&str],
+        shallow: bool,
+    ) -> Result<()> {
+        if self.net.git_fetch_with_cli {
+            git::fetch_with_cli(db, url, refspecs, shallow, &self.client)
+        } else {
+            // libgit2 doesn't understand shallow repositories, so if the git cli made one of
+            // these before, we have to start over.
+            if db.is_shallow() {
+                git::reinitialize(db)?;
+            }
+            git::fetch(db, url, refspecs, &self.client)
+        }
+    }
+
+    /// Checks out `commit` of the repository at `url` into `into`, along with all of its
+    /// submodules (if `submodules` is true). Submodules get their own dbs, just like any other git
+    /// repository.
+    #[allow(clippy::too_many_arguments)]
+    fn checkout_git(
+        &self,
+        db: &Repository,
+        url: &Url,
+        commit: Oid,
+        into: &Path,
+        submodules: bool,
+        offline: bool,
+        dl_f: &impl Fn(),
+    ) -> Result<()> {
+        git::checkout(db, commit, into)?;
+        if !submodules {
+            return Ok(());
+        }
+
+        for (path, sub_url, sub_commit) in git::submodules(db, commit, into, url)? {
+            let sub_loc = DirectRes::Git {
+                repo: sub_url.clone(),
+                tag: sub_commit.to_string(),
+                submodules: true,
+            };
+            let sub_lock = self.lock(
+                &self.layout.git.join(Self::get_source_dir(&sub_loc, false)),
+                LockKind::Exclusive,
+            )?;
+            let mut sub_db = git::open_db(sub_lock.path())?;
+
+            if sub_db.find_commit(sub_commit).is_err() {
+                if offline {
+                    bail!("Can't download package in offline mode")
+                }
+                dl_f();
+                self.fetch_rev(&mut sub_db, &sub_url, &sub_commit.to_string())
+                    .with_context(|e| {
+                        format_err!(
+                            "failed to fetch submodule {} from {}:\n{}",
+                            path.display(),
+                            sub_url,
+                            e
+                        )
+                    })?;
+            }
+
+            self.checkout_git(
+                &sub_db,
+                &sub_url,
+                sub_commit,
+                &into.join(&path),
+                true,
+                offline,
+                dl_f,
+            )
+            .with_context(|e| {
+                format_err!("failed to update submodule {}:\n{}", path.display(), e)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Older versions of elba kept a full clone of a git repository for every revision that was
+    /// asked for, and copied it whenever that revision moved. If we've got one of those lying
+    /// around for this resolution, we use it to fill the db (so that we don't need to go to the
+    /// network) and get rid of it if it isn't pinned to a commit.
+    fn migrate_git_source(&self, loc: &DirectRes, db: &mut Repository) -> Result<()> {
+        let old = self.layout.src.join(Self::get_source_dir(loc, true));
+        if !old.join(".git").exists() {
+            return Ok(());
+        }
+
+        if let Ok(url) = Url::from_directory_path(&old) {
+            // The old clone is only an optimization; if we can't read from it, the network will
+            // do.
+            let _ = git::fetch(db, &url, &["+refs/heads/*:refs/heads/*"], &self.client);
+        }
+
+        let pinned = match loc {
+            DirectRes::Git { tag, .. } => git::resolve(db, tag).map(|x| &x.to_string() == tag),
+            _ => None,
+        };
+        if pinned == Some(true) {
+            // This is a valid checkout of a single commit, just with some extra baggage.
+            remove_dir_all::remove_dir_all(old.join(".git"))?;
+        } else {
+            remove_dir_all::remove_dir_all(&old)?;
+        }
+
+        Ok(())
     }
 
     /// Gets the corresponding directory of a package.
@@ -253,21 +545,52 @@ impl Cache {
     }
 
     /// Return the build directory exists, else None.
+    ///
+    /// If the build isn't in the cache but is in the secondary build cache, it's copied over.
     pub fn checkout_build(&self, hash: &BuildHash) -> Result<Option<Binary>> {
         if let Some(path) = self.check_build(&hash) {
-            Ok(Some(Binary::new(DirLock::acquire(&path)?)))
-        } else {
-            Ok(None)
+            self.touch(EntryKind::Build, &path);
+            return Ok(Some(Binary::new(self.lock(&path, LockKind::Shared)?)));
+        }
+
+        if let Some(secondary) = &self.layout.secondary_build {
+            let from = secondary.join(&hash.0);
+            if from.is_dir() {
+                let meta = BuildsManifest::from_dir(secondary)
+                    .ok()
+                    .and_then(|mut x| x.builds.remove(&hash.0))
+                    .unwrap_or_default();
+                debug!(
+                    self.logger, "copying build from secondary cache";
+                    "hash" => &hash.0,
+                    "from" => from.display()
+                );
+                return Ok(Some(self.store_build(&from, hash, &meta)?));
+            }
         }
+
+        Ok(None)
+    }
+
+    /// Adds a build from somewhere else (an export of another cache, usually) to the cache.
+    /// Returns `false` if the cache already had that build.
+    pub fn import_build(&self, from: &Path, hash: &BuildHash, meta: &BuildMeta) -> Result<bool> {
+        if self.check_build(hash).is_some() {
+            return Ok(false);
+        }
+
+        self.store_build(from, hash, meta)?;
+        Ok(true)
     }
 
     /// Returns a lock on a temporary build directory.
     /// Note that the format of this directory should be an OutputLayout.
     pub fn checkout_tmp(&self, hash: &BuildHash) -> Result<OutputLayout> {
         let path = self.layout.tmp.join(&hash.0);
-        let lock = DirLock::acquire(&path)?;
+        self.touch(EntryKind::Tmp, &path);
+        let lock = self.lock(&path, LockKind::Exclusive)?;
         if lock.path().exists() {
-            clear_dir(&lock.path()).context(format_err!(
+            lock.clear().context(format_err!(
                 "couldn't remove existing output path: {}",
                 lock.path().display()
             ))?;
@@ -275,6 +598,15 @@ impl Cache {
         OutputLayout::new(lock)
     }
 
+    /// Returns a lock on the temporary directory a build script is built and run in. Unlike with
+    /// `checkout_tmp`, whatever an earlier run left behind is kept, so that it can be reused.
+    pub fn checkout_script(&self, hash: &BuildHash) -> Result<OutputLayout> {
+        let path = self.layout.tmp.join(&hash.0);
+        self.touch(EntryKind::Tmp, &path);
+        let lock = self.lock(&path, LockKind::Exclusive)?;
+        OutputLayout::new(lock)
+    }
+
     pub fn store_bins(&self, bins: &[(PathBuf, String)], force: bool) -> Result<()> {
         // We use a file .bins in the bin directory to keep track of installed bins
         let mut dot_f = fs::OpenOptions::new()
@@ -419,19 +751,27 @@ impl Cache {
         Ok(c)
     }
 
-    pub fn store_build(&self, from: &Path, hash: &BuildHash) -> Result<Binary> {
+    pub fn store_build(&self, from: &Path, hash: &BuildHash, meta: &BuildMeta) -> Result<Binary> {
         let dest = self.layout.build.join(&hash.0);
 
         if !dest.exists() {
             fs::create_dir_all(&dest)?;
         }
 
-        let dest = DirLock::acquire(&dest)?;
+        let lock = self.lock(&dest, LockKind::Exclusive)?;
 
-        clear_dir(dest.path())?;
-        copy_dir(from, dest.path(), false)?;
+        lock.clear()?;
+        copy_dir_iter(build_files(from), from, lock.path())?;
+        let record = Record {
+            last_used: now(),
+            hash: None,
+            build: Some(meta.clone()),
+        };
+        self.write_record(EntryKind::Build, &hash.0, &record)?;
 
-        Ok(Binary::new(dest))
+        // Others can use the build as soon as it's stored
+        drop(lock);
+        Ok(Binary::new(self.lock(&dest, LockKind::Shared)?))
     }
 
     fn check_build(&self, hash: &BuildHash) -> Option<PathBuf> {
@@ -444,97 +784,229 @@ impl Cache {
         }
     }
 
+    /// Retrieves all of the given indices, along with every index they depend on.
+    ///
+    /// Indices are fetched in waves: everything we currently know about is retrieved in parallel
+    /// (up to `net.jobs` at once), and any newly-discovered dependent indices make up the next
+    /// wave. Indices which couldn't be loaded are skipped, and reported all at once at the end.
     pub fn get_indices(&self, index_reses: &[DirectRes], eager: bool, offline: bool) -> Indices {
         let mut indices = vec![];
-        let mut seen = vec![];
-        let mut q: VecDeque<DirectRes> = index_reses.iter().cloned().collect();
-
-        while let Some(index) = q.pop_front() {
-            if seen.contains(&index) {
-                continue;
-            }
-
-            // We special-case a local dir index because `dir` won't exist for it.
-            if let DirectRes::Dir { path } = &index {
-                let lock = match DirLock::acquire(path) {
-                    Ok(dir) => dir,
-                    Err(e) => {
-                        self.shell.println(
-                            style("[warn]").yellow().bold(),
-                            format!("Couldn't lock dir index {}: {}", path.display(), e),
-                            Verbosity::Quiet,
-                        );
-                        continue;
-                    }
-                };
+        let mut seen = IndexSet::new();
+        let mut failures = vec![];
+        let mut wave: Vec<DirectRes> = index_reses
+            .iter()
+            .filter(|&index| seen.insert(index.clone()))
+            .cloned()
+            .collect();
+        let mut pool = Pool::new(self.net.jobs.max(1));
+
+        while !wave.is_empty() {
+            let mut results: Vec<Option<Result<Index>>> = wave.iter().map(|_| None).collect();
+
+            pool.scoped(|scope| {
+                for (index, slot) in wave.iter().zip(results.iter_mut()) {
+                    scope.execute(move || {
+                        *slot = Some(self.get_index(index, eager, offline));
+                    });
+                }
+            });
 
-                let ix = Index::from_disk(index.clone(), lock);
-                if let Ok(ix) = ix {
-                    for dependent in ix.depends().cloned().map(|i| i.res) {
-                        q.push_back(dependent);
+            let mut next = vec![];
+            for (index, res) in wave.into_iter().zip(results) {
+                match res.unwrap() {
+                    Ok(ix) => {
+                        for dependent in ix.depends().cloned().map(|i| i.res) {
+                            if seen.insert(dependent.clone()) {
+                                next.push(dependent);
+                            }
+                        }
+                        indices.push(ix);
                     }
-                    seen.push(index);
-                    indices.push(ix);
+                    Err(e) => failures.push(format!("{}: {}", index, e)),
                 }
-                continue;
             }
+            wave = next;
+        }
 
-            let index_path = self.layout.indices.join(Self::get_index_dir(&index));
-            let dir = match DirLock::acquire(&index_path) {
-                Ok(dir) => dir,
-                Err(e) => {
-                    self.shell.println(
-                        style("[warn]").yellow().bold(),
-                        format!("Couldn't lock cached index {}: {}", index, e),
-                        Verbosity::Quiet,
-                    );
-                    continue;
-                }
-            };
+        if !failures.is_empty() {
+            self.shell.println(
+                style("[warn]").yellow().bold(),
+                format!(
+                    "Couldn't load {} {}:\n{}",
+                    failures.len(),
+                    if failures.len() == 1 {
+                        "index"
+                    } else {
+                        "indices"
+                    },
+                    failures.join("\n")
+                ),
+                Verbosity::Quiet,
+            );
+        }
 
-            let res = index.retrieve(&self.client, &dir, eager, |dl_online| {
+        Indices::new(indices)
+    }
+
+    /// Loads a single index, retrieving it first if it isn't a local directory.
+    fn get_index(&self, index: &DirectRes, eager: bool, offline: bool) -> Result<Index> {
+        // We special-case a local dir index because `dir` won't exist for it.
+        if let DirectRes::Dir { path } = index {
+            let lock = self
+                .lock(path, LockKind::Shared)
+                .with_context(|e| format_err!("couldn't lock dir index: {}", e))?;
+            return Ok(Index::from_disk(index.clone(), lock)
+                .with_context(|e| format_err!("invalid/corrupt index: {}", e))?);
+        }
+
+        let index_path = self.layout.indices.join(Self::get_index_dir(index));
+        let dir = self
+            .lock(&index_path, LockKind::Exclusive)
+            .with_context(|e| format_err!("couldn't lock cached index: {}", e))?;
+
+        index
+            .retrieve(&self.client, &dir, eager, |dl_online| {
                 if offline && dl_online {
                     return Err(format_err!("Offline mode; can't update indices"));
                 }
                 self.shell.println(
                     style("Retrieving").cyan(),
-                    format!("index {}", &index),
+                    format!("index {}", index),
                     Verbosity::Normal,
                 );
                 Ok(())
-            });
+            })
+            .with_context(|e| format_err!("couldn't retrieve index: {}", e))?;
+        self.touch(EntryKind::Indices, dir.path());
+
+        // We only read the index from here on out
+        drop(dir);
+        let dir = self
+            .lock(&index_path, LockKind::Shared)
+            .with_context(|e| format_err!("couldn't lock cached index: {}", e))?;
+
+        Ok(Index::from_disk(index.clone(), dir)
+            .with_context(|e| format_err!("invalid/corrupt index: {}", e))?)
+    }
 
-            match res {
-                Ok(_) => {
-                    let ix = Index::from_disk(index.clone(), dir);
-                    match ix {
-                        Ok(ix) => {
-                            for dependent in ix.depends().cloned().map(|i| i.res) {
-                                q.push_back(dependent);
-                            }
-                            seen.push(index);
-                            indices.push(ix);
-                        }
-                        Err(e) => {
-                            self.shell.println(
-                                style("[warn]").yellow().bold(),
-                                format!("Invalid/corrupt index {}: {}", index, e),
-                                Verbosity::Quiet,
-                            );
-                        }
-                    }
-                }
-                Err(e) => {
-                    self.shell.println(
-                        style("[warn]").yellow().bold(),
-                        format!("Couldn't retrieve cache {}: {}", index, e),
-                        Verbosity::Quiet,
-                    );
+    fn lock(&self, path: &Path, kind: LockKind) -> Result<DirLock> {
+        DirLock::acquire_with(path, kind, None, self.shell)
+    }
+
+    /// Lists everything in the cache, along with how big it is and when it was last used.
+    pub fn entries(&self) -> Result<Vec<CacheEntry>> {
+        let mut res = vec![];
+
+        for &kind in EntryKind::all().iter() {
+            let dir = kind.dir(&self.layout);
+            if !dir.exists() {
+                continue;
+            }
+
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_dir() {
+                    continue;
                 }
+
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let path = entry.path();
+                let record = self.read_record(kind, &name);
+                // Entries from before we kept records fall back to the modification time of
+                // the directory, which is the best guess we've got.
+                let last_used = match &record {
+                    Some(record) => UNIX_EPOCH + Duration::from_secs(record.last_used),
+                    None => entry.metadata()?.modified().unwrap_or(UNIX_EPOCH),
+                };
+
+                let (hash, build) = record.map(|x| (x.hash, x.build)).unwrap_or_default();
+                res.push(CacheEntry {
+                    kind,
+                    name,
+                    size: dir_size(&path),
+                    path,
+                    last_used,
+                    hash,
+                    build,
+                });
             }
         }
 
-        Indices::new(indices)
+        Ok(res)
+    }
+
+    /// Checks the contents of a source against the hash recorded when it was stored. Returns
+    /// `None` if there's nothing to check against (the entry isn't a source, or it was stored
+    /// before we kept records).
+    pub fn verify_entry(&self, entry: &CacheEntry) -> Result<Option<bool>> {
+        match (entry.kind, &entry.hash) {
+            (EntryKind::Src, Some(hash)) => {
+                let lock = self.lock(&entry.path, LockKind::Shared)?;
+                Ok(Some(&hash_dir(lock.path())? == hash))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Deletes an entry from the cache, along with its record. Entries which are currently in use
+    /// by someone else are left alone, in which case this returns `false`.
+    pub fn remove_entry(&self, entry: &CacheEntry) -> Result<bool> {
+        let lock = match DirLock::try_acquire(&entry.path, LockKind::Exclusive)? {
+            Some(lock) => lock,
+            None => return Ok(false),
+        };
+
+        // The record goes first, so that nobody takes what's left of the entry for the real thing
+        let record = self.record_path(entry.kind, &entry.name);
+        if record.exists() {
+            fs::remove_file(record)?;
+        }
+
+        remove_dir_all::remove_dir_all(lock.path())
+            .with_context(|e| format_err!("couldn't remove {}: {}", entry.path.display(), e))?;
+        drop(lock);
+
+        Ok(true)
+    }
+
+    fn record_path(&self, kind: EntryKind, name: &str) -> PathBuf {
+        self.layout.records.join(kind.to_string()).join(name)
+    }
+
+    fn read_record(&self, kind: EntryKind, name: &str) -> Option<Record> {
+        let contents = fs::read_to_string(self.record_path(kind, name)).ok()?;
+        toml::from_str(&contents).ok()
+    }
+
+    fn write_record(&self, kind: EntryKind, name: &str, record: &Record) -> Result<()> {
+        let path = self.record_path(kind, name);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, toml::to_string(record)?)?;
+        Ok(())
+    }
+
+    /// Notes down that the entry at `path` was just used.
+    ///
+    /// Records are only used to decide what to garbage collect, so failing to write one isn't
+    /// worth failing over.
+    fn touch(&self, kind: EntryKind, path: &Path) {
+        if let Some(name) = path.file_name().and_then(|x| x.to_str()) {
+            let mut record = self.read_record(kind, name).unwrap_or_default();
+            record.last_used = now();
+            let _ = self.write_record(kind, name, &record);
+        }
+    }
+
+    /// Records the hash of a source which was just put into the cache, so that it can be verified
+    /// later.
+    fn record_source(&self, path: &Path) -> Result<()> {
+        let name = path.file_name().unwrap().to_string_lossy();
+        let record = Record {
+            last_used: now(),
+            hash: Some(hash_dir(path)?),
+            build: None,
+        };
+        self.write_record(EntryKind::Src, &name, &record)
     }
 
     fn get_index_dir(loc: &DirectRes) -> String {
@@ -567,6 +1039,162 @@ impl Cache {
     }
 }
 
+/// The kinds of entries in the global cache, named after the directories they live in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum EntryKind {
+    Src,
+    Build,
+    Tmp,
+    Indices,
+    Git,
+}
+
+impl EntryKind {
+    pub fn all() -> [EntryKind; 5] {
+        [
+            EntryKind::Src,
+            EntryKind::Build,
+            EntryKind::Tmp,
+            EntryKind::Indices,
+            EntryKind::Git,
+        ]
+    }
+
+    fn dir(self, layout: &Layout) -> &Path {
+        match self {
+            EntryKind::Src => &layout.src,
+            EntryKind::Build => &layout.build,
+            EntryKind::Tmp => &layout.tmp,
+            EntryKind::Indices => &layout.indices,
+            EntryKind::Git => &layout.git,
+        }
+    }
+}
+
+impl fmt::Display for EntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EntryKind::Src => write!(f, "src"),
+            EntryKind::Build => write!(f, "build"),
+            EntryKind::Tmp => write!(f, "tmp"),
+            EntryKind::Indices => write!(f, "indices"),
+            EntryKind::Git => write!(f, "git"),
+        }
+    }
+}
+
+/// A single entry of the global cache: a source, a build, an index, etc.
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub kind: EntryKind,
+    /// The name of the entry's directory
+    pub name: String,
+    pub path: PathBuf,
+    /// The total size of the entry in bytes
+    pub size: u64,
+    pub last_used: SystemTime,
+    /// For sources, the hash of their contents when they were stored
+    pub hash: Option<String>,
+    /// For builds, what they were built with
+    pub build: Option<BuildMeta>,
+}
+
+/// What the Cache remembers about one of its entries.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Record {
+    /// When the entry was last used, in seconds since the Unix epoch
+    last_used: u64,
+    hash: Option<String>,
+    build: Option<BuildMeta>,
+}
+
+/// The environment a build was made in. This is already part of its `BuildHash`, but we keep it
+/// around in readable form so that builds can be shared between machines.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BuildMeta {
+    /// The output of `<compiler> --version`
+    #[serde(default)]
+    pub compiler: String,
+    /// The codegen backend the build was made with, if it went through codegen
+    pub backend: Option<String>,
+}
+
+/// The name of the manifest in a directory of exported builds.
+pub const BUILDS_MANIFEST: &str = "elba-builds.toml";
+
+/// Describes a directory of exported builds: every build lives in a folder named after its
+/// `BuildHash`, and this manifest (stored as `elba-builds.toml`) says what each was built with.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct BuildsManifest {
+    #[serde(default)]
+    pub builds: IndexMap<String, BuildMeta>,
+}
+
+impl BuildsManifest {
+    pub fn from_dir(dir: &Path) -> Result<Self> {
+        let path = dir.join(BUILDS_MANIFEST);
+        let contents = fs::read_to_string(&path)
+            .with_context(|e| format_err!("couldn't read {}: {}", path.display(), e))?;
+        Ok(toml::from_str(&contents)
+            .with_context(|e| format_err!("invalid builds manifest {}: {}", path.display(), e))?)
+    }
+
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        fs::write(dir.join(BUILDS_MANIFEST), toml::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+/// Lists the files making up a build, leaving out lockfiles.
+pub fn build_files(path: &Path) -> impl Iterator<Item = walkdir::DirEntry> {
+    WalkDir::new(path)
+        .follow_links(true)
+        .into_iter()
+        .filter_entry(|x| !is_lockfile(x.file_name()))
+        .filter_map(|x| x.ok())
+        .filter(|x| x.file_type().is_file())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
+
+fn dir_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|x| x.ok())
+        .filter_map(|x| x.metadata().ok())
+        .filter(|x| x.is_file())
+        .map(|x| x.len())
+        .sum()
+}
+
+/// Hashes the paths and contents of every file in a directory. Lock files and git metadata are
+/// ignored.
+fn hash_dir(path: &Path) -> Result<String> {
+    let walker = WalkDir::new(path)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_entry(|x| x.file_name() != ".git" && !is_lockfile(x.file_name()));
+
+    let mut hash = Sha256::new();
+    for entry in walker {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let rel = entry.path().strip_prefix(path).unwrap();
+        hash.input(rel.to_string_lossy().as_bytes());
+        hash.input(&[0]);
+        io::copy(&mut File::open(entry.path())?, &mut hash)?;
+    }
+
+    Ok(hex::encode(hash.result()))
+}
+
 /// Layouts encapsulate the logic behind our directory structure.
 #[derive(Debug, Clone)]
 pub struct Layout {
@@ -580,6 +1208,14 @@ pub struct Layout {
     pub tmp: PathBuf,
     /// Directory of all the indices
     pub indices: PathBuf,
+    /// Bare git repositories which git sources are checked out from
+    pub git: PathBuf,
+    /// Records of when every cache entry was last used
+    pub records: PathBuf,
+    /// A read-only directory of exported builds to check before building anything
+    pub secondary_build: Option<PathBuf>,
+    /// File holding the auth tokens the user has logged in with
+    pub logins: PathBuf,
 }
 
 impl Layout {
@@ -589,6 +1225,8 @@ impl Layout {
         fs::create_dir_all(&self.src)?;
         fs::create_dir_all(&self.build)?;
         fs::create_dir_all(&self.indices)?;
+        fs::create_dir_all(&self.git)?;
+        fs::create_dir_all(&self.records)?;
         fs::create_dir_all(&self.tmp)?;
 
         Ok(())
@@ -706,7 +1344,7 @@ impl Source {
             file.read_to_string(&mut contents)?;
             if let Some(x) = Manifest::workspace(&contents) {
                 if let Some(p) = x.get(pkg.name()) {
-                    let lock = DirLock::acquire(&path.path().join(&p.0))?;
+                    let lock = DirLock::acquire_shared(&path.path().join(&p.0))?;
                     // We immediately release our lock on the parent folder
                     drop(path);
                     return Source::from_folder(pkg, lock, location);
@@ -715,7 +1353,16 @@ impl Source {
         }
 
         let (_, manifest) = find_manifest(path.path(), true, None)?;
+        Source::new(pkg, path, location, manifest)
+    }
 
+    /// Creates a Source from a folder on disk whose manifest has already been loaded.
+    pub fn new(
+        pkg: &PackageId,
+        path: DirLock,
+        location: DirectRes,
+        manifest: Manifest,
+    ) -> Result<Self> {
         if manifest.name() != pkg.name() {
             bail!(
                 "names don't match: {} was declared, but {} was found in elba.toml",
@@ -729,7 +1376,10 @@ impl Source {
             .list_files(path.path(), path.path(), |entry| {
                 entry.file_name() != ".git" && entry.file_name() != "target"
             })?
-            .filter(valid_file);
+            .filter(valid_file)
+            // The lockfiles list whoever's holding the lock, which has nothing to do with the
+            // contents of the package
+            .filter(|entry| !is_lockfile(entry.file_name()));
 
         let mut hash = Sha256::new();
         for f in walker {
@@ -820,10 +1470,15 @@ impl BuildHash {
         targets: &Targets,
         ctx: &BuildContext,
         codegen: bool,
+        scripts: &HashMap<NodeIndex, ScriptOutput>,
     ) -> Self {
         let mut hasher = Sha256::default();
-        for (_, src) in sources.sub_tree(sources.find_id(root).unwrap()) {
+        for (ix, src) in sources.sub_tree(sources.find_id(root).unwrap()) {
             hasher.input(&src.hash().as_bytes());
+            // What a build script generates doesn't have to be the same every time
+            if let Some(script) = scripts.get(&ix) {
+                hasher.input(script.hash.as_bytes());
+            }
         }
 
         // Take into account the build context
@@ -833,6 +1488,7 @@ impl BuildHash {
         for opt in &ctx.opts {
             hasher.input(opt.as_bytes());
         }
+        hasher.input(ctx.profile.as_bytes());
         if codegen {
             hasher.input(ctx.backend.name.as_bytes());
             for opt in &ctx.backend.opts {
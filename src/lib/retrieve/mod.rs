@@ -6,12 +6,15 @@
 
 pub mod cache;
 
-use std::borrow::Cow;
+use std::{borrow::Cow, collections::HashMap};
 
 use console::style;
+use crossbeam::queue::MsQueue;
 use failure::{format_err, ResultExt};
 use indexmap::{indexmap, IndexMap, IndexSet};
 use itertools::Either::{self, Left, Right};
+use petgraph::graph::NodeIndex;
+use scoped_threadpool::Pool;
 use semver::Version;
 use semver_constraints::{Constraint, Interval, Range, Relation};
 use slog::{debug, info, o, trace, Logger};
@@ -58,12 +61,20 @@ pub struct Retriever<'cache> {
 }
 
 impl<'cache> Retriever<'cache> {
+    /// Creates a Retriever which resolves the dependencies of `root`.
+    ///
+    /// The indices are either ones which were just loaded from disk, along with the resolutions
+    /// they get updated from once a package can't be found in them, or ones which are already up
+    /// to date. If the root package is on disk already, `root_source` is that package, which
+    /// the Retriever uses as it is instead of loading it all over again.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         plog: &Logger,
         cache: &'cache Cache,
         root: Summary,
         root_deps: Vec<(PackageId, Constraint)>,
-        reses: Either<Vec<DirectRes>, Indices>,
+        root_source: Option<Source>,
+        reses: Either<(Vec<DirectRes>, Indices), Indices>,
         lockfile: Graph<Summary>,
         ixmap: &'cache IndexMap<String, IndexRes>,
         shell: Shell,
@@ -83,10 +94,15 @@ impl<'cache> Retriever<'cache> {
         };
 
         let (indices, indices_set, reses) = match reses {
-            Left(v) => (cache.get_indices(&v, false, offline), false, v),
+            Left((v, e)) => (e, false, v),
             Right(e) => (e, true, vec![]),
         };
 
+        let mut sources = indexmap!();
+        if let Some(source) = root_source {
+            sources.insert(root.id.clone(), source);
+        }
+
         Retriever {
             cache,
             root,
@@ -99,7 +115,7 @@ impl<'cache> Retriever<'cache> {
             ixmap,
             shell,
             offline_cache,
-            sources: indexmap!(),
+            sources,
             res_mapping: indexmap!(),
         }
     }
@@ -107,44 +123,70 @@ impl<'cache> Retriever<'cache> {
     /// Loads all of the packages selected in a Solve into the Cache, returning a new graph of all
     /// the Sources.
     ///
-    /// This downloads all the packages into the cache. If we wanted to parallelize downloads
-    /// later, this is where we'd deal with all the Tokio stuff.
+    /// This downloads all the packages into the cache, using up to `net.jobs` threads at once.
+    /// Packages which would end up in the same cache directory (i.e. different tags of the same
+    /// git repo) are always retrieved one after another by the same thread, so that we never
+    /// contend with ourselves for a `DirLock`. The resulting graph has the same shape and node
+    /// order as the solve, no matter which download finishes first.
     pub fn retrieve_packages(&mut self, solve: &Graph<Summary>) -> Result<Graph<Source>> {
-        // let mut prg = 0;
-        // Until pb.println gets added, we can't use progress bars
-        // let pb = ProgressBar::new(solve.inner.raw_nodes().len() as u64);
-        // pb.set_style(ProgressStyle::default_bar().template("  [-->] {bar} {pos}/{len}"));
+        info!(self.logger, "beginning bulk package retrieval"; "jobs" => self.cache.net.jobs);
 
-        info!(self.logger, "beginning bulk package retrieval");
+        let mut retrieved: HashMap<NodeIndex, Result<Source>> = HashMap::new();
+        let mut groups: IndexMap<String, Vec<(NodeIndex, &Summary, DirectRes)>> = indexmap!();
 
-        let sources = solve.map(|_, sum| {
+        // Selecting index entries needs mutable access to the Retriever, so we figure out where
+        // every package lives before handing anything off to other threads.
+        for ix in solve.inner.node_indices() {
+            let sum = &solve[ix];
             let loc = match sum.resolution() {
                 Resolution::Direct(direct) => direct.clone(),
-                Resolution::Index(_) => self.select(sum).unwrap().into_owned().location,
+                Resolution::Index(_) => self.select(sum)?.into_owned().location,
             };
 
             if let Some(s) = self.remove(sum.id()) {
-                // prg += 1;
-                // pb.set_position(prg);
-                Ok(s)
+                retrieved.insert(ix, Ok(s));
             } else {
-                let source = self
-                    .cache
-                    .checkout_source(sum.id(), &loc, false, self.offline_cache.is_some(), || {
-                        self.shell.println(
-                            style("Retrieving").cyan(),
-                            sum.to_string(),
-                            Verbosity::Normal,
-                        );
-                    })
-                    .context(format_err!("unable to retrieve package {}", sum))?;
-                // prg += 1;
-                // pb.set_position(prg);
-                Ok(source.1)
+                groups
+                    .entry(Cache::get_source_dir(&loc, false))
+                    .or_insert_with(Vec::new)
+                    .push((ix, sum, loc));
+            }
+        }
+
+        let results = &MsQueue::new();
+        let cache = self.cache;
+        let shell = self.shell;
+        let offline = self.offline_cache.is_some();
+        let mut pool = Pool::new(cache.net.jobs.max(1));
+
+        pool.scoped(|scope| {
+            for group in groups.values() {
+                scope.execute(move || {
+                    for (ix, sum, loc) in group {
+                        let source = cache
+                            .checkout_source(sum.id(), loc, false, offline, || {
+                                shell.println(
+                                    style("Retrieving").cyan(),
+                                    sum.to_string(),
+                                    Verbosity::Normal,
+                                );
+                            })
+                            .map(|(_, source)| source)
+                            .context(format_err!("unable to retrieve package {}", sum))
+                            .map_err(failure::Error::from);
+                        results.push((*ix, source));
+                    }
+                });
             }
-        })?;
+        });
+
+        while let Some((ix, source)) = results.try_pop() {
+            retrieved.insert(ix, source);
+        }
+
+        // Errors are reported in node order, so that the same failure always shows up first.
+        let sources = solve.map(|ix, _| retrieved.remove(&ix).unwrap())?;
 
-        // pb.finish_and_clear();
         self.shell.println(
             style("Cached").dim(),
             format!("packages in {}", self.cache.layout.src.display()),
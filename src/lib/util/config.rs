@@ -35,6 +35,12 @@ pub struct Config {
     pub indices: IndexMap<String, IndexRes>,
     #[serde(default)]
     pub backend: Vec<Backend>,
+    #[serde(default)]
+    pub driver: Driver,
+    #[serde(default)]
+    pub net: Net,
+    #[serde(default)]
+    pub http: Http,
 }
 
 fn default_compiler() -> String {
@@ -112,8 +118,12 @@ impl Config {
             bin: self.directories.bin.to_path_buf(),
             build: self.directories.cache.join("build"),
             indices: self.directories.cache.join("indices"),
+            git: self.directories.cache.join("git"),
+            records: self.directories.cache.join("records"),
+            secondary_build: self.directories.secondary_build.clone(),
             src: self.directories.cache.join("src"),
             tmp: self.directories.cache.join("tmp"),
+            logins: self.directories.data.join("logins.toml"),
         }
     }
 }
@@ -128,6 +138,9 @@ impl Default for Config {
             directories: Directories::default(),
             indices: IndexMap::default(),
             backend: Vec::default(),
+            driver: Driver::default(),
+            net: Net::default(),
+            http: Http::default(),
         }
     }
 }
@@ -144,7 +157,12 @@ fn default_aliases() -> IndexMap<String, String> {
 fn default_indices() -> IndexMap<String, IndexRes> {
     let repo = Url::parse("https://github.com/elba/index").unwrap();
     let tag = "master".to_string();
-    indexmap!("official".to_string() => DirectRes::Git { repo, tag }.into())
+    indexmap!("official".to_string() => DirectRes::Git {
+        repo,
+        tag,
+        submodules: true,
+    }
+    .into())
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -182,6 +200,9 @@ pub struct Directories {
     pub data: PathBuf,
     #[serde(default = "default_cache_dir")]
     pub cache: PathBuf,
+    /// A read-only directory of exported builds to check before building anything
+    #[serde(default)]
+    pub secondary_build: Option<PathBuf>,
 }
 
 fn default_bin_dir() -> PathBuf {
@@ -208,6 +229,7 @@ impl Default for Directories {
             bin: default_bin_dir(),
             data: default_data_dir(),
             cache: default_cache_dir(),
+            secondary_build: None,
         }
     }
 }
@@ -236,3 +258,85 @@ impl Default for Backend {
         }
     }
 }
+
+/// How libraries get built.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Driver {
+    /// elba checks each module itself, skipping the ones which haven't changed
+    Modules,
+    /// elba generates an ipkg for the library and has the compiler build it with `--build`,
+    /// leaving incremental compilation up to the compiler
+    Ipkg,
+}
+
+impl Default for Driver {
+    fn default() -> Self {
+        Driver::Modules
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Net {
+    /// The maximum number of packages to retrieve at once
+    #[serde(default = "default_net_jobs")]
+    pub jobs: u32,
+    /// Whether to fetch git repositories with the system's git executable instead of libgit2
+    #[serde(default, alias = "git-fetch-with-cli")]
+    pub git_fetch_with_cli: bool,
+}
+
+fn default_net_jobs() -> u32 {
+    4
+}
+
+impl Default for Net {
+    fn default() -> Self {
+        Net {
+            jobs: default_net_jobs(),
+            git_fetch_with_cli: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Http {
+    /// The proxy to send all HTTP traffic through
+    pub proxy: Option<String>,
+    /// A PEM bundle of extra CA certificates to trust
+    pub cainfo: Option<PathBuf>,
+    /// The connection timeout in seconds, and how long a transfer can stall or stay under the
+    /// low-speed limit before it's aborted
+    #[serde(default = "default_http_timeout")]
+    pub timeout: u64,
+    /// The slowest transfer speed (in bytes/sec) that we'll put up with
+    #[serde(default = "default_http_low_speed_limit")]
+    pub low_speed_limit: u32,
+    /// How many times to retry a failed download
+    #[serde(default = "default_http_retry")]
+    pub retry: u32,
+}
+
+fn default_http_timeout() -> u64 {
+    30
+}
+
+fn default_http_low_speed_limit() -> u32 {
+    10
+}
+
+fn default_http_retry() -> u32 {
+    2
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Http {
+            proxy: None,
+            cainfo: None,
+            timeout: default_http_timeout(),
+            low_speed_limit: default_http_low_speed_limit(),
+            retry: default_http_retry(),
+        }
+    }
+}
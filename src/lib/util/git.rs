@@ -28,10 +28,24 @@
 // IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
-use crate::util::error::Result;
-use failure::{format_err, ResultExt};
+use crate::util::{
+    error::Result,
+    http::{Auth, Client},
+    lock::is_lockfile,
+};
+use failure::{bail, format_err, ResultExt};
 use git2;
-use std::{env, fs, path::Path};
+use std::{
+    cell::Cell,
+    env,
+    ffi::CString,
+    fs,
+    os::raw::{c_char, c_int},
+    path::{Path, PathBuf},
+    process::Command,
+    ptr,
+    sync::Mutex,
+};
 use url::Url;
 
 pub fn init(path: &Path) -> Result<()> {
@@ -39,20 +53,110 @@ pub fn init(path: &Path) -> Result<()> {
     Ok(())
 }
 
-pub fn clone(url: &Url, into: &Path) -> Result<git2::Repository> {
+/// Opens the bare repository at `path`, creating it if there isn't one there yet.
+pub fn open_db(path: &Path) -> Result<git2::Repository> {
+    match git2::Repository::open_bare(path) {
+        Ok(repo) => Ok(repo),
+        Err(_) => Ok(git2::Repository::init_bare(path)?),
+    }
+}
+
+/// Whether `rev` is a full commit hash, as opposed to a branch or tag.
+pub fn is_commit_hash(rev: &str) -> bool {
+    rev.len() == 40 && rev.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Finds the commit that `rev` (a branch, tag or commit hash) points to in `repo`, if it's there.
+pub fn resolve(repo: &git2::Repository, rev: &str) -> Option<git2::Oid> {
+    repo.revparse_single(rev)
+        .and_then(|x| x.peel_to_commit())
+        .map(|x| x.id())
+        .ok()
+}
+
+/// Writes the tree of `commit` from `db` into the directory `into`, without making a repository
+/// out of that directory. This works for bare repositories too.
+///
+/// Submodules are left as empty directories; see `submodules` for finding out what should go in
+/// them.
+pub fn checkout(db: &git2::Repository, commit: git2::Oid, into: &Path) -> Result<()> {
+    let commit = db.find_commit(commit)?;
+    let mut opts = git2::build::CheckoutBuilder::new();
+    opts.target_dir(into).update_index(false).force();
+    db.checkout_tree(commit.as_object(), Some(&mut opts))?;
+    Ok(())
+}
+
+/// Lists the submodules of `commit`, as checked out into `workdir`, along with the url they should
+/// be retrieved from and the commit they should be checked out to.
+///
+/// Relative submodule urls are resolved against `url`, the url of the parent repository.
+pub fn submodules(
+    db: &git2::Repository,
+    commit: git2::Oid,
+    workdir: &Path,
+    url: &Url,
+) -> Result<Vec<(PathBuf, Url, git2::Oid)>> {
+    let gitmodules = workdir.join(".gitmodules");
+    if !gitmodules.exists() {
+        return Ok(vec![]);
+    }
+
+    let tree = db.find_commit(commit)?.tree()?;
+    let cfg = git2::Config::open(&gitmodules)?;
+    let mut res = vec![];
+
+    let entries = cfg.entries(Some(r"submodule\..*\.path"))?;
+    for entry in &entries {
+        let entry = entry?;
+        let (name, path) = match (entry.name(), entry.value()) {
+            (Some(name), Some(path)) => (name, path),
+            _ => bail!("non-utf8 submodule in .gitmodules"),
+        };
+        let name = &name["submodule.".len()..name.len() - ".path".len()];
+
+        let sub_url = cfg
+            .get_string(&format!("submodule.{}.url", name))
+            .with_context(|_| format_err!("no url for submodule `{}`", name))?;
+        let sub_url = if sub_url.starts_with("./") || sub_url.starts_with("../") {
+            // Git treats relative urls as being relative to the parent's url as a directory
+            let mut base = url.clone();
+            base.set_path(&format!("{}/", url.path().trim_end_matches('/')));
+            base.join(&sub_url)?
+        } else {
+            Url::parse(&sub_url)
+                .with_context(|_| format_err!("invalid url for submodule `{}`", name))?
+        };
+
+        // A submodule which is listed in .gitmodules but isn't actually in the tree gets ignored,
+        // just like git would.
+        let oid = match tree.get_path(Path::new(path)) {
+            Ok(entry) if entry.kind() == Some(git2::ObjectType::Commit) => entry.id(),
+            _ => continue,
+        };
+
+        res.push((PathBuf::from(path), sub_url, oid));
+    }
+
+    Ok(res)
+}
+
+pub fn clone(url: &Url, into: &Path, http: &Client) -> Result<git2::Repository> {
     let git_config = git2::Config::open_default()?;
-    with_fetch_options(&git_config, &url, &mut |opts| {
-        let repo = git2::build::RepoBuilder::new()
-            .fetch_options(opts)
-            .clone(url.as_str(), into)?;
+    http.retry(|| {
+        with_fetch_options(&git_config, &url, http, &mut |opts| {
+            let repo = git2::build::RepoBuilder::new()
+                .fetch_options(opts)
+                .clone(url.as_str(), into)?;
 
-        Ok(repo)
+            Ok(repo)
+        })
     })
 }
 
-pub fn update_submodules(repo: &git2::Repository) -> Result<()> {
+pub fn update_submodules(repo: &git2::Repository, http: &Client) -> Result<()> {
     for mut child in repo.submodules()? {
-        update_submodule(repo, &mut child).with_context(|_| {
+        update_submodule(repo, &mut child, http).with_context(|_| {
             format!(
                 "failed to update submodule `{}`",
                 child.name().unwrap_or("")
@@ -62,7 +166,11 @@ pub fn update_submodules(repo: &git2::Repository) -> Result<()> {
     Ok(())
 }
 
-fn update_submodule(parent: &git2::Repository, child: &mut git2::Submodule) -> Result<()> {
+fn update_submodule(
+    parent: &git2::Repository,
+    child: &mut git2::Submodule,
+    http: &Client,
+) -> Result<()> {
     child.init(false)?;
     let url = child
         .url()
@@ -86,7 +194,7 @@ fn update_submodule(parent: &git2::Repository, child: &mut git2::Submodule) -> R
     let mut repo = match head_and_repo {
         Ok((head, repo)) => {
             if child.head_id() == head {
-                return update_submodules(&repo);
+                return update_submodules(&repo, http);
             }
             repo
         }
@@ -100,7 +208,7 @@ fn update_submodule(parent: &git2::Repository, child: &mut git2::Submodule) -> R
     // Fetch data from origin and reset to the head commit
     let refspec = "refs/heads/*:refs/heads/*";
     let url = Url::parse(url)?;
-    fetch(&mut repo, &url, refspec).with_context(|_| {
+    fetch(&mut repo, &url, &[refspec], http).with_context(|_| {
         format_err!(
             "failed to fetch submodule `{}` from {}",
             child.name().unwrap_or(""),
@@ -110,10 +218,15 @@ fn update_submodule(parent: &git2::Repository, child: &mut git2::Submodule) -> R
 
     let obj = repo.find_object(head, None)?;
     reset(&repo, &obj)?;
-    update_submodules(&repo)
+    update_submodules(&repo, http)
 }
 
-pub fn fetch(repo: &mut git2::Repository, url: &Url, refspec: &str) -> Result<()> {
+pub fn fetch(
+    repo: &mut git2::Repository,
+    url: &Url,
+    refspecs: &[&str],
+    http: &Client,
+) -> Result<()> {
     // The `fetch` operation here may fail spuriously due to a corrupt
     // repository. It could also fail, however, for a whole slew of other
     // reasons (aka network related reasons). We want Cargo to automatically
@@ -124,31 +237,107 @@ pub fn fetch(repo: &mut git2::Repository, url: &Url, refspec: &str) -> Result<()
     // looks like a "corrupt repo" error then we blow away the repo and try
     // again. If it looks like any other kind of error, or if we've already
     // blown away the repository, then we want to return the error as-is.
+    //
+    // Network errors, on the other hand, get retried the same way failed downloads do.
     let mut repo_reinitialized = false;
     let git_config = git2::Config::open_default()?;
-    with_fetch_options(&git_config, url, &mut |mut opts| {
-        loop {
-            let res = repo
-                .remote_anonymous(url.as_str())?
-                .fetch(&[refspec], Some(&mut opts), None);
-            let err = match res {
-                Ok(()) => break,
-                Err(e) => e,
-            };
-
-            if !repo_reinitialized && err.class() == git2::ErrorClass::Reference {
-                repo_reinitialized = true;
-                // This is a corrupt repo; reinit and try again
-                if reinitialize(repo).is_ok() {
-                    continue;
+    http.retry(|| {
+        with_fetch_options(&git_config, url, http, &mut |mut opts| {
+            loop {
+                let res =
+                    repo.remote_anonymous(url.as_str())?
+                        .fetch(refspecs, Some(&mut opts), None);
+                let err = match res {
+                    Ok(()) => break,
+                    Err(e) => e,
+                };
+
+                if !repo_reinitialized && err.class() == git2::ErrorClass::Reference {
+                    repo_reinitialized = true;
+                    // This is a corrupt repo; reinit and try again
+                    if reinitialize(repo).is_ok() {
+                        continue;
+                    }
                 }
+
+                return Err(err.into());
             }
 
-            return Err(err.into());
+            Ok(())
+        })
+    })
+}
+
+/// Fetches `refspecs` from `url` into `repo` using the git executable on the system rather than
+/// libgit2, for environments where only git itself knows how to talk to the remote (custom
+/// credential setups, odd proxies, etc.).
+///
+/// Unlike libgit2, git can do shallow fetches: if `shallow` is true, only the commits that
+/// `refspecs` point to are fetched. Otherwise, every tag is fetched along with `refspecs`, and a
+/// shallow repository is made whole again.
+pub fn fetch_with_cli(
+    repo: &git2::Repository,
+    url: &Url,
+    refspecs: &[&str],
+    shallow: bool,
+    http: &Client,
+) -> Result<()> {
+    let mut cmd = Command::new("git");
+    cmd.arg("fetch").arg("--force").arg("--update-head-ok");
+    if shallow {
+        cmd.arg("--depth=1");
+    } else {
+        cmd.arg("--tags");
+        if repo.is_shallow() {
+            cmd.arg("--unshallow");
         }
+    }
+    cmd.arg(url.as_str())
+        .args(refspecs)
+        .env("GIT_DIR", repo.path())
+        .env("GIT_TERMINAL_PROMPT", "0");
 
-        Ok(())
-    })
+    // Extra configuration goes through the environment rather than the command line, so that
+    // tokens don't show up in the process list. Basic auth is left to git, which reads the netrc
+    // file on its own. git has no connection timeout of its own, but it does have a low-speed
+    // limit, which also catches stalled transfers.
+    let settings = http.config();
+    let mut config = vec![
+        (
+            "http.lowSpeedLimit",
+            settings.low_speed_limit.max(1).to_string(),
+        ),
+        ("http.lowSpeedTime", settings.timeout.to_string()),
+    ];
+    if let Some(proxy) = http.proxy() {
+        config.push(("http.proxy", proxy.to_owned()));
+    }
+    if let Some(cainfo) = &settings.cainfo {
+        config.push(("http.sslCAInfo", cainfo.display().to_string()));
+    }
+    if let Some(Auth::Bearer(token)) = http.credentials().get(url)? {
+        config.push((
+            "http.extraHeader",
+            format!("Authorization: Bearer {}", token),
+        ));
+    }
+    cmd.env("GIT_CONFIG_COUNT", config.len().to_string());
+    for (ix, (key, value)) in config.into_iter().enumerate() {
+        cmd.env(format!("GIT_CONFIG_KEY_{}", ix), key)
+            .env(format!("GIT_CONFIG_VALUE_{}", ix), value);
+    }
+
+    let output = cmd
+        .output()
+        .with_context(|e| format_err!("couldn't run git (is it installed?): {}", e))?;
+    if !output.status.success() {
+        bail!(
+            "git fetch failed:\n{}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+    }
+
+    Ok(())
 }
 
 pub fn reset(repo: &git2::Repository, obj: &git2::Object) -> Result<()> {
@@ -157,7 +346,7 @@ pub fn reset(repo: &git2::Repository, obj: &git2::Object) -> Result<()> {
     Ok(())
 }
 
-fn reinitialize(repo: &mut git2::Repository) -> Result<()> {
+pub fn reinitialize(repo: &mut git2::Repository) -> Result<()> {
     // Here we want to drop the current repository object pointed to by `repo`,
     // so we initialize temporary repository in a sub-folder, blow away the
     // existing git folder, and then recreate the git repo. Finally we blow away
@@ -168,7 +357,7 @@ fn reinitialize(repo: &mut git2::Repository) -> Result<()> {
     *repo = git2::Repository::init(&tmp)?;
     for entry in path.read_dir()? {
         let entry = entry?;
-        if entry.file_name().to_str() == Some("tmp") {
+        if entry.file_name().to_str() == Some("tmp") || is_lockfile(&entry.file_name()) {
             continue;
         }
         let path = entry.path();
@@ -183,7 +372,12 @@ fn reinitialize(repo: &mut git2::Repository) -> Result<()> {
     Ok(())
 }
 
-fn with_authentication<T, F>(url: &str, cfg: &git2::Config, mut f: F) -> Result<T>
+fn with_authentication<T, F>(
+    url: &str,
+    cfg: &git2::Config,
+    userpass: Option<(String, String)>,
+    mut f: F,
+) -> Result<T>
 where
     F: FnMut(&mut git2::Credentials) -> Result<T>,
 {
@@ -195,6 +389,7 @@ where
     let mut ssh_agent_attempts = Vec::new();
     let mut any_attempts = false;
     let mut tried_sshkey = false;
+    let mut tried_userpass = false;
 
     let mut res = f(&mut |url, username, allowed| {
         any_attempts = true;
@@ -244,10 +439,14 @@ where
 
         // Sometimes libgit2 will ask for a username/password in plaintext. This
         // is where Cargo would have an interactive prompt if we supported it,
-        // but we currently don't! Right now the only way we support fetching a
-        // plaintext password is through the `credential.helper` support, so
-        // fetch that here.
+        // but we currently don't! Instead, we first try the credentials elba
+        // has for this host (a token or a netrc entry), and then fall back to
+        // the `credential.helper` support.
         if allowed.contains(git2::CredentialType::USER_PASS_PLAINTEXT) {
+            if let Some((login, password)) = userpass.as_ref().filter(|_| !tried_userpass) {
+                tried_userpass = true;
+                return git2::Cred::userpass_plaintext(login, password);
+            }
             let r = git2::Cred::credential_helper(cfg, url, username);
             cred_helper_bad = Some(r.is_err());
             return r;
@@ -347,6 +546,12 @@ where
                 names
             ));
         }
+        if tried_userpass {
+            msg.push_str(
+                "\nattempted to use the credentials elba has for this host, \
+                 but they were rejected",
+            );
+        }
         if let Some(failed_cred_helper) = cred_helper_bad {
             if failed_cred_helper {
                 msg.push_str(
@@ -366,20 +571,105 @@ where
     Ok(res)
 }
 
+extern "C" {
+    // libgit2-sys doesn't have bindings for this, since it's variadic.
+    fn git_libgit2_opts(option: c_int, ...) -> c_int;
+}
+
+/// `GIT_OPT_SET_SSL_CERT_LOCATIONS` from libgit2's `git_libgit2_opt_t`.
+const GIT_OPT_SET_SSL_CERT_LOCATIONS: c_int = 12;
+
+/// Makes libgit2 trust the certificates in the CA bundle at `cainfo`, on top of the system ones.
+///
+/// This is a global libgit2 setting, so we only do it once for any given bundle. libgit2 has to
+/// have been initialized already (opening a `git2::Config` does that).
+fn set_cainfo(cainfo: &Path) -> Result<()> {
+    static CAINFO: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+    let mut current = CAINFO.lock().unwrap();
+    if current.as_deref() == Some(cainfo) {
+        return Ok(());
+    }
+
+    let file = CString::new(cainfo.to_string_lossy().into_owned())
+        .with_context(|_| format_err!("invalid CA bundle path {}", cainfo.display()))?;
+    let res = unsafe {
+        git_libgit2_opts(
+            GIT_OPT_SET_SSL_CERT_LOCATIONS,
+            file.as_ptr(),
+            ptr::null::<c_char>(),
+        )
+    };
+    if res < 0 {
+        let err = git2::Error::last_error(res)
+            .map(|e| e.message().to_owned())
+            .unwrap_or_default();
+        bail!("couldn't use CA bundle {}: {}", cainfo.display(), err)
+    }
+
+    *current = Some(cainfo.to_owned());
+    Ok(())
+}
+
 pub fn with_fetch_options<T>(
     git_config: &git2::Config,
     url: &Url,
+    http: &Client,
     cb: &mut dyn FnMut(git2::FetchOptions) -> Result<T>,
 ) -> Result<T> {
-    with_authentication(url.as_str(), git_config, |f| {
+    if let Some(cainfo) = &http.config().cainfo {
+        set_cainfo(cainfo)?;
+    }
+
+    // libgit2 doesn't have any timeouts, so the best we can do is give up on transfers that stay
+    // under the low-speed limit for too long. Transfers which stall completely are left to the OS.
+    let too_slow = Cell::new(None);
+    let userpass = http.credentials().userpass(url)?;
+    let res = with_authentication(url.as_str(), git_config, userpass, |f| {
         let mut rcb = git2::RemoteCallbacks::new();
         rcb.credentials(f);
 
+        let mut speed = http.speed_check();
+        let mut received = 0;
+        rcb.transfer_progress(|progress| {
+            // Once everything's been received, libgit2 keeps reporting progress while it indexes
+            // what it got, which doesn't say anything about the network.
+            if progress.received_objects() == progress.total_objects() {
+                return true;
+            }
+            let bytes = progress.received_bytes();
+            let res = speed.update((bytes - received) as u64);
+            received = bytes;
+            match res {
+                Ok(()) => true,
+                Err(e) => {
+                    too_slow.set(Some(e));
+                    false
+                }
+            }
+        });
+
+        // If elba wasn't told about a proxy, we let libgit2 pick one up from the git config and
+        // the environment, just like git itself would.
+        let mut popts = git2::ProxyOptions::new();
+        if let Some(proxy) = http.proxy() {
+            popts.url(proxy);
+        } else {
+            popts.auto();
+        }
+
         // Create a local anonymous remote in the repository to fetch the
         // url
         let mut opts = git2::FetchOptions::new();
         opts.remote_callbacks(rcb)
+            .proxy_options(popts)
             .download_tags(git2::AutotagOption::All);
         cb(opts)
-    })
+    });
+
+    // libgit2 just tells us that the transfer was cancelled, so we swap in the real reason
+    match too_slow.into_inner() {
+        Some(e) => Err(e.into()),
+        None => res,
+    }
 }
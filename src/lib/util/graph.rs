@@ -55,6 +55,30 @@ impl<T: Eq> Graph<T> {
             .map(move |node_id| (node_id, &self.inner[node_id]))
     }
 
+    /// Copies the sub tree of the given root into a graph of its own, with the root at index 0
+    pub fn sub_graph(&self, root_id: NodeIndex) -> Graph<T>
+    where
+        T: Clone,
+    {
+        let mut tree = petgraph::Graph::new();
+        let mut node_map: HashMap<NodeIndex, NodeIndex> = HashMap::new();
+
+        // The root is the first node a breadth-first search visits
+        for (idx, weight) in self.sub_tree(root_id) {
+            node_map.insert(idx, tree.add_node(weight.clone()));
+        }
+
+        for edge in self.inner.edge_references() {
+            if let (Some(&source), Some(&target)) =
+                (node_map.get(&edge.source()), node_map.get(&edge.target()))
+            {
+                tree.add_edge(source, target, ());
+            }
+        }
+
+        Graph::new(tree)
+    }
+
     /// Traverse all direct children of the given node
     pub fn children<'a>(
         &'a self,
@@ -128,3 +152,37 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn graph_sub_graph() {
+        let mut inner = petgraph::Graph::new();
+        let root = inner.add_node("root");
+        let a = inner.add_node("a");
+        let b = inner.add_node("b");
+        let c = inner.add_node("c");
+        let d = inner.add_node("d");
+        inner.add_edge(root, a, ());
+        inner.add_edge(root, b, ());
+        inner.add_edge(a, c, ());
+        inner.add_edge(b, c, ());
+        inner.add_edge(c, d, ());
+        let graph = Graph::new(inner);
+
+        let sub = graph.sub_graph(b);
+        assert_eq!(sub.root(), Some(&"b"));
+        assert_eq!(sub.inner.node_count(), 3);
+        assert!(sub.find_id(&"a").is_none());
+
+        // The edges between the nodes which were kept come along, and nothing else
+        let edges = sub
+            .inner
+            .edge_references()
+            .map(|edge| (sub[edge.source()], sub[edge.target()]))
+            .collect::<Vec<_>>();
+        assert_eq!(edges, vec![("b", "c"), ("c", "d")]);
+    }
+}
@@ -0,0 +1,613 @@
+//! Downloading things over HTTP.
+//!
+//! All of elba's HTTP traffic goes through the `Client` in this module, which takes care of
+//! proxies, custom CA bundles, timeouts and retrying spurious failures according to the `[http]`
+//! section of the configuration, as well as attaching per-host credentials to requests. Git
+//! fetches made through libgit2 don't go through the `Client` itself, but share its settings,
+//! low-speed check and retry loop.
+
+use std::{
+    env, fmt, fs,
+    io::Read,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use directories::BaseDirs;
+use failure::{format_err, Fail, ResultExt};
+use indexmap::IndexMap;
+use reqwest::{blocking, Certificate, Proxy, StatusCode};
+use url::Url;
+
+use crate::util::{config::Http, error::Result};
+
+/// The error we give back when a transfer drops below the configured low-speed limit.
+#[derive(Debug, Fail)]
+#[fail(
+    display = "transfer slower than {} bytes/sec for over {} seconds",
+    limit, secs
+)]
+pub(crate) struct TooSlow {
+    limit: u32,
+    secs: u64,
+}
+
+/// Keeps track of how fast a transfer is going, so that transfers which stay under the
+/// low-speed limit for too long can be aborted.
+pub(crate) struct SpeedCheck {
+    limit: u32,
+    window: Duration,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl SpeedCheck {
+    fn new(config: &Http) -> Self {
+        SpeedCheck {
+            limit: config.low_speed_limit,
+            window: Duration::from_secs(config.timeout),
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    /// Records that another `n` bytes came in, failing if the transfer has been too slow.
+    pub(crate) fn update(&mut self, n: u64) -> std::result::Result<(), TooSlow> {
+        self.window_bytes += n;
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= self.window {
+            if self.window_bytes < u64::from(self.limit) * elapsed.as_secs() {
+                return Err(TooSlow {
+                    limit: self.limit,
+                    secs: self.window.as_secs(),
+                });
+            }
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+        }
+
+        Ok(())
+    }
+}
+
+/// An HTTP client set up according to the `[http]` section of the configuration.
+#[derive(Debug, Clone)]
+pub struct Client {
+    inner: blocking::Client,
+    config: Http,
+    credentials: Credentials,
+}
+
+impl Client {
+    pub fn new(config: &Http, credentials: Credentials) -> Result<Self> {
+        // The blocking client applies its timeout to waiting for the response and to every
+        // read of the body separately, so a stalled connection fails without big tarballs timing
+        // out just because they're big; slow transfers are dealt with by the low-speed limit.
+        let mut builder = blocking::Client::builder()
+            .connect_timeout(Duration::from_secs(config.timeout))
+            .timeout(Duration::from_secs(config.timeout));
+
+        if let Some(proxy) = &config.proxy {
+            let proxy = Proxy::all(proxy.as_str())
+                .with_context(|e| format_err!("invalid http proxy {}: {}", proxy, e))?;
+            builder = builder.proxy(proxy);
+        }
+
+        if let Some(cainfo) = &config.cainfo {
+            let pem = fs::read(cainfo).with_context(|e| {
+                format_err!("couldn't read CA bundle {}: {}", cainfo.display(), e)
+            })?;
+            let cert = Certificate::from_pem(&pem)
+                .with_context(|e| format_err!("invalid CA bundle {}: {}", cainfo.display(), e))?;
+            builder = builder.add_root_certificate(cert);
+        }
+
+        Ok(Client {
+            inner: builder.build()?,
+            config: config.clone(),
+            credentials,
+        })
+    }
+
+    /// The credentials which get attached to requests made by this client.
+    pub fn credentials(&self) -> &Credentials {
+        &self.credentials
+    }
+
+    /// The proxy that should be used for all HTTP traffic, if one was configured.
+    pub fn proxy(&self) -> Option<&str> {
+        self.config.proxy.as_deref()
+    }
+
+    /// The `[http]` section of the configuration this client was set up with.
+    pub fn config(&self) -> &Http {
+        &self.config
+    }
+
+    /// Starts keeping track of the speed of a new transfer.
+    pub(crate) fn speed_check(&self) -> SpeedCheck {
+        SpeedCheck::new(&self.config)
+    }
+
+    /// Downloads the contents of `url`.
+    pub fn get(&self, url: &Url) -> Result<Vec<u8>> {
+        self.retry(|| self.try_get(url))
+    }
+
+    /// Runs `f` until it succeeds, retrying it after spurious failures (connection problems,
+    /// timeouts, server errors and overly slow transfers) up to `http.retry` times, and waiting
+    /// twice as long before each new attempt.
+    pub fn retry<T, F>(&self, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        let mut tries = 0;
+        loop {
+            match f() {
+                Ok(x) => return Ok(x),
+                Err(e) => {
+                    if tries >= self.config.retry || !is_spurious(&e) {
+                        return Err(e);
+                    }
+                    thread::sleep(Duration::from_secs(1 << tries.min(6)));
+                    tries += 1;
+                }
+            }
+        }
+    }
+
+    fn try_get(&self, url: &Url) -> Result<Vec<u8>> {
+        let mut req = self.inner.get(url.as_str());
+        match self.credentials.get(url)? {
+            Some(Auth::Bearer(token)) => req = req.bearer_auth(token),
+            Some(Auth::Basic(login, password)) => req = req.basic_auth(login, password),
+            None => {}
+        }
+
+        let mut resp = req.send().and_then(|resp| resp.error_for_status())?;
+        let mut speed = self.speed_check();
+
+        let mut buf = vec![];
+        let mut chunk = [0u8; 16 * 1024];
+        loop {
+            let n = resp.read(&mut chunk)?;
+            if n == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+            speed.update(n as u64)?;
+        }
+
+        Ok(buf)
+    }
+}
+
+fn is_spurious(err: &failure::Error) -> bool {
+    if let Some(e) = err.downcast_ref::<reqwest::Error>() {
+        if e.is_builder() || e.is_redirect() {
+            return false;
+        }
+        return e
+            .status()
+            .map(|s| s.is_server_error() || s == StatusCode::TOO_MANY_REQUESTS)
+            .unwrap_or(true);
+    }
+
+    if let Some(e) = err.downcast_ref::<git2::Error>() {
+        // Bad credentials and certificates won't get any better by trying again.
+        return match e.code() {
+            git2::ErrorCode::Auth | git2::ErrorCode::Certificate => false,
+            _ => match e.class() {
+                git2::ErrorClass::Net | git2::ErrorClass::Os | git2::ErrorClass::Ssl => true,
+                // The libgit2 we use doesn't classify bad HTTP responses at all, so all we have to
+                // go on is the message.
+                git2::ErrorClass::None => e
+                    .message()
+                    .trim_start_matches("unexpected http status code: ")
+                    .parse::<u16>()
+                    .ok()
+                    .and_then(|code| StatusCode::from_u16(code).ok())
+                    .map(|s| s.is_server_error() || s == StatusCode::TOO_MANY_REQUESTS)
+                    .unwrap_or(false),
+                _ => false,
+            },
+        };
+    }
+
+    err.downcast_ref::<std::io::Error>().is_some() || err.downcast_ref::<TooSlow>().is_some()
+}
+
+/// The credentials to use for a given host.
+///
+/// Note that this type purposely doesn't implement `Debug`, so that secrets can't accidentally
+/// end up in log output or error messages.
+pub enum Auth {
+    Bearer(String),
+    Basic(String, Option<String>),
+}
+
+/// All of the credentials that elba knows about, keyed by host.
+///
+/// Bearer tokens are looked up first in the environment, in a variable named `ELBA_TOKEN_<HOST>`
+/// (where `<HOST>` is the uppercased host name with every non-alphanumeric character replaced by
+/// an underscore), and then in the logins file. If neither has a token for a host, we fall back
+/// to basic auth using the matching entry of the user's netrc file.
+#[derive(Clone)]
+pub struct Credentials {
+    /// The tokens from the logins file, or why we couldn't read them. Plenty of commands never
+    /// need any credentials, so a broken logins file is only an error once we go looking for a
+    /// token in it.
+    tokens: std::result::Result<IndexMap<String, String>, String>,
+    netrc: Vec<NetrcEntry>,
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Credentials {
+            tokens: Ok(IndexMap::new()),
+            netrc: vec![],
+        }
+    }
+}
+
+impl Credentials {
+    /// Loads the tokens stored in the logins file at `logins` along with the user's netrc file,
+    /// which is either `~/.netrc` or whatever the `NETRC` environment variable points to.
+    pub fn load(logins: &Path) -> Self {
+        let tokens = if logins.exists() {
+            fs::read_to_string(logins)
+                .map_err(|e| format!("couldn't read logins file {}: {}", logins.display(), e))
+                .and_then(|contents| {
+                    // We don't say *why* parsing failed here, since toml errors like to quote
+                    // the offending text back at us, and that text might be a token.
+                    toml::from_str(&contents)
+                        .map_err(|_| format!("invalid logins file {}", logins.display()))
+                })
+        } else {
+            Ok(IndexMap::new())
+        };
+
+        let netrc = netrc_path()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|contents| parse_netrc(&contents))
+            .unwrap_or_default();
+
+        Credentials { tokens, netrc }
+    }
+
+    /// Finds the credentials to use for requests to `url`.
+    ///
+    /// This fails if we need to look in the logins file and it couldn't be loaded.
+    pub fn get(&self, url: &Url) -> Result<Option<Auth>> {
+        let host = match url.host_str() {
+            Some(host) => host,
+            None => return Ok(None),
+        };
+
+        if let Ok(token) = env::var(token_var(host)) {
+            return Ok(Some(Auth::Bearer(token)));
+        }
+
+        let tokens = self.tokens.as_ref().map_err(|e| format_err!("{}", e))?;
+        if let Some(token) = tokens.get(host) {
+            return Ok(Some(Auth::Bearer(token.clone())));
+        }
+
+        Ok(self
+            .netrc
+            .iter()
+            .find(|entry| entry.machine.as_deref() == Some(host))
+            .or_else(|| self.netrc.iter().find(|entry| entry.machine.is_none()))
+            .and_then(|entry| {
+                entry
+                    .login
+                    .clone()
+                    .map(|login| Auth::Basic(login, entry.password.clone()))
+            }))
+    }
+
+    /// Finds a username and password to use for `url`, for protocols (like git over HTTP) which
+    /// only understand basic auth. Bearer tokens are passed along as the password.
+    pub fn userpass(&self, url: &Url) -> Result<Option<(String, String)>> {
+        Ok(match self.get(url)? {
+            Some(Auth::Basic(login, password)) => Some((login, password.unwrap_or_default())),
+            Some(Auth::Bearer(token)) => {
+                let login = if url.username().is_empty() {
+                    "elba"
+                } else {
+                    url.username()
+                };
+                Some((login.to_owned(), token))
+            }
+            None => None,
+        })
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field(
+                "tokens",
+                &self.tokens.as_ref().map(|x| x.keys().collect::<Vec<_>>()),
+            )
+            .field(
+                "netrc",
+                &self.netrc.iter().map(|x| &x.machine).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Eq)]
+struct NetrcEntry {
+    /// The host this entry is for; `None` for the `default` entry.
+    machine: Option<String>,
+    login: Option<String>,
+    password: Option<String>,
+}
+
+fn token_var(host: &str) -> String {
+    let host: String = host
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    format!("ELBA_TOKEN_{}", host)
+}
+
+fn netrc_path() -> Option<PathBuf> {
+    env::var_os("NETRC")
+        .map(PathBuf::from)
+        .or_else(|| BaseDirs::new().map(|dirs| dirs.home_dir().join(".netrc")))
+}
+
+fn parse_netrc(contents: &str) -> Vec<NetrcEntry> {
+    let mut entries = vec![];
+    let mut current: Option<NetrcEntry> = None;
+
+    let mut lines = contents.lines();
+    while let Some(line) = lines.next() {
+        let mut tokens = line.split_whitespace();
+        while let Some(token) = tokens.next() {
+            match token {
+                "machine" | "default" => {
+                    entries.extend(current.take());
+                    current = Some(NetrcEntry {
+                        machine: if token == "machine" {
+                            tokens.next().map(|x| x.to_owned())
+                        } else {
+                            None
+                        },
+                        ..NetrcEntry::default()
+                    });
+                }
+                "login" => {
+                    let login = tokens.next().map(|x| x.to_owned());
+                    if let Some(entry) = current.as_mut() {
+                        entry.login = login;
+                    }
+                }
+                "password" => {
+                    let password = tokens.next().map(|x| x.to_owned());
+                    if let Some(entry) = current.as_mut() {
+                        entry.password = password;
+                    }
+                }
+                "account" => {
+                    tokens.next();
+                }
+                "macdef" => {
+                    // Macro definitions go on until the next blank line.
+                    for line in &mut lines {
+                        if line.trim().is_empty() {
+                            break;
+                        }
+                    }
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+    entries.extend(current);
+
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::git;
+    use std::{
+        io::Write,
+        net::{TcpListener, TcpStream},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    /// Serves every connection to a local listener with `respond`, returning the URL to hit.
+    fn serve<F>(respond: F) -> Url
+    where
+        F: Fn(usize, TcpStream) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        thread::spawn(move || {
+            for (ix, stream) in listener.incoming().enumerate() {
+                respond(ix, stream.unwrap());
+            }
+        });
+
+        url
+    }
+
+    fn client(timeout: u64, low_speed_limit: u32, retry: u32) -> Client {
+        let config = Http {
+            timeout,
+            low_speed_limit,
+            retry,
+            ..Http::default()
+        };
+        Client::new(&config, Credentials::default()).unwrap()
+    }
+
+    #[test]
+    fn http_stalled_server() {
+        // Accepts the connection, but never sends anything back
+        let url = serve(|_, stream| {
+            thread::spawn(move || {
+                thread::sleep(Duration::from_secs(30));
+                drop(stream);
+            });
+        });
+
+        let start = Instant::now();
+        let err = client(1, 10, 0).get(&url).unwrap_err();
+        assert!(is_spurious(&err));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn http_retries_server_errors() {
+        let url = serve(|ix, mut stream| {
+            let mut req = [0u8; 1024];
+            let _ = stream.read(&mut req);
+            let resp = if ix == 0 {
+                "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n"
+            } else {
+                "HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nhello"
+            };
+            stream.write_all(resp.as_bytes()).unwrap();
+        });
+
+        assert!(client(5, 10, 0).get(&url).is_err());
+        assert_eq!(client(5, 10, 1).get(&url).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn http_low_speed_limit() {
+        // Trickles out a byte every half second, well under the limit
+        let url = serve(|_, mut stream| {
+            thread::spawn(move || {
+                let mut req = [0u8; 1024];
+                let _ = stream.read(&mut req);
+                let head = "HTTP/1.1 200 OK\r\ncontent-length: 100\r\n\r\n";
+                if stream.write_all(head.as_bytes()).is_err() {
+                    return;
+                }
+                for _ in 0..100 {
+                    thread::sleep(Duration::from_millis(500));
+                    if stream.write_all(b"x").is_err() {
+                        return;
+                    }
+                }
+            });
+        });
+
+        let err = client(1, 100, 0).get(&url).unwrap_err();
+        assert!(err.downcast_ref::<TooSlow>().is_some());
+    }
+
+    #[test]
+    fn git_fetch_retries() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let url = serve(move |_, mut stream| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let mut req = [0u8; 1024];
+            let _ = stream.read(&mut req);
+            let resp = "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n";
+            stream.write_all(resp.as_bytes()).unwrap();
+        });
+
+        let dir = tempdir::TempDir::new("elba").unwrap();
+        let mut repo = git2::Repository::init_bare(dir.path()).unwrap();
+        let refspec = "+refs/heads/*:refs/heads/*";
+
+        let err = git::fetch(&mut repo, &url, &[refspec], &client(5, 10, 0)).unwrap_err();
+        assert!(is_spurious(&err));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        hits.store(0, Ordering::SeqCst);
+        assert!(git::fetch(&mut repo, &url, &[refspec], &client(5, 10, 1)).is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn netrc_entries() {
+        let netrc = "machine pkgs.example.com login alice password hunter2\n\
+                     macdef init\n\
+                     machine fake login mallory\n\
+                     \n\
+                     machine other.example.com\n\
+                     \tlogin bob\n\
+                     default login anonymous password guest";
+
+        let entries = parse_netrc(netrc);
+
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].machine.as_ref().unwrap(), "pkgs.example.com");
+        assert_eq!(entries[0].password.as_ref().unwrap(), "hunter2");
+        assert_eq!(entries[1].login.as_ref().unwrap(), "bob");
+        assert!(entries[1].password.is_none());
+        assert!(entries[2].machine.is_none());
+    }
+
+    #[test]
+    fn credentials_lookup() {
+        let creds = Credentials {
+            tokens: Ok(indexmap::indexmap!("pkgs.example.com".to_owned() => "t0ken".to_owned())),
+            netrc: parse_netrc("machine git.example.com login alice password hunter2"),
+        };
+
+        let url = Url::parse("https://pkgs.example.com/a/b.tar.gz").unwrap();
+        match creds.get(&url).unwrap() {
+            Some(Auth::Bearer(token)) => assert_eq!(token, "t0ken"),
+            _ => panic!("expected a bearer token"),
+        }
+
+        let url = Url::parse("https://git.example.com/index.git").unwrap();
+        assert_eq!(
+            creds.userpass(&url).unwrap(),
+            Some(("alice".to_owned(), "hunter2".to_owned()))
+        );
+
+        let url = Url::parse("https://elsewhere.example.com/").unwrap();
+        assert!(creds.get(&url).unwrap().is_none());
+    }
+
+    #[test]
+    fn credentials_invalid_logins() {
+        let dir = tempdir::TempDir::new("elba").unwrap();
+        let logins = dir.path().join("logins.toml");
+        fs::write(&logins, "pkgs.example.com = t0ken").unwrap();
+
+        // Loading always works; the error only comes up once we need a token
+        let creds = Credentials::load(&logins);
+        let url = Url::parse("https://pkgs.example.com/a/b.tar.gz").unwrap();
+        let err = match creds.get(&url) {
+            Err(e) => e.to_string(),
+            Ok(_) => panic!("expected an invalid logins file"),
+        };
+        assert!(err.starts_with("invalid logins file"));
+        assert!(!err.contains("t0ken"));
+
+        let url = Url::parse("file:///a/b.tar.gz").unwrap();
+        assert!(creds.get(&url).unwrap().is_none());
+    }
+
+    #[test]
+    fn token_vars() {
+        assert_eq!(token_var("pkgs.example.com"), "ELBA_TOKEN_PKGS_EXAMPLE_COM");
+    }
+}
@@ -2,24 +2,96 @@
 //!
 //! As it is currently designed, `elba` doesn't need to lock individual files. It does, however,
 //! need to lock directories to prevent other processes from using them.
+//!
+//! Directories can be locked either exclusively, for when we're going to change their contents, or
+//! shared, for when we're only going to read them; any number of processes can hold a shared lock
+//! on a directory at once. The lockfile itself records the PIDs of everyone holding the lock, so
+//! that if we end up waiting on a lock forever, there's some way of figuring out who's to blame.
+//! Since any number of shared holders can be updating that list at once, they take turns through
+//! a second lockfile, held only for as long as it takes to update the list.
 
-use failure::{bail, format_err, Error, ResultExt};
+use crate::util::shell::{Shell, Verbosity};
+use console::style;
+use failure::{bail, format_err, Error, Fail, ResultExt};
 use fs2::FileExt;
 use std::{
-    fs,
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, prelude::*, SeekFrom},
     path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, Instant},
 };
 
-/// A lock on a directory. This just generates a sibling file to the directory which indicates that
-/// the directory is locked.
-#[derive(Debug, PartialEq, Eq)]
+/// The name of the lockfile in a locked directory.
+const LOCKFILE: &str = ".dirlock";
+/// The name of the lockfile which shared holders take turns updating the list of PIDs through.
+const PIDS_LOCKFILE: &str = ".dirlock.pids";
+
+/// Whether a file in a locked directory is one of the lockfiles, rather than part of its contents.
+pub fn is_lockfile(name: &OsStr) -> bool {
+    name == LOCKFILE || name == PIDS_LOCKFILE
+}
+
+/// The ways in which a directory can be locked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    /// Any number of processes can hold a shared lock at once. Used for read-only access.
+    Shared,
+    /// Only one process can hold an exclusive lock, and nobody can hold a shared lock alongside it.
+    Exclusive,
+}
+
+/// A lock on a directory. This just generates a file inside the directory which indicates that
+/// the directory is locked, and which contains the PIDs of the processes holding the lock.
+#[derive(Debug)]
 pub struct DirLock {
     path: PathBuf,
     lock_path: PathBuf,
+    file: File,
+    kind: LockKind,
 }
 
 impl DirLock {
+    /// Takes an exclusive lock on a directory, waiting for as long as it takes.
     pub fn acquire(path: &Path) -> Result<Self, Error> {
+        Self::acquire_with(path, LockKind::Exclusive, None, Shell::default())
+    }
+
+    /// Takes a shared lock on a directory, waiting for as long as it takes.
+    pub fn acquire_shared(path: &Path) -> Result<Self, Error> {
+        Self::acquire_with(path, LockKind::Shared, None, Shell::default())
+    }
+
+    /// Takes a lock on a directory only if nobody else is in the way.
+    pub fn try_acquire(path: &Path, kind: LockKind) -> Result<Option<Self>, Error> {
+        match Self::acquire_with(
+            path,
+            kind,
+            Some(Duration::from_secs(0)),
+            Shell {
+                verbosity: Verbosity::None,
+            },
+        ) {
+            Ok(lock) => Ok(Some(lock)),
+            Err(e) => {
+                if e.downcast_ref::<TimedOut>().is_some() {
+                    Ok(None)
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    /// Takes a lock on a directory. If someone else is holding it, we tell the user that we're
+    /// waiting, and give up once `timeout` has passed (if there is one).
+    pub fn acquire_with(
+        path: &Path,
+        kind: LockKind,
+        timeout: Option<Duration>,
+        shell: Shell,
+    ) -> Result<Self, Error> {
         fs::create_dir_all(&path).with_context(|e| {
             format_err!(
                 "couldn't create dir {} while locking: {}",
@@ -28,57 +100,302 @@ impl DirLock {
             )
         })?;
 
-        let lock_path = path.join(".dirlock");
+        let lock_path = path.join(LOCKFILE);
+        let deadline = timeout.map(|x| Instant::now() + x);
+        let mut warned = false;
 
-        let f = fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&lock_path)
-            .with_context(|e| {
-                format_err!("couldn't open lockfile {}: {}", lock_path.display(), e)
-            })?;
+        loop {
+            let file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&lock_path)
+                .with_context(|e| {
+                    format_err!("couldn't open lockfile {}: {}", lock_path.display(), e)
+                })?;
 
-        if f.metadata()
-            .with_context(|e| format_err!("couldn't get lockfile metadata: {}", e))?
-            .len()
-            != 0
-        {
-            bail!(
-                "lockfile name conflict with existing file {}",
-                lock_path.display()
-            )
-        }
+            match try_lock(&file, kind) {
+                Ok(()) => {}
+                Err(ref e) if is_contended(e) => {
+                    if !warned {
+                        shell.println(
+                            style("Blocking").cyan(),
+                            format!(
+                                "waiting for lock on {}{}",
+                                path.display(),
+                                describe_holders(&file)
+                            ),
+                            Verbosity::Normal,
+                        );
+                        warned = true;
+                    }
 
-        f.lock_exclusive().with_context(|e| {
-            format_err!("couldn't lock lockfile {}: {}", lock_path.display(), e)
-        })?;
+                    if let Some(deadline) = deadline {
+                        wait_until(&file, kind, deadline).map_err(|e| {
+                            if is_contended(&e) {
+                                Error::from(TimedOut {
+                                    path: path.display().to_string(),
+                                    holders: describe_holders(&file),
+                                })
+                            } else {
+                                format_err!("couldn't lock lockfile {}: {}", lock_path.display(), e)
+                            }
+                        })?;
+                    } else {
+                        lock(&file, kind).with_context(|e| {
+                            format_err!("couldn't lock lockfile {}: {}", lock_path.display(), e)
+                        })?;
+                    }
+                }
+                Err(e) => bail!("couldn't lock lockfile {}: {}", lock_path.display(), e),
+            }
 
-        Ok(DirLock {
-            path: path.to_path_buf(),
-            lock_path,
-        })
+            // Whoever held the lock before us might have deleted the lockfile after we opened it,
+            // in which case we've locked a file nobody else can see; if so, try again.
+            if !is_same_file(&file, &lock_path) {
+                continue;
+            }
+
+            let lock = DirLock {
+                path: path.to_path_buf(),
+                lock_path,
+                file,
+                kind,
+            };
+            lock.add_pid().with_context(|e| {
+                format_err!(
+                    "couldn't write to lockfile {}: {}",
+                    lock.lock_path.display(),
+                    e
+                )
+            })?;
+
+            return Ok(lock);
+        }
     }
 
     pub fn path(&self) -> &Path {
         &self.path
     }
+
+    pub fn kind(&self) -> LockKind {
+        self.kind
+    }
+
+    /// Removes everything in the locked directory except for the lockfile itself, so that the
+    /// directory stays locked while it's emptied.
+    pub fn clear(&self) -> Result<(), Error> {
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            if is_lockfile(&entry.file_name()) {
+                continue;
+            }
+            let path = entry.path();
+            if path.is_dir() {
+                remove_dir_all::remove_dir_all(&path)?;
+            } else {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_pid(&self) -> io::Result<()> {
+        let line = format!("{}\n", process::id());
+        match self.kind {
+            LockKind::Exclusive => {
+                let mut file = &self.file;
+                file.set_len(0)?;
+                file.seek(SeekFrom::Start(0))?;
+                file.write_all(line.as_bytes())
+            }
+            LockKind::Shared => {
+                let _turn = self.pids_turn()?;
+                let mut file = &self.file;
+                file.seek(SeekFrom::End(0))?;
+                file.write_all(line.as_bytes())
+            }
+        }
+    }
+
+    /// Removes our PID from the lockfile, returning whether anybody might be left holding the
+    /// lock. Lines which aren't PIDs are left alone, since we can't tell who they belong to.
+    fn remove_pid(&self) -> io::Result<bool> {
+        let _turn = self.pids_turn()?;
+        let pid = process::id().to_string();
+        let mut contents = String::new();
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_string(&mut contents)?;
+
+        let mut lines = contents
+            .lines()
+            .filter(|x| !x.trim().is_empty())
+            .collect::<Vec<_>>();
+        if let Some(ix) = lines.iter().position(|x| x.trim() == pid) {
+            lines.remove(ix);
+        }
+
+        let contents: String = lines.iter().map(|x| format!("{}\n", x)).collect();
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(contents.as_bytes())?;
+
+        Ok(!lines.is_empty())
+    }
+
+    /// Waits for our turn to update the list of PIDs in the lockfile, which lasts until the
+    /// returned file is dropped.
+    fn pids_turn(&self) -> io::Result<File> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.path.join(PIDS_LOCKFILE))?;
+        FileExt::lock_exclusive(&file)?;
+        Ok(file)
+    }
+}
+
+impl PartialEq for DirLock {
+    fn eq(&self, other: &DirLock) -> bool {
+        self.path == other.path && self.kind == other.kind
+    }
 }
 
+impl Eq for DirLock {}
+
 impl Drop for DirLock {
     fn drop(&mut self) {
-        let f = fs::OpenOptions::new()
-            .read(true)
-            .create(true)
-            .open(&self.lock_path);
+        let others = match self.kind {
+            LockKind::Exclusive => Some(false),
+            LockKind::Shared => self.remove_pid().ok(),
+        };
 
-        if let Ok(f) = f {
-            let _ = f.unlock();
+        // We only get rid of the lockfile once we're sure nobody else is using it.
+        let last = match (self.kind, others) {
+            (LockKind::Exclusive, _) => true,
+            (LockKind::Shared, Some(false)) => FileExt::try_lock_exclusive(&self.file).is_ok(),
+            _ => false,
+        };
+
+        // Nobody touches the list of PIDs without holding the lock, so with the lock to ourselves,
+        // it's safe to get rid of the other lockfile too.
+        if last && is_same_file(&self.file, &self.lock_path) {
+            let _ = fs::remove_file(self.path.join(PIDS_LOCKFILE));
+            let _ = fs::remove_file(&self.lock_path);
         }
 
-        let _ = fs::remove_file(&self.lock_path);
+        let _ = FileExt::unlock(&self.file);
     }
 }
 
+/// The error we give back when we gave up on waiting for a lock.
+#[derive(Debug, Fail)]
+#[fail(display = "timed out waiting for lock on {}{}", path, holders)]
+struct TimedOut {
+    path: String,
+    holders: String,
+}
+
+fn try_lock(file: &File, kind: LockKind) -> io::Result<()> {
+    match kind {
+        LockKind::Shared => FileExt::try_lock_shared(file),
+        LockKind::Exclusive => FileExt::try_lock_exclusive(file),
+    }
+}
+
+fn lock(file: &File, kind: LockKind) -> io::Result<()> {
+    match kind {
+        LockKind::Shared => FileExt::lock_shared(file),
+        LockKind::Exclusive => FileExt::lock_exclusive(file),
+    }
+}
+
+fn wait_until(file: &File, kind: LockKind, deadline: Instant) -> io::Result<()> {
+    loop {
+        match try_lock(file, kind) {
+            Err(ref e) if is_contended(e) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(fs2::lock_contended_error());
+                }
+                thread::sleep((deadline - now).min(Duration::from_millis(100)));
+            }
+            res => return res,
+        }
+    }
+}
+
+fn is_contended(e: &io::Error) -> bool {
+    e.raw_os_error() == fs2::lock_contended_error().raw_os_error()
+}
+
+/// Reads the PIDs out of a lockfile. Returns `None` if the file has something other than PIDs in
+/// it, in which case we can't tell who's holding the lock.
+fn holders(mut file: &File) -> Option<Vec<u32>> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+
+    contents
+        .lines()
+        .filter(|x| !x.trim().is_empty())
+        .map(|x| x.trim().parse().ok())
+        .collect()
+}
+
+fn describe_holders(file: &File) -> String {
+    let pids = match holders(file) {
+        Some(pids) => pids,
+        None => return " (held by unknown processes)".to_string(),
+    };
+    if pids.is_empty() {
+        return String::new();
+    }
+
+    let pids = pids
+        .iter()
+        .map(|&pid| match is_running(pid) {
+            Some(false) => format!("{} (not running; stale entry)", pid),
+            _ => pid.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(" (held by pid {})", pids)
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> Option<bool> {
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    Some(res == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM))
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> Option<bool> {
+    None
+}
+
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+// Windows won't let anyone delete a file someone else has open, so there's nothing to check.
+#[cfg(not(unix))]
+fn is_same_file(_file: &File, path: &Path) -> bool {
+    path.exists()
+}
+
 #[cfg(test)]
 mod test {
     extern crate tempdir;
@@ -105,12 +422,96 @@ mod test {
     }
 
     #[test]
-    fn dirlock_existing_err() {
+    fn dirlock_existing_garbage() {
         let tmp = tempdir::TempDir::new("elba").unwrap();
         fs::write(tmp.path().join(".dirlock"), b"hello world").unwrap();
 
-        let lock = DirLock::acquire(tmp.path());
+        // We can't tell who's holding the lock, but that doesn't stop us from taking it
+        let shared = DirLock::acquire_shared(tmp.path()).unwrap();
+        let holders = describe_holders(&shared.file);
+        assert_eq!(holders, " (held by unknown processes)");
+        drop(shared);
+
+        let lock = DirLock::acquire(tmp.path()).unwrap();
+        let pid = process::id().to_string();
+        let contents = fs::read_to_string(tmp.path().join(".dirlock")).unwrap();
+        assert_eq!(contents.trim(), pid);
+        drop(lock);
+    }
+
+    #[test]
+    fn dirlock_shared_concurrent() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let path = tmp.path().to_path_buf();
+        // Keep one reader around the whole time, so the lockfile sticks around
+        let _reader = DirLock::acquire_shared(&path).unwrap();
+
+        let threads = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        let lock = DirLock::acquire_shared(&path).unwrap();
+                        assert!(holders(&lock.file).is_some());
+                        drop(lock);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        // Every PID that was added got removed again, leaving only the one reader
+        let pid = process::id().to_string();
+        let contents = fs::read_to_string(path.join(".dirlock")).unwrap();
+        assert_eq!(contents.lines().collect::<Vec<_>>(), vec![pid.as_str()]);
+    }
+
+    #[test]
+    fn dirlock_shared() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let a = DirLock::acquire_shared(tmp.path()).unwrap();
+        let b = DirLock::acquire_shared(tmp.path()).unwrap();
+
+        // Both readers are recorded in the lockfile
+        let pid = process::id().to_string();
+        let contents = fs::read_to_string(tmp.path().join(".dirlock")).unwrap();
+        assert_eq!(contents.lines().filter(|x| *x == pid).count(), 2);
+
+        // Writers have to wait for every reader to be done
+        assert!(DirLock::try_acquire(tmp.path(), LockKind::Exclusive)
+            .unwrap()
+            .is_none());
+        drop(a);
+        assert!(DirLock::try_acquire(tmp.path(), LockKind::Exclusive)
+            .unwrap()
+            .is_none());
+        drop(b);
+        assert!(!tmp.path().join(".dirlock").exists());
+        assert!(DirLock::try_acquire(tmp.path(), LockKind::Exclusive)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn dirlock_timeout() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let _lock = DirLock::acquire(tmp.path()).unwrap();
+
+        let shell = Shell {
+            verbosity: Verbosity::None,
+        };
+        let err = DirLock::acquire_with(
+            tmp.path(),
+            LockKind::Shared,
+            Some(Duration::from_millis(200)),
+            shell,
+        )
+        .unwrap_err();
 
-        assert!(lock.is_err());
+        assert!(err
+            .to_string()
+            .contains(&format!("held by pid {}", process::id())));
     }
 }
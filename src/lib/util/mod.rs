@@ -4,6 +4,7 @@ pub mod config;
 pub mod error;
 pub mod git;
 pub mod graph;
+pub mod http;
 pub mod lock;
 pub mod parser;
 pub mod read2;
@@ -1,6 +1,8 @@
 use nom::{
     bytes::complete::{tag, take_while1},
     character::complete::{space0, space1},
+    combinator::opt,
+    sequence::pair,
     IResult,
 };
 
@@ -28,6 +30,7 @@ fn parse_import(i: &str) -> IResult<&str, Module> {
     let (i, _) = space0(i)?;
     let (i, _) = tag("import")(i)?;
     let (i, _) = space1(i)?;
+    let (i, _) = opt(pair(tag("public"), space1))(i)?;
     let (i, module) = take_while1(|c: char| c == '.' || c.is_ascii_alphanumeric())(i)?;
     Ok((i, Module(module.to_string())))
 }
@@ -60,6 +63,23 @@ main = do let t = toTree [1,8,2,7,9,3]
         );
     }
 
+    #[test]
+    fn test_parse_import_public() {
+        let src = r#"
+module Btree
+
+import public Btree.Node
+import Data.List
+"#;
+        assert_eq!(
+            find_imports(src, false),
+            vec![
+                Module("Btree.Node".to_string()),
+                Module("Data.List".to_string())
+            ]
+        );
+    }
+
     #[test]
     fn test_parse_import_literal() {
         let src = r#"
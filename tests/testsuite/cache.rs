@@ -0,0 +1,298 @@
+use super::util::{layout, shell, CACHE, CACHE_DIR, LOGGER};
+use elba::{
+    package::{Name, PackageId},
+    remote::resolution::DirectRes,
+    retrieve::{
+        cache::{BuildHash, BuildMeta, BuildsManifest, EntryKind, Layout},
+        Cache,
+    },
+    util::{
+        config::{Http, Net},
+        lock::DirLock,
+    },
+};
+use git2::{Repository, Signature};
+use std::{fs, path::Path, str::FromStr};
+use tempdir::TempDir;
+use url::Url;
+
+fn commit(repo: &Repository, version: &str) -> String {
+    let root = repo.workdir().unwrap();
+    fs::write(
+        root.join("elba.toml"),
+        format!(
+            "[package]\nname = \"git/repo\"\nversion = \"{}\"\nauthors = []\n",
+            version
+        ),
+    )
+    .unwrap();
+
+    let mut index = repo.index().unwrap();
+    index.add_path(Path::new("elba.toml")).unwrap();
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+
+    let sig = Signature::now("elba", "elba@example.com").unwrap();
+    let parent = repo.head().ok().map(|x| x.peel_to_commit().unwrap());
+    let parents = parent.iter().collect::<Vec<_>>();
+
+    repo.commit(Some("HEAD"), &sig, &sig, version, &tree, &parents)
+        .unwrap()
+        .to_string()
+}
+
+#[test]
+fn git_checkouts() {
+    let remote = TempDir::new("elba-git").unwrap();
+    let repo = Repository::init(remote.path()).unwrap();
+    let first = commit(&repo, "0.1.0");
+
+    let url = Url::from_directory_path(remote.path()).unwrap();
+    let loc = DirectRes::from_str(&format!("git+{}#master", url)).unwrap();
+    let pkg = PackageId::new(Name::from_str("git/repo").unwrap(), loc.clone().into());
+
+    let (res, src) = CACHE
+        .checkout_source(&pkg, &loc, false, false, || ())
+        .unwrap();
+    let pinned = DirectRes::from_str(&format!("git+{}#{}", url, first)).unwrap();
+    assert_eq!(res, Some(pinned.clone()));
+    assert_eq!(src.meta().version().to_string(), "0.1.0");
+    assert!(!src.path().join(".git").exists());
+    drop(src);
+
+    // With the lockfile pointing at the first commit, we don't move even though master has
+    // moved on
+    commit(&repo, "0.2.0");
+    let (_, src) = CACHE
+        .checkout_source(&pkg, &pinned, false, false, || ())
+        .unwrap();
+    assert_eq!(src.meta().version().to_string(), "0.1.0");
+    drop(src);
+
+    // ...unless we're updating
+    let (_, src) = CACHE
+        .checkout_source(&pkg, &loc, true, false, || ())
+        .unwrap();
+    assert_eq!(src.meta().version().to_string(), "0.2.0");
+    drop(src);
+
+    // Both revisions live in the same db
+    assert_eq!(
+        fs::read_dir(CACHE_DIR.path().join("git")).unwrap().count(),
+        1
+    );
+}
+
+#[test]
+fn git_tags_and_submodules() {
+    let remote = TempDir::new("elba-git").unwrap();
+    let repo = Repository::init(remote.path()).unwrap();
+    let first = commit(&repo, "0.1.0");
+    let obj = repo.revparse_single(&first).unwrap();
+    let sig = Signature::now("elba", "elba@example.com").unwrap();
+    repo.tag("v0.1.0", &obj, &sig, "v0.1.0", false).unwrap();
+    commit(&repo, "0.2.0");
+
+    let url = Url::from_directory_path(remote.path()).unwrap();
+    let loc = DirectRes::from_str(&format!("git+{}?submodules=false#v0.1.0", url)).unwrap();
+    match &loc {
+        DirectRes::Git {
+            repo, submodules, ..
+        } => {
+            assert_eq!(repo, &url);
+            assert!(!submodules);
+        }
+        _ => panic!("expected a git resolution"),
+    }
+    assert_eq!(
+        loc.to_string(),
+        format!("git+{}?submodules=false#v0.1.0", url)
+    );
+
+    let pkg = PackageId::new(Name::from_str("git/repo").unwrap(), loc.clone().into());
+    let (res, src) = CACHE
+        .checkout_source(&pkg, &loc, false, false, || ())
+        .unwrap();
+    assert_eq!(src.meta().version().to_string(), "0.1.0");
+    assert_eq!(
+        res.unwrap().to_string(),
+        format!("git+{}?submodules=false#{}", url, first)
+    );
+}
+
+#[test]
+fn git_fetch_with_cli() {
+    let remote = TempDir::new("elba-git").unwrap();
+    let repo = Repository::init(remote.path()).unwrap();
+    let first = commit(&repo, "0.1.0");
+    commit(&repo, "0.2.0");
+
+    let dir = TempDir::new("elba-cache").unwrap();
+    let layout = layout(dir.path());
+    let net = Net {
+        git_fetch_with_cli: true,
+        ..Net::default()
+    };
+    let cache = Cache::from_disk(&LOGGER, layout, net, &Http::default(), shell()).unwrap();
+
+    // Pinned to a commit, which only gets fetched shallowly
+    let url = Url::from_directory_path(remote.path()).unwrap();
+    let loc = DirectRes::from_str(&format!("git+{}#{}", url, first)).unwrap();
+    let pkg = PackageId::new(Name::from_str("git/repo").unwrap(), loc.clone().into());
+    let (_, src) = cache
+        .checkout_source(&pkg, &loc, false, false, || ())
+        .unwrap();
+    assert_eq!(src.meta().version().to_string(), "0.1.0");
+    drop(src);
+
+    let db = Repository::open_bare(
+        dir.path()
+            .join("git")
+            .join(Cache::get_source_dir(&loc, false)),
+    )
+    .unwrap();
+    assert!(db.is_shallow());
+
+    // Branches get everything
+    let loc = DirectRes::from_str(&format!("git+{}#master", url)).unwrap();
+    let pkg = PackageId::new(Name::from_str("git/repo").unwrap(), loc.clone().into());
+    let (_, src) = cache
+        .checkout_source(&pkg, &loc, true, false, || ())
+        .unwrap();
+    assert_eq!(src.meta().version().to_string(), "0.2.0");
+}
+
+#[test]
+fn entries_verify_and_remove() {
+    let remote = TempDir::new("elba-git").unwrap();
+    let repo = Repository::init(remote.path()).unwrap();
+    commit(&repo, "0.1.0");
+
+    let dir = TempDir::new("elba-cache").unwrap();
+    let layout = layout(dir.path());
+    let cache =
+        Cache::from_disk(&LOGGER, layout, Net::default(), &Http::default(), shell()).unwrap();
+
+    let url = Url::from_directory_path(remote.path()).unwrap();
+    let loc = DirectRes::from_str(&format!("git+{}#master", url)).unwrap();
+    let pkg = PackageId::new(Name::from_str("git/repo").unwrap(), loc.clone().into());
+    let (_, src) = cache
+        .checkout_source(&pkg, &loc, false, false, || ())
+        .unwrap();
+    let path = src.path().to_path_buf();
+    drop(src);
+
+    let entries = cache.entries().unwrap();
+    assert!(entries.iter().any(|x| x.kind == EntryKind::Git));
+    let entry = entries
+        .into_iter()
+        .find(|x| x.kind == EntryKind::Src)
+        .unwrap();
+    assert!(entry.size > 0);
+    assert!(entry.hash.is_some());
+    assert_eq!(cache.verify_entry(&entry).unwrap(), Some(true));
+
+    // Tampering with a source gets noticed
+    fs::write(path.join("elba.toml"), "garbage").unwrap();
+    assert_eq!(cache.verify_entry(&entry).unwrap(), Some(false));
+
+    assert!(cache.remove_entry(&entry).unwrap());
+    assert!(!path.exists());
+    assert!(cache
+        .entries()
+        .unwrap()
+        .iter()
+        .all(|x| x.kind != EntryKind::Src));
+}
+
+#[test]
+fn removed_sources_come_back() {
+    let remote = TempDir::new("elba-git").unwrap();
+    let repo = Repository::init(remote.path()).unwrap();
+    commit(&repo, "0.1.0");
+
+    let dir = TempDir::new("elba-cache").unwrap();
+    let cache = Cache::from_disk(
+        &LOGGER,
+        layout(dir.path()),
+        Net::default(),
+        &Http::default(),
+        shell(),
+    )
+    .unwrap();
+
+    let url = Url::from_directory_path(remote.path()).unwrap();
+    let loc = DirectRes::from_str(&format!("git+{}#master", url)).unwrap();
+    let pkg = PackageId::new(Name::from_str("git/repo").unwrap(), loc.clone().into());
+    let (_, src) = cache
+        .checkout_source(&pkg, &loc, false, false, || ())
+        .unwrap();
+    let path = src.path().to_path_buf();
+    drop(src);
+
+    let entry = cache
+        .entries()
+        .unwrap()
+        .into_iter()
+        .find(|x| x.kind == EntryKind::Src)
+        .unwrap();
+    assert!(cache.remove_entry(&entry).unwrap());
+
+    // Somebody locking the source just as it was removed leaves an empty directory behind, which
+    // mustn't pass for the source itself
+    drop(DirLock::acquire_shared(&path).unwrap());
+    assert!(path.exists());
+
+    let (_, src) = cache
+        .checkout_source(&pkg, &loc, false, false, || ())
+        .unwrap();
+    assert_eq!(src.path(), path.as_path());
+    assert_eq!(src.meta().version().to_string(), "0.1.0");
+}
+
+#[test]
+fn secondary_builds() {
+    // Pretend some other machine exported a build to a shared directory
+    let shared = TempDir::new("elba-shared").unwrap();
+    let hash = BuildHash("ab".repeat(32));
+    let meta = BuildMeta {
+        compiler: "1.3.2".to_owned(),
+        backend: Some("c".to_owned()),
+    };
+    fs::create_dir_all(shared.path().join(&hash.0).join("Foo")).unwrap();
+    fs::write(shared.path().join(&hash.0).join("Foo/Bar.ibc"), "ibc").unwrap();
+    let mut manifest = BuildsManifest::default();
+    manifest.builds.insert(hash.0.clone(), meta.clone());
+    manifest.write(shared.path()).unwrap();
+
+    let dir = TempDir::new("elba-cache").unwrap();
+    let layout = Layout {
+        secondary_build: Some(shared.path().to_path_buf()),
+        ..layout(dir.path())
+    };
+    let cache =
+        Cache::from_disk(&LOGGER, layout, Net::default(), &Http::default(), shell()).unwrap();
+
+    let binary = cache.checkout_build(&hash).unwrap().unwrap();
+    assert!(binary.target.path().join("Foo/Bar.ibc").exists());
+    drop(binary);
+
+    // It's been copied into our own cache, metadata and all
+    let entry = cache
+        .entries()
+        .unwrap()
+        .into_iter()
+        .find(|x| x.kind == EntryKind::Build)
+        .unwrap();
+    assert_eq!(entry.name, hash.0);
+    assert_eq!(entry.build, Some(meta.clone()));
+
+    // ...so importing it again doesn't do anything
+    assert!(!cache
+        .import_build(&shared.path().join(&hash.0), &hash, &meta)
+        .unwrap());
+    assert!(cache
+        .checkout_build(&BuildHash("cd".repeat(32)))
+        .unwrap()
+        .is_none());
+}
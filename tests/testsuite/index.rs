@@ -1,5 +1,5 @@
-use super::util::index;
-use elba::package::Name;
+use super::util::{index, CACHE, INDEX_DIR};
+use elba::{package::Name, remote::resolution::DirectRes};
 use std::str::FromStr;
 
 #[test]
@@ -9,3 +9,14 @@ fn index_success() {
     let vs = i.entries(&Name::from_str("no_conflict/root").unwrap());
     vs.unwrap();
 }
+
+#[test]
+fn indices_dedup() {
+    let res = DirectRes::Dir {
+        path: INDEX_DIR.path().to_owned(),
+    };
+
+    let ixs = CACHE.get_indices(&[res.clone(), res], false, false);
+
+    assert_eq!(ixs.indices.len(), 1);
+}
@@ -1,4 +1,5 @@
 mod build;
+mod cache;
 mod index;
 mod resolver;
 mod util;
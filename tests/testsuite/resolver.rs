@@ -49,6 +49,7 @@ fn retriever(root: Summary) -> Retriever<'static> {
         &CACHE,
         root,
         root_deps,
+        None,
         Right(ixs),
         Graph::default(),
         &IXMAP,
@@ -4,12 +4,20 @@ use elba::{
         Index,
     },
     retrieve::cache::{Cache, Layout},
-    util::{copy_dir, lock::DirLock, shell::Shell},
+    util::{
+        config::{Http, Net},
+        copy_dir,
+        lock::DirLock,
+        shell::Shell,
+    },
 };
 use indexmap::{indexmap, IndexMap};
 use lazy_static::lazy_static;
 use slog::{self, o, Logger};
-use std::{path::PathBuf, str::FromStr};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 use tempdir::TempDir;
 
 lazy_static! {
@@ -37,16 +45,25 @@ pub fn shell() -> Shell {
     Shell::default()
 }
 
+/// The layout of a cache which lives in `root`.
+pub fn layout(root: &Path) -> Layout {
+    Layout {
+        bin: root.join("bin"),
+        build: root.join("build"),
+        indices: root.join("indices"),
+        git: root.join("git"),
+        records: root.join("records"),
+        secondary_build: None,
+        src: root.join("src"),
+        tmp: root.join("tmp"),
+        logins: root.join("logins.toml"),
+    }
+}
+
 pub fn cache() -> Cache {
-    let layout = Layout {
-        bin: CACHE_DIR.path().join("bin"),
-        build: CACHE_DIR.path().join("build"),
-        indices: CACHE_DIR.path().join("indices"),
-        src: CACHE_DIR.path().join("src"),
-        tmp: CACHE_DIR.path().join("tmp"),
-    };
+    let layout = layout(CACHE_DIR.path());
 
-    Cache::from_disk(&LOGGER, layout, shell()).unwrap()
+    Cache::from_disk(&LOGGER, layout, Net::default(), &Http::default(), shell()).unwrap()
 }
 
 fn index_dir() -> TempDir {